@@ -2,6 +2,7 @@ use crate::get::GetCommand;
 use crate::list::ListCommand;
 use crate::load::LoadCommand;
 use crate::unload::UnloadCommand;
+use crate::update::UpdateCommand;
 use agent_api::new_agent_client;
 use clap::{Parser, Subcommand};
 
@@ -34,6 +35,10 @@ pub(crate) enum SubCommands {
     /// Retrieves detailed information about a specific program.
     /// Requires the name of the program to be retrieved.
     Get(GetCommand),
+
+    /// Updates the metadata or bytecode of a loaded program in place.
+    /// Requires the name of the program to be updated.
+    Update(UpdateCommand),
 }
 
 impl AgentCli {
@@ -45,6 +50,7 @@ impl AgentCli {
             SubCommands::Unload(u) => u.execute(agent_client).await,
             SubCommands::List(l) => l.execute(agent_client).await,
             SubCommands::Get(g) => g.execute(agent_client).await,
+            SubCommands::Update(u) => u.execute(agent_client).await,
             // SubCommands::Image(i) => i.execute(agent_client).await,
         }
     }
@@ -1,8 +1,15 @@
-use clap::{Args, Subcommand};
+use std::fs::canonicalize;
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::{ArgGroup, Args, Subcommand};
 use tonic::transport::Channel;
 
 use agent_api::v1::agent_client::AgentClient;
-use agent_api::v1::LoadRequest;
+use agent_api::v1::bytecode_location::Location;
+use agent_api::v1::{BytecodeImage, BytecodeLocation, LoadRequest};
+use agent_api::ImagePullPolicy;
 
 use crate::table::ProgTable;
 use crate::utils::parse_key_val;
@@ -30,6 +37,12 @@ pub(crate) struct LoadBuiltinArgs {
     #[clap(short, long)]
     pub(crate) name: String,
 
+    /// Optional: The builtin program to create a new instance of, named after
+    /// the given name. Defaults to the name.
+    /// Example: --name service_map_east --kind service_map
+    #[clap(short, long, verbatim_doc_comment)]
+    pub(crate) kind: Option<String>,
+
     /// Optional: Specify Key/Value metadata to be attached to a program when it
     /// is loaded by agent.
     /// Format: <KEY>=<VALUE>
@@ -48,6 +61,7 @@ pub(crate) struct LoadBuiltinArgs {
 }
 
 #[derive(Args, Debug)]
+#[command(group(ArgGroup::new("bytecode").required(true).args(["image_url", "file"])))]
 pub(crate) struct LoadWasmArgs {
     /// Specify how the bytecode image should be pulled.
     #[command(flatten)]
@@ -57,6 +71,12 @@ pub(crate) struct LoadWasmArgs {
     #[clap(short, long, verbatim_doc_comment, default_value = "")]
     pub(crate) name: String,
 
+    /// Optional: Load the bytecode from a local file instead of an image.
+    /// Refused by agents which verify image signatures.
+    /// Example: --file ./prog.wasm
+    #[clap(short, long, verbatim_doc_comment, conflicts_with = "image_url")]
+    pub(crate) file: Option<PathBuf>,
+
     /// Optional: Specify Key/Value metadata to be attached to a program when it
     /// is loaded by bpfman.
     /// Format: <KEY>=<VALUE>
@@ -77,9 +97,9 @@ pub(crate) struct LoadWasmArgs {
 #[derive(Args, Debug)]
 #[command(disable_version_flag = true)]
 pub(crate) struct PullBytecodeArgs {
-    /// Required: Container Image URL.
+    /// Optional: Container Image URL.
     #[clap(short, long, verbatim_doc_comment)]
-    pub(crate) image_url: String,
+    pub(crate) image_url: Option<String>,
 
     /// Optional: Registry auth for authenticating with the specified image registry.
     /// This should be base64 encoded from the '<username>:<password>' string just like
@@ -117,6 +137,7 @@ pub(crate) async fn execute_load_builtin(
             .iter()
             .map(|(k, v)| (k.to_owned(), v.to_owned()))
             .collect(),
+        kind: args.kind.clone().unwrap_or_default(),
     });
 
     let response = client.load(request).await?.into_inner();
@@ -125,9 +146,88 @@ pub(crate) async fn execute_load_builtin(
     Ok(())
 }
 
+impl TryFrom<&PullBytecodeArgs> for BytecodeImage {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &PullBytecodeArgs) -> Result<Self, Self::Error> {
+        let pull_policy: ImagePullPolicy = value.pull_policy.as_str().try_into()?;
+        let (username, password) = match &value.registry_auth {
+            Some(auth) => {
+                let decoded = String::from_utf8(STANDARD.decode(auth)?)?;
+                let (username, password) = decoded.split_once(':').ok_or(anyhow::anyhow!(
+                    "Registry auth must be <username>:<password>"
+                ))?;
+                (Some(username.to_string()), Some(password.to_string()))
+            }
+            None => (None, None),
+        };
+
+        let url = value
+            .image_url
+            .clone()
+            .ok_or(anyhow::anyhow!("Image url is required"))?;
+
+        Ok(BytecodeImage {
+            url,
+            image_pull_policy: pull_policy.into(),
+            username,
+            password,
+        })
+    }
+}
+
+/// Builds the bytecode location from either a local file or an image url.
+pub(crate) fn bytecode_location(
+    file: &Option<PathBuf>,
+    pull_args: &PullBytecodeArgs,
+) -> anyhow::Result<Option<BytecodeLocation>> {
+    let location = match file {
+        // The agent resolves paths against its own working directory, so send
+        // an absolute one.
+        Some(file) => {
+            let path = canonicalize(file)
+                .map_err(|e| anyhow::anyhow!("Failed to resolve {}: {}", file.display(), e))?;
+            Location::File(path.to_string_lossy().to_string())
+        }
+        None if pull_args.image_url.is_some() => {
+            Location::Image(BytecodeImage::try_from(pull_args)?)
+        }
+        None => return Ok(None),
+    };
+    Ok(Some(BytecodeLocation {
+        location: Some(location),
+    }))
+}
+
 async fn execute_load_wasm(
-    client: AgentClient<Channel>,
+    mut client: AgentClient<Channel>,
     args: &LoadWasmArgs,
 ) -> anyhow::Result<()> {
-    todo!()
+    let bytecode = bytecode_location(&args.file, &args.pull_args)?;
+
+    let request = tonic::Request::new(LoadRequest {
+        bytecode,
+        name: args.name.clone(),
+        program_type: 1,
+        metadata: args
+            .metadata
+            .clone()
+            .unwrap_or_default()
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect(),
+        ebpf_maps: args
+            .ebpf_maps
+            .clone()
+            .unwrap_or_default()
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect(),
+        kind: String::new(),
+    });
+
+    let response = client.load(request).await?.into_inner();
+    ProgTable::new_program(&response.info)?.print();
+
+    Ok(())
 }
@@ -7,6 +7,7 @@ mod list;
 mod load;
 mod table;
 mod unload;
+mod update;
 mod utils;
 
 #[tokio::main]
@@ -34,6 +34,7 @@ impl ProgTable {
         match info.program_type.try_into()? {
             Builtin => {
                 table.add_row(vec!["Type:", "Builtin"]);
+                table.add_row(vec!["Kind:", &info.kind]);
             }
             Wasm => {
                 table.add_row(vec!["Type:", "Wasm"]);
@@ -78,6 +79,11 @@ impl ProgTable {
             }
         };
 
+        table.add_row(vec!["Restarts:", &info.restart_count.to_string()]);
+        if !info.last_error.is_empty() {
+            table.add_row(vec!["Last Error:", &info.last_error]);
+        }
+
         if info.ebpf_maps.is_empty() {
             table.add_row(vec!["Maps:", "None"]);
         } else {
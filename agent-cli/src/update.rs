@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use tonic::transport::Channel;
+
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::UpdateRequest;
+
+use crate::load::{bytecode_location, PullBytecodeArgs};
+use crate::table::ProgTable;
+use crate::utils::parse_key_val;
+
+#[derive(Parser, Debug)]
+pub(crate) struct UpdateCommand {
+    /// Required: The name of the program to update.
+    pub(crate) name: String,
+
+    /// Optional: Key/Value metadata to merge into the metadata of the program.
+    /// A key with an empty value is removed.
+    /// Format: <KEY>=<VALUE>
+    /// Example: --metadata interval=30
+    #[clap(short, long, verbatim_doc_comment, value_parser=parse_key_val, value_delimiter = ',')]
+    pub(crate) metadata: Option<Vec<(String, String)>>,
+
+    /// Optional: Replace the bytecode of a wasm program with a local file.
+    /// Example: --file ./prog.wasm
+    #[clap(short, long, verbatim_doc_comment, conflicts_with = "image_url")]
+    pub(crate) file: Option<PathBuf>,
+
+    /// Optional: Replace the bytecode of a wasm program with an image.
+    #[command(flatten)]
+    pub(crate) pull_args: PullBytecodeArgs,
+}
+
+impl UpdateCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        let mut client = agent_client;
+        let request = UpdateRequest {
+            name: self.name.clone(),
+            bytecode: bytecode_location(&self.file, &self.pull_args)?,
+            metadata: self
+                .metadata
+                .clone()
+                .unwrap_or_default()
+                .iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+        };
+        let response = client.update(request).await?.into_inner();
+        ProgTable::new_program(&response.info)?.print();
+        Ok(())
+    }
+}
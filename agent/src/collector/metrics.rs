@@ -0,0 +1,246 @@
+//! Agent self-metrics about the programs it runs, so a program which silently
+//! stops producing data can be alerted on.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use ahash::AHashMap;
+use parking_lot::RwLock;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::metrics::MetricType;
+use prometheus_client::registry::Unit;
+
+use agent_api::ProgramState;
+
+use crate::progs::types::Program;
+
+const PROGRAM_STATES: [ProgramState; 5] = [
+    ProgramState::Uninitialized,
+    ProgramState::Initialized,
+    ProgramState::Running,
+    ProgramState::Stopped,
+    ProgramState::Failed,
+];
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ProgramLabels {
+    program: String,
+    program_type: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StateLabels {
+    program: String,
+    program_type: String,
+    state: String,
+}
+
+#[derive(Clone, Debug)]
+struct ProgramStats {
+    collect_duration: Histogram,
+    collect_errors: Counter,
+    restarts: Counter,
+}
+
+impl Default for ProgramStats {
+    fn default() -> Self {
+        Self {
+            // 0.5ms up to ~1s
+            collect_duration: Histogram::new(exponential_buckets(0.0005, 2.0, 12)),
+            collect_errors: Counter::default(),
+            restarts: Counter::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ProgramMetrics {
+    inner: Arc<RwLock<AHashMap<String, ProgramStats>>>,
+}
+
+impl ProgramMetrics {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    fn stats(&self, name: &str) -> ProgramStats {
+        if let Some(stats) = self.inner.read().get(name) {
+            return stats.clone();
+        }
+        let mut inner = self.inner.write();
+        inner.entry(name.to_string()).or_default().clone()
+    }
+
+    pub(crate) fn observe_collect(&self, name: &str, duration: Duration, failed: bool) {
+        let stats = self.stats(name);
+        stats.collect_duration.observe(duration.as_secs_f64());
+        if failed {
+            stats.collect_errors.inc();
+        }
+    }
+
+    /// Records a program being restarted by its supervisor. Loading a program
+    /// again after it was unloaded is not a restart.
+    pub(crate) fn record_restart(&self, name: &str) {
+        self.stats(name).restarts.inc();
+    }
+
+    pub(crate) fn restart_count(&self, name: &str) -> u64 {
+        let inner = self.inner.read();
+        inner
+            .get(name)
+            .map(|stats| stats.restarts.get())
+            .unwrap_or_default()
+    }
+
+    /// Drops the series of a program which is no longer registered.
+    pub(crate) fn remove(&self, name: &str) {
+        self.inner.write().remove(name);
+    }
+
+    /// Encodes the self-metrics of `progs`, whatever state they are in.
+    pub(crate) fn encode(
+        &self,
+        encoder: &mut DescriptorEncoder,
+        progs: &[Arc<dyn Program>],
+    ) -> Result<(), std::fmt::Error> {
+        let entries: Vec<_> = progs
+            .iter()
+            .map(|prog| {
+                let labels = ProgramLabels {
+                    program: prog.get_name(),
+                    program_type: format!("{:?}", prog.get_type()).to_lowercase(),
+                };
+                (labels, prog.get_state(), self.stats(&prog.get_name()))
+            })
+            .collect();
+
+        let mut metric_encoder = encoder.encode_descriptor(
+            "program_state",
+            "current state of programs, 1 for the state the program is in",
+            None,
+            MetricType::Gauge,
+        )?;
+        for (labels, state, _) in entries.iter() {
+            for s in PROGRAM_STATES.iter() {
+                let state_labels = StateLabels {
+                    program: labels.program.clone(),
+                    program_type: labels.program_type.clone(),
+                    state: format!("{:?}", s).to_lowercase(),
+                };
+                metric_encoder
+                    .encode_family(&state_labels)?
+                    .encode_gauge(&((s == state) as i64))?;
+            }
+        }
+
+        let mut metric_encoder = encoder.encode_descriptor(
+            "program_collect_duration",
+            "time spent collecting the metrics of programs",
+            Some(&Unit::Seconds),
+            MetricType::Histogram,
+        )?;
+        for (labels, _, stats) in entries.iter() {
+            stats
+                .collect_duration
+                .encode(metric_encoder.encode_family(labels)?)?;
+        }
+
+        let mut metric_encoder = encoder.encode_descriptor(
+            "program_collect_errors",
+            "number of failed metric collections of programs",
+            None,
+            MetricType::Counter,
+        )?;
+        for (labels, _, stats) in entries.iter() {
+            stats
+                .collect_errors
+                .encode(metric_encoder.encode_family(labels)?)?;
+        }
+
+        let mut metric_encoder = encoder.encode_descriptor(
+            "program_restarts",
+            "number of times programs were restarted",
+            None,
+            MetricType::Counter,
+        )?;
+        for (labels, _, stats) in entries.iter() {
+            stats
+                .restarts
+                .encode(metric_encoder.encode_family(labels)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::collector::Collector;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+
+    use super::*;
+    use crate::progs::wasm::program::WasmProgram;
+
+    #[derive(Debug)]
+    struct MetricsCollector(ProgramMetrics, Vec<Arc<dyn Program>>);
+
+    impl Collector for MetricsCollector {
+        fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+            self.0.encode(&mut encoder, &self.1)
+        }
+    }
+
+    fn scrape(metrics: &ProgramMetrics, prog: &Arc<dyn Program>) -> String {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(MetricsCollector(
+            metrics.clone(),
+            vec![prog.clone()],
+        )));
+        let mut buf = String::new();
+        encode(&mut buf, &registry).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_program_metrics() {
+        let metrics = ProgramMetrics::new();
+        let prog: Arc<dyn Program> =
+            Arc::new(WasmProgram::new("w", b"(module)", Default::default()).unwrap());
+        let state = |state: &str, value: i64| {
+            format!(
+                "program_state{{program=\"w\",program_type=\"wasm\",state=\"{}\"}} {}\n",
+                state, value
+            )
+        };
+
+        let text = scrape(&metrics, &prog);
+        assert!(text.contains(&state("uninitialized", 1)));
+        assert!(text.contains(&state("running", 0)));
+        assert!(text.contains("program_restarts_total{program=\"w\",program_type=\"wasm\"} 0\n"));
+
+        prog.set_state(ProgramState::Running);
+        metrics.observe_collect("w", Duration::from_millis(1), true);
+        let text = scrape(&metrics, &prog);
+        assert!(text.contains(&state("uninitialized", 0)));
+        assert!(text.contains(&state("running", 1)));
+        assert!(
+            text.contains("program_collect_errors_total{program=\"w\",program_type=\"wasm\"} 1\n")
+        );
+
+        prog.set_state(ProgramState::Failed);
+        metrics.record_restart("w");
+        metrics.record_restart("w");
+        let text = scrape(&metrics, &prog);
+        assert!(text.contains(&state("running", 0)));
+        assert!(text.contains(&state("failed", 1)));
+        assert!(text.contains("program_restarts_total{program=\"w\",program_type=\"wasm\"} 2\n"));
+        assert_eq!(metrics.restart_count("w"), 2);
+
+        metrics.remove("w");
+        assert_eq!(metrics.restart_count("w"), 0);
+    }
+}
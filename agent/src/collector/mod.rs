@@ -1,25 +1,39 @@
 use std::fmt::Debug;
+use std::time::Instant;
 
+use log::error;
 use prometheus_client::collector::Collector as PrometheusCollector;
 use prometheus_client::encoding::DescriptorEncoder;
 
+use crate::common::types::ListFilter;
 use crate::managers::registry::RegistryManager;
 use agent_api::ProgramState;
 
+pub(crate) mod metrics;
+
 #[derive(Debug)]
 pub(crate) struct Collector {
     registry_manager: RegistryManager,
+    filter: ListFilter,
 }
 
 impl Collector {
     pub(crate) fn new(registry_manager: RegistryManager) -> Self {
-        Self { registry_manager }
+        Self::with_filter(registry_manager, ListFilter::default())
+    }
+
+    /// Creates a collector which only encodes programs matching `filter`.
+    pub(crate) fn with_filter(registry_manager: RegistryManager, filter: ListFilter) -> Self {
+        Self {
+            registry_manager,
+            filter,
+        }
     }
 }
 
 impl PrometheusCollector for Collector {
     fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
-        let progs = self.registry_manager.builtin.list();
+        let progs = self.registry_manager.list_programs(self.filter.clone());
 
         // 筛选出状态为 Running 的 progs
         let running_progs: Vec<_> = progs
@@ -28,11 +42,20 @@ impl PrometheusCollector for Collector {
             .collect();
 
         for prog in running_progs {
-            if let Err(e) = prog.collect(&mut encoder) {
-                eprintln!("Failed to collect metrics: {:?}", e);
+            let start = Instant::now();
+            let result = prog.collect(&mut encoder);
+            if let Err(e) = &result {
+                error!("Failed to collect metrics of {}: {:?}", prog.get_name(), e);
             }
+            self.registry_manager.metrics.observe_collect(
+                &prog.get_name(),
+                start.elapsed(),
+                result.is_err(),
+            );
         }
 
+        self.registry_manager.metrics.encode(&mut encoder, &progs)?;
+
         Ok(())
     }
 }
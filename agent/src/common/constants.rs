@@ -3,6 +3,7 @@ pub mod directories {
     pub const RTDIR_MODE: u32 = 0o6770;
     pub const RTDIR: &str = "/run/eva";
     pub const RTPATH_AGENT_SOCKET: &str = "/run/eva/agent.sock";
+    pub const RTDIR_IMAGES: &str = "/run/eva/images";
     pub const RTDIR_FS_MAPS: &str = "/run/bpfman/fs/maps";
 }
 
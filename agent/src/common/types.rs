@@ -6,6 +6,7 @@ use std::sync::Arc;
 pub struct ListFilter {
     pub(crate) program_type: Option<u32>,
     pub(crate) metadata_selector: HashMap<String, String>,
+    pub(crate) program_names: Vec<String>,
 }
 
 impl ListFilter {
@@ -13,14 +14,25 @@ impl ListFilter {
         Self {
             program_type,
             metadata_selector: metadata,
+            program_names: Vec::new(),
         }
     }
 
+    /// Restricts the filter to the given program names, an empty list matches
+    /// every program.
+    pub(crate) fn with_program_names(mut self, names: Vec<String>) -> Self {
+        self.program_names = names;
+        self
+    }
+
     pub(crate) fn matches(&self, prog: Arc<dyn Program>) -> bool {
         let program_type: u32 = match prog.get_type().try_into() {
             Ok(t) => t,
             Err(_) => return false,
         };
+        if !self.program_names.is_empty() && !self.program_names.contains(&prog.get_name()) {
+            return false;
+        }
         if let Some(filter_type) = self.program_type {
             if filter_type != program_type {
                 return false;
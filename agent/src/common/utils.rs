@@ -1,11 +1,13 @@
 use std::hash::Hasher;
 use std::result::Result;
+use std::time::Duration;
 
 use bytes::Bytes;
 use fnv::FnvHasher;
 use http_body_util::Empty;
 use hyper::{body::Incoming, Request, Response};
 use hyper_util::rt::TokioIo;
+use nix::time::{clock_gettime, ClockId};
 use tokio::net::TcpStream;
 
 pub async fn fetch_url(
@@ -46,3 +48,8 @@ pub fn fnv_hash(s: &str) -> u32 {
     hasher.write(s.as_bytes());
     hasher.finish() as u32
 }
+
+/// Returns the time of `CLOCK_MONOTONIC`, which `bpf_ktime_get_ns` reads.
+pub(crate) fn monotonic_now() -> Result<Duration, anyhow::Error> {
+    Ok(clock_gettime(ClockId::CLOCK_MONOTONIC)?.into())
+}
@@ -2,6 +2,8 @@ use std::path::PathBuf;
 
 use clap::Parser;
 
+use crate::managers::cache::kubernetes::WatchedKind;
+use crate::managers::cache::MetadataSource;
 use crate::server::serve;
 use crate::utils::init_env;
 
@@ -34,6 +36,45 @@ pub(crate) struct Args {
         default_value = "/run/bpfman-sock/bpfman.sock"
     )]
     pub(crate) bpfman_socket_path: String,
+    /// Optional: PEM encoded public key pulled program images must be signed with.
+    /// Images without a valid cosign signature are rejected when set, as is
+    /// bytecode loaded from a local file.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) image_verification_key: Option<PathBuf>,
+    /// Optional: Directory the loaded programs are persisted to, they are
+    /// loaded again from there when the agent restarts.
+    #[clap(long, verbatim_doc_comment, default_value = "/run/eva/state")]
+    pub(crate) state_dir: PathBuf,
+    /// Optional: Where the workloads of the observed addresses and processes
+    /// are looked up, the local provider is for hosts outside Kubernetes.
+    #[clap(long, verbatim_doc_comment, value_enum, default_value = "kubernetes")]
+    pub(crate) metadata_source: MetadataSource,
+    /// Optional: File of static `address namespace/name [kind]` entries
+    /// resolved by the local metadata provider.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) metadata_file: Option<PathBuf>,
+    /// Optional: Name of the node the agent runs on. Only the pods of this
+    /// node are watched when set, the other pod IPs are looked up on demand.
+    #[clap(long, verbatim_doc_comment, env = "NODE_NAME")]
+    pub(crate) node_name: Option<String>,
+    /// Optional: Comma separated kinds of Kubernetes objects watched to
+    /// resolve addresses to workloads.
+    #[clap(
+        long,
+        verbatim_doc_comment,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "pod,node,service,endpointslice,replicaset,deployment,statefulset,daemonset,job,cronjob"
+    )]
+    pub(crate) watched_kinds: Vec<WatchedKind>,
+    /// Optional: Comma separated keys of the pod and workload labels promoted
+    /// into the metric labels, e.g. `app.kubernetes.io/team,version`.
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) promoted_labels: Vec<String>,
+    /// Optional: Comma separated keys of the pod and workload annotations
+    /// promoted into the metric labels.
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) promoted_annotations: Vec<String>,
 }
 
 #[tokio::main]
@@ -0,0 +1,113 @@
+//! The Service ports the endpoints of the cluster serve.
+//!
+//! An endpoint is an address and a port, either a ClusterIP and a port of its
+//! Service, or a pod IP and a target port listed by an EndpointSlice of the
+//! Service. The endpoints are indexed per object listing them, for them to be
+//! replaced when the object changes and dropped when it is deleted.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use ahash::AHashMap;
+
+use crate::managers::cache::ServiceEndpoint;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct EndpointKey {
+    pub ip: IpAddr,
+    pub port: u16,
+    /// The protocol as named by Kubernetes, e.g. `TCP`.
+    pub protocol: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ServiceEndpoints {
+    endpoints: AHashMap<EndpointKey, Arc<ServiceEndpoint>>,
+    /// The endpoints listed by each object, per uid.
+    objects: AHashMap<String, Vec<(EndpointKey, Arc<ServiceEndpoint>)>>,
+}
+
+impl ServiceEndpoints {
+    /// Replaces the endpoints listed by an object.
+    pub(crate) fn update(
+        &mut self,
+        uid: &str,
+        endpoints: Vec<(EndpointKey, Arc<ServiceEndpoint>)>,
+    ) {
+        self.remove(uid);
+        for (key, endpoint) in endpoints.iter() {
+            self.endpoints.insert(key.clone(), endpoint.clone());
+        }
+        self.objects.insert(uid.to_string(), endpoints);
+    }
+
+    /// Drops the endpoints listed by an object, unless they were listed by
+    /// another object since.
+    pub(crate) fn remove(&mut self, uid: &str) {
+        for (key, endpoint) in self.objects.remove(uid).into_iter().flatten() {
+            let current = self.endpoints.get(&key);
+            if current.is_some_and(|current| Arc::ptr_eq(current, &endpoint)) {
+                self.endpoints.remove(&key);
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, key: &EndpointKey) -> Option<Arc<ServiceEndpoint>> {
+        self.endpoints.get(key).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::cache::Workload;
+
+    fn endpoint(service: &str, port_name: &str) -> Arc<ServiceEndpoint> {
+        Arc::new(ServiceEndpoint {
+            service: Arc::new(Workload {
+                name: service.to_string(),
+                namespace: "default".to_string(),
+                kind: "Service".to_string(),
+                ..Default::default()
+            }),
+            port_name: port_name.to_string(),
+        })
+    }
+
+    fn key(ip: &str, port: u16) -> EndpointKey {
+        EndpointKey {
+            ip: ip.parse().unwrap(),
+            port,
+            protocol: "TCP".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_update_endpoints() {
+        let (grpc, http) = (endpoint("checkout", "grpc"), endpoint("checkout", "http"));
+        let mut endpoints = ServiceEndpoints::default();
+        endpoints.update(
+            "slice-1",
+            vec![
+                (key("10.0.0.1", 50051), grpc.clone()),
+                (key("10.0.0.1", 8080), http.clone()),
+            ],
+        );
+        assert_eq!(endpoints.get(&key("10.0.0.1", 50051)), Some(grpc));
+        assert_eq!(endpoints.get(&key("10.0.0.1", 8080)), Some(http));
+        assert_eq!(endpoints.get(&key("10.0.0.2", 50051)), None);
+
+        // the pod moved to another slice before its first slice was updated
+        let moved = endpoint("checkout", "grpc");
+        endpoints.update("slice-2", vec![(key("10.0.0.1", 50051), moved.clone())]);
+        endpoints.update("slice-1", vec![]);
+        assert!(Arc::ptr_eq(
+            &endpoints.get(&key("10.0.0.1", 50051)).unwrap(),
+            &moved
+        ));
+        assert_eq!(endpoints.get(&key("10.0.0.1", 8080)), None);
+
+        endpoints.remove("slice-2");
+        assert_eq!(endpoints.get(&key("10.0.0.1", 50051)), None);
+    }
+}
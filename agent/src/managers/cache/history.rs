@@ -0,0 +1,161 @@
+//! Ownership history of the addresses of the cluster.
+//!
+//! Pod IPs are recycled, so the owner of an address is recorded with the time
+//! it was seen taking the address, and a released address keeps a tombstone
+//! instead of disappearing. A connection is then attributed to the workload
+//! which owned its addresses when it was observed, even when that workload is
+//! gone or the address was reassigned since. Times are durations on the
+//! monotonic clock, the clock of `bpf_ktime_get_ns`.
+
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ahash::AHashMap;
+
+use crate::managers::cache::Workload;
+
+/// Owners kept per address, the current one included.
+const MAX_OWNERS: usize = 4;
+/// How long a previous owner or a tombstone is kept.
+const RETENTION: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+struct Ownership {
+    since: Duration,
+    /// `None` for a tombstone.
+    workload: Option<Arc<Workload>>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct IpHistory {
+    owners: AHashMap<IpAddr, VecDeque<Ownership>>,
+    /// Released addresses in the order of their release.
+    tombstones: VecDeque<(Duration, IpAddr)>,
+}
+
+impl IpHistory {
+    pub(crate) fn insert(&mut self, ip: IpAddr, workload: Arc<Workload>, now: Duration) {
+        self.push(ip, Some(workload), now);
+    }
+
+    /// Records that an address was released by its owner.
+    pub(crate) fn remove(&mut self, ip: IpAddr, now: Duration) {
+        if self.current(ip).is_some() {
+            self.push(ip, None, now);
+            self.tombstones.push_back((now, ip));
+        }
+    }
+
+    /// The current owner of an address.
+    pub(crate) fn current(&self, ip: IpAddr) -> Option<Arc<Workload>> {
+        self.owners.get(&ip)?.back()?.workload.clone()
+    }
+
+    /// The owner of an address at a time. An address observed before its
+    /// oldest known owner is attributed to that owner.
+    pub(crate) fn lookup(&self, ip: IpAddr, at: Duration) -> Option<Arc<Workload>> {
+        let owners = self.owners.get(&ip)?;
+        owners
+            .iter()
+            .rev()
+            .find(|owner| owner.since <= at)
+            .or_else(|| owners.front())?
+            .workload
+            .clone()
+    }
+
+    fn push(&mut self, ip: IpAddr, workload: Option<Arc<Workload>>, now: Duration) {
+        let owners = self.owners.entry(ip).or_default();
+        if owners.back().map(|owner| &owner.workload) != Some(&workload) {
+            owners.push_back(Ownership {
+                since: now,
+                workload,
+            });
+        }
+        // an owner is only needed until its successor is older than the retention
+        while owners.len() > MAX_OWNERS || (owners.len() > 1 && owners[1].since + RETENTION < now) {
+            owners.pop_front();
+        }
+        self.expire(now);
+    }
+
+    /// Forgets the addresses released for longer than the retention and not
+    /// taken again since.
+    fn expire(&mut self, now: Duration) {
+        while let Some((released, ip)) = self.tombstones.front().copied() {
+            if released + RETENTION >= now {
+                break;
+            }
+            self.tombstones.pop_front();
+            let expired = self
+                .owners
+                .get(&ip)
+                .and_then(|owners| owners.back())
+                .is_some_and(|owner| owner.workload.is_none() && owner.since + RETENTION < now);
+            if expired {
+                self.owners.remove(&ip);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.owners.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workload(name: &str) -> Arc<Workload> {
+        Arc::new(Workload {
+            name: name.to_string(),
+            namespace: "default".to_string(),
+            kind: "Deployment".to_string(),
+            ..Default::default()
+        })
+    }
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn test_recycled_ip() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let (old, new) = (workload("old"), workload("new"));
+        let mut history = IpHistory::default();
+        history.insert(ip, old.clone(), secs(100));
+        history.remove(ip, secs(200));
+        assert_eq!(history.current(ip), None);
+        assert_eq!(history.lookup(ip, secs(150)), Some(old.clone()));
+        assert_eq!(history.lookup(ip, secs(210)), None);
+
+        history.insert(ip, new.clone(), secs(220));
+        assert_eq!(history.current(ip), Some(new.clone()));
+        assert_eq!(history.lookup(ip, secs(50)), Some(old.clone()));
+        assert_eq!(history.lookup(ip, secs(199)), Some(old));
+        assert_eq!(history.lookup(ip, secs(230)), Some(new.clone()));
+
+        // the previous owners expire once the new owner is old enough
+        history.insert(ip, new.clone(), secs(1000));
+        assert_eq!(history.lookup(ip, secs(150)), Some(new));
+    }
+
+    #[test]
+    fn test_expired_tombstone() {
+        let (ip, other): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let mut history = IpHistory::default();
+        history.insert(ip, workload("a"), secs(0));
+        history.remove(ip, secs(10));
+        history.insert(other, workload("b"), secs(20));
+        assert_eq!(history.len(), 2);
+        history.insert(other, workload("b"), secs(10 + RETENTION.as_secs() + 1));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.lookup(ip, secs(5)), None);
+    }
+}
@@ -0,0 +1,1074 @@
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use futures::{StreamExt, TryStreamExt};
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, ReplicaSet, StatefulSet};
+use k8s_openapi::api::batch::v1::{CronJob, Job};
+use k8s_openapi::api::core::v1::{Node, NodeSpec, Pod, PodSpec, Service};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+use kube::runtime::reflector::store::Writer;
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::{
+    api::{Api, ListParams},
+    runtime::{predicates, reflector, watcher, WatchStreamExt},
+    Client, Resource, ResourceExt,
+};
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
+
+use crate::common::utils::monotonic_now;
+use crate::managers::cache::endpoints::{EndpointKey, ServiceEndpoints};
+use crate::managers::cache::history::IpHistory;
+use crate::managers::cache::lookups::RemoteLookups;
+use crate::managers::cache::process::{parse_cgroup, strip_runtime, ProcessCache};
+use crate::managers::cache::{parse_ip, MetadataProvider, ServiceEndpoint, Topology, Workload};
+use crate::progs::service_map::external::Cidr;
+
+type Cache<K, V> = Arc<RwLock<AHashMap<K, Arc<V>>>>;
+
+const ZONE_LABEL: &str = "topology.kubernetes.io/zone";
+const REGION_LABEL: &str = "topology.kubernetes.io/region";
+const LEGACY_ZONE_LABEL: &str = "failure-domain.beta.kubernetes.io/zone";
+const LEGACY_REGION_LABEL: &str = "failure-domain.beta.kubernetes.io/region";
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+const DEFAULT_PROTOCOL: &str = "TCP";
+
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, ValueEnum)]
+#[value(rename_all = "lower")]
+pub(crate) enum WatchedKind {
+    Pod,
+    Node,
+    Service,
+    EndpointSlice,
+    ReplicaSet,
+    Deployment,
+    StatefulSet,
+    DaemonSet,
+    Job,
+    CronJob,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct KubeConfig {
+    /// Only the pods scheduled on this node are watched when set, the
+    /// addresses of the other pods are looked up on demand.
+    pub node_name: Option<String>,
+    pub watched_kinds: HashSet<WatchedKind>,
+    /// Keys of the labels and annotations of the pods and of their owners
+    /// copied to the workloads. Only allowlisted keys are promoted, as each
+    /// distinct value makes new metric series.
+    pub promoted_labels: Vec<String>,
+    pub promoted_annotations: Vec<String>,
+}
+
+impl Default for KubeConfig {
+    fn default() -> Self {
+        Self {
+            node_name: None,
+            watched_kinds: WatchedKind::value_variants().iter().copied().collect(),
+            promoted_labels: Vec::new(),
+            promoted_annotations: Vec::new(),
+        }
+    }
+}
+
+impl KubeConfig {
+    fn watches(&self, kind: WatchedKind) -> bool {
+        self.watched_kinds.contains(&kind)
+    }
+}
+
+/// Resolves addresses to the workloads of a Kubernetes cluster, from the
+/// objects watched on the API server.
+#[derive(Clone, Debug)]
+pub(crate) struct KubeMetadata {
+    pub config: KubeConfig,
+    pub pods: Store<Pod>,
+    pub nodes: Store<Node>,
+    pub services: Store<Service>,
+    pub endpoint_slices: Store<EndpointSlice>,
+    pub replicasets: Store<ReplicaSet>,
+    pub deployments: Store<Deployment>,
+    pub statefulsets: Store<StatefulSet>,
+    pub daemonsets: Store<DaemonSet>,
+    pub jobs: Store<Job>,
+    pub cronjobs: Store<CronJob>,
+    pub pod_descriptors: Cache<ObjectRef<Pod>, Workload>,
+    pub ips: Arc<RwLock<IpHistory>>,
+    pub ip_to_node: Cache<IpAddr, String>,
+    pub endpoints: Arc<RwLock<ServiceEndpoints>>,
+    pub container_to_workload: Cache<String, Workload>,
+    pub pod_uid_to_workload: Cache<String, Workload>,
+    /// The pod CIDRs of the nodes, by node name.
+    pub pod_cidrs: Arc<RwLock<AHashMap<String, Vec<Cidr>>>>,
+    processes: Arc<ProcessCache>,
+    lookups: Arc<Mutex<RemoteLookups>>,
+}
+
+macro_rules! spawn_watcher {
+    ($mgr:expr, $resource:ident, $writer:expr, $watcher:ident) => {{
+        let r = $mgr.clone();
+        let writer = $writer;
+        if r.config.watches(WatchedKind::$resource) {
+            tokio::spawn(async move {
+                let _ = r.$watcher(writer).await;
+            });
+        }
+    }};
+}
+
+impl KubeMetadata {
+    pub(crate) async fn new(config: KubeConfig) -> anyhow::Result<KubeMetadata> {
+        info!("Initializing cache manager");
+        let (pod_reader, pod_writer) = reflector::store::<Pod>();
+        let (node_reader, node_writer) = reflector::store::<Node>();
+        let (svc_reader, svc_writer) = reflector::store::<Service>();
+        let (slices_reader, slices_writer) = reflector::store::<EndpointSlice>();
+        let (rs_reader, rs_writer) = reflector::store::<ReplicaSet>();
+        let (deploy_reader, deploy_writer) = reflector::store::<Deployment>();
+        let (sts_reader, sts_writer) = reflector::store::<StatefulSet>();
+        let (ds_reader, ds_writer) = reflector::store::<DaemonSet>();
+        let (jobs_reader, jobs_writer) = reflector::store::<Job>();
+        let (cronjobs_reader, cronjobs_writer) = reflector::store::<CronJob>();
+
+        let cache_mgr = Self {
+            config,
+            pods: pod_reader,
+            nodes: node_reader,
+            services: svc_reader,
+            endpoint_slices: slices_reader,
+            replicasets: rs_reader,
+            deployments: deploy_reader,
+            statefulsets: sts_reader,
+            daemonsets: ds_reader,
+            jobs: jobs_reader,
+            cronjobs: cronjobs_reader,
+            pod_descriptors: Arc::new(RwLock::new(AHashMap::new())),
+            ips: Arc::new(RwLock::new(IpHistory::default())),
+            ip_to_node: Arc::new(RwLock::new(AHashMap::new())),
+            endpoints: Arc::new(RwLock::new(ServiceEndpoints::default())),
+            container_to_workload: Arc::new(RwLock::new(AHashMap::new())),
+            pod_uid_to_workload: Arc::new(RwLock::new(AHashMap::new())),
+            pod_cidrs: Arc::new(RwLock::new(AHashMap::new())),
+            processes: Arc::new(ProcessCache::default()),
+            lookups: Arc::new(Mutex::new(RemoteLookups::default())),
+        };
+
+        spawn_watcher!(cache_mgr, Pod, pod_writer, watching_pods);
+        spawn_watcher!(cache_mgr, Node, node_writer, watching_nodes);
+        spawn_watcher!(cache_mgr, Service, svc_writer, watching_services);
+        spawn_watcher!(
+            cache_mgr,
+            EndpointSlice,
+            slices_writer,
+            watching_endpoint_slices
+        );
+        spawn_watcher!(cache_mgr, ReplicaSet, rs_writer, watching_replicasets);
+        spawn_watcher!(cache_mgr, Deployment, deploy_writer, watching_deployments);
+        spawn_watcher!(cache_mgr, StatefulSet, sts_writer, watching_statefulsets);
+        spawn_watcher!(cache_mgr, DaemonSet, ds_writer, watching_daemonsets);
+        spawn_watcher!(cache_mgr, Job, jobs_writer, watching_jobs);
+        spawn_watcher!(cache_mgr, CronJob, cronjobs_writer, watching_cronjobs);
+
+        if cache_mgr.config.node_name.is_some() {
+            let r = cache_mgr.clone();
+            tokio::spawn(async move {
+                let _ = r.looking_up_remote_ips().await;
+            });
+        }
+
+        Ok(cache_mgr)
+    }
+
+    /// The metadata of the owner of a pod, or of the owner of an owner.
+    fn owner_metadata(&self, owner_ref: &OwnerReference, namespace: &str) -> Option<ObjectMeta> {
+        let name = owner_ref.name.as_str();
+        match owner_ref.kind.as_str() {
+            "ReplicaSet" => {
+                let obj_ref = ObjectRef::<ReplicaSet>::new(name).within(namespace);
+                self.replicasets.get(&obj_ref).map(|rs| rs.metadata.clone())
+            }
+            "Deployment" => {
+                let obj_ref = ObjectRef::<Deployment>::new(name).within(namespace);
+                self.deployments
+                    .get(&obj_ref)
+                    .map(|deployment| deployment.metadata.clone())
+            }
+            "DaemonSet" => {
+                let obj_ref = ObjectRef::<DaemonSet>::new(name).within(namespace);
+                self.daemonsets
+                    .get(&obj_ref)
+                    .map(|daemonset| daemonset.metadata.clone())
+            }
+            "StatefulSet" => {
+                let obj_ref = ObjectRef::<StatefulSet>::new(name).within(namespace);
+                self.statefulsets
+                    .get(&obj_ref)
+                    .map(|statefulset| statefulset.metadata.clone())
+            }
+            "Job" => {
+                let obj_ref = ObjectRef::<Job>::new(name).within(namespace);
+                self.jobs.get(&obj_ref).map(|job| job.metadata.clone())
+            }
+            "CronJob" => {
+                let obj_ref = ObjectRef::<CronJob>::new(name).within(namespace);
+                self.cronjobs
+                    .get(&obj_ref)
+                    .map(|cronjob| cronjob.metadata.clone())
+            }
+            _ => None,
+        }
+    }
+
+    async fn resolve_pod_descriptor(&self, pod: &Pod) -> Arc<Workload> {
+        // if pod already exists in the cache, return it
+        let entry = {
+            let pod_descriptors = self.pod_descriptors.read();
+            if let Some(entry) = pod_descriptors.get(&ObjectRef::from_obj(pod)) {
+                Some(entry.clone())
+            } else {
+                None
+            }
+        };
+
+        if let Some(entry) = entry {
+            return entry;
+        }
+
+        let mut name = pod.name_any();
+        let namespace = pod.namespace().unwrap_or_default();
+        let mut kind = "Pod".to_string();
+
+        let mut labels = BTreeMap::new();
+        let mut annotations = BTreeMap::new();
+        self.promote(&pod.metadata, &mut labels, &mut annotations);
+
+        let mut owner_ref = controller_of(&pod.metadata);
+        while let Some(owner) = owner_ref {
+            let Some(metadata) = self.owner_metadata(&owner, &namespace) else {
+                break;
+            };
+            // the labels of the pod take precedence over those of its owners
+            self.promote(&metadata, &mut labels, &mut annotations);
+            let controller = controller_of(&metadata);
+            if let Some(ref controller) = controller {
+                name = controller.name.clone();
+                kind = controller.kind.clone();
+            }
+            owner_ref = controller;
+        }
+
+        let entry = Arc::new(Workload {
+            name,
+            namespace,
+            kind,
+            labels,
+            annotations,
+        });
+        let mut pod_descriptors = self.pod_descriptors.write();
+        pod_descriptors.insert(ObjectRef::from_obj(pod), entry.clone());
+        entry
+    }
+
+    fn forget_pod_descriptor(&self, pod: &Pod) -> Option<Arc<Workload>> {
+        self.pod_descriptors
+            .write()
+            .remove(&ObjectRef::from_obj(pod))
+    }
+
+    /// Copies the allowlisted labels and annotations of an object which are
+    /// not set yet.
+    fn promote(
+        &self,
+        metadata: &ObjectMeta,
+        labels: &mut BTreeMap<String, String>,
+        annotations: &mut BTreeMap<String, String>,
+    ) {
+        let promoted = [
+            (&metadata.labels, &self.config.promoted_labels, labels),
+            (
+                &metadata.annotations,
+                &self.config.promoted_annotations,
+                annotations,
+            ),
+        ];
+        for (values, keys, target) in promoted {
+            let Some(values) = values.as_ref() else {
+                continue;
+            };
+            for key in keys {
+                if let Some(value) = values.get(key) {
+                    target.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+    }
+
+    async fn watching_pods(&self, mut writer: Writer<Pod>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<Pod> = Api::all(client);
+        let mut config = watcher::Config::default().any_semantic();
+        if let Some(node_name) = self.config.node_name.as_ref() {
+            config = config.fields(&format!("spec.nodeName={}", node_name));
+        }
+        let promoted = self.config.promoted_annotations.clone();
+        let stream = watcher(api, config).default_backoff().modify(move |pod| {
+            // only the node is needed to know the topology of the pod
+            pod.spec = pod.spec.take().map(|spec| PodSpec {
+                node_name: spec.node_name,
+                host_network: spec.host_network,
+                ..Default::default()
+            });
+            pod.managed_fields_mut().clear();
+            pod.annotations_mut()
+                .retain(|key, _| promoted.contains(key));
+        });
+        futures::pin_mut!(stream);
+
+        // deleted pods are needed to release their IPs, the store is updated
+        // last for a relist to be compared with the pods it held
+        while let Some(event) = stream.try_next().await? {
+            let now = monotonic_now()?;
+            match &event {
+                watcher::Event::Applied(pod) => self.index_pod(pod, now).await,
+                watcher::Event::Deleted(pod) => self.release_pod_ips(pod, now).await,
+                watcher::Event::Restarted(pods) => {
+                    for pod in unlisted(self.pods.state(), pods) {
+                        self.release_pod_ips(&pod, now).await;
+                    }
+                    for pod in pods {
+                        self.index_pod(pod, now).await;
+                    }
+                }
+            }
+            writer.apply_watcher_event(&event);
+        }
+
+        Ok(())
+    }
+
+    /// Indexes a pod by its IPs, its uid and the ids of its containers.
+    async fn index_pod(&self, pod: &Pod, now: Duration) {
+        let phase = pod
+            .status
+            .as_ref()
+            .and_then(|status| status.phase.as_deref());
+        if matches!(phase, Some("Succeeded" | "Failed")) {
+            self.release_pod_ips(pod, now).await;
+            return;
+        }
+        if self.promoted_changed(pod) {
+            self.forget_pod_descriptor(pod);
+        }
+        let entry = self.resolve_pod_descriptor(pod).await;
+        if let Some(uid) = pod.uid() {
+            self.pod_uid_to_workload.write().insert(uid, entry.clone());
+        }
+        self.container_to_workload
+            .write()
+            .extend(container_ids(pod).into_iter().map(|id| (id, entry.clone())));
+
+        // the IPs of the pods on the host network are the IPs of their node
+        if is_host_network(pod) {
+            return;
+        }
+        let node = pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.node_name.clone())
+            .map(Arc::new);
+        let mut ips = self.ips.write();
+        let mut nodes = self.ip_to_node.write();
+        for ip in pod_ips(pod) {
+            ips.insert(ip, entry.clone(), now);
+            if let Some(node) = node.as_ref() {
+                nodes.insert(ip, node.clone());
+            }
+        }
+    }
+
+    /// Whether the labels and annotations promoted from a pod changed since the
+    /// version of the pod in the store, which is updated after the event.
+    fn promoted_changed(&self, pod: &Pod) -> bool {
+        let Some(stored) = self.pods.get(&ObjectRef::from_obj(pod)) else {
+            return false;
+        };
+        let promoted = |metadata: &ObjectMeta| {
+            let (mut labels, mut annotations) = (BTreeMap::new(), BTreeMap::new());
+            self.promote(metadata, &mut labels, &mut annotations);
+            (labels, annotations)
+        };
+        promoted(&stored.metadata) != promoted(&pod.metadata)
+    }
+
+    /// Leaves a tombstone for the IPs of a pod which is gone, unless they were
+    /// already taken by another workload, and forgets its descriptor and its
+    /// containers.
+    async fn release_pod_ips(&self, pod: &Pod, now: Duration) {
+        if let Some(uid) = pod.uid() {
+            self.pod_uid_to_workload.write().remove(&uid);
+        }
+        {
+            let mut containers = self.container_to_workload.write();
+            for id in container_ids(pod) {
+                containers.remove(&id);
+            }
+        }
+
+        let Some(entry) = self.forget_pod_descriptor(pod) else {
+            return;
+        };
+        if is_host_network(pod) {
+            return;
+        }
+        let mut ips = self.ips.write();
+        for ip in pod_ips(pod) {
+            if ips.current(ip).as_ref() == Some(&entry) {
+                ips.remove(ip, now);
+            }
+        }
+    }
+
+    /// Looks up the pods of the addresses `resolve_ip` missed, when only the
+    /// pods of the local node are watched, see `RemoteLookups`.
+    async fn looking_up_remote_ips(&self) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<Pod> = Api::all(client);
+        let mut interval = tokio::time::interval(LOOKUP_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            let (expired, due) = {
+                let mut lookups = self.lookups.lock();
+                let now = Instant::now();
+                (lookups.expire(now), lookups.due(now))
+            };
+            for (ip, workload) in expired {
+                self.release_remote_ip(ip, &workload)?;
+            }
+            for ip in due {
+                let params = ListParams::default().fields(&format!("status.podIP={}", ip));
+                let pods = match api.list(&params).await {
+                    Ok(pods) => pods,
+                    Err(e) => {
+                        warn!("Failed to look up pod IP {}: {:?}", ip, e);
+                        continue;
+                    }
+                };
+                match pods.items.first() {
+                    Some(pod) => {
+                        let workload = self.resolve_pod_descriptor(pod).await;
+                        self.index_pod(pod, monotonic_now()?).await;
+                        // the pod is not watched, its descriptor is rebuilt on
+                        // the next lookup rather than kept forever
+                        self.forget_pod_descriptor(pod);
+                        self.lookups.lock().found(ip, workload, Instant::now());
+                    }
+                    None => {
+                        let released = self.lookups.lock().not_found(ip, Instant::now());
+                        if let Some(workload) = released {
+                            self.release_remote_ip(ip, &workload)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Leaves a tombstone for an address resolved by a lookup, unless it was
+    /// taken by another workload since.
+    fn release_remote_ip(&self, ip: IpAddr, workload: &Arc<Workload>) -> anyhow::Result<()> {
+        let mut ips = self.ips.write();
+        if ips.current(ip).as_ref() == Some(workload) {
+            ips.remove(ip, monotonic_now()?);
+        }
+        Ok(())
+    }
+
+    /// Queues the lookup of a missed address which may be a pod IP, and keeps
+    /// the lookups of the resolved ones refreshed.
+    fn look_up_missed(&self, ip: IpAddr, workload: Option<Arc<Workload>>) -> Option<Arc<Workload>> {
+        if self.config.node_name.is_none() {
+            return workload;
+        }
+        let now = Instant::now();
+        if workload.is_some() {
+            self.lookups.lock().asked(ip, now);
+        } else if self.in_pod_cidrs(ip) {
+            self.lookups.lock().queue(ip, now);
+        }
+        workload
+    }
+
+    /// Whether an address is in the pod CIDR of a node, any address may be a
+    /// pod IP when the nodes have none.
+    fn in_pod_cidrs(&self, ip: IpAddr) -> bool {
+        let pod_cidrs = self.pod_cidrs.read();
+        pod_cidrs.values().all(Vec::is_empty)
+            || pod_cidrs.values().flatten().any(|cidr| cidr.contains(ip))
+    }
+
+    async fn watching_nodes(&self, writer: Writer<Node>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<Node> = Api::all(client);
+
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .modify(|node| {
+                node.spec = node.spec.take().map(|spec| NodeSpec {
+                    pod_cidrs: spec.pod_cidrs,
+                    ..Default::default()
+                });
+                node.metadata.managed_fields = None;
+                node.metadata.annotations = None;
+            })
+            .reflect(writer)
+            .applied_objects()
+            .predicate_filter(predicates::resource_version);
+        futures::pin_mut!(stream);
+
+        while let Some(node) = stream.try_next().await? {
+            let name = Arc::new(node.name_any());
+            // invalid CIDRs are skipped
+            let pod_cidrs = node
+                .spec
+                .iter()
+                .flat_map(|spec| spec.pod_cidrs.iter().flatten())
+                .filter_map(|cidr| cidr.parse().ok())
+                .collect();
+            self.pod_cidrs.write().insert(node.name_any(), pod_cidrs);
+            let now = monotonic_now()?;
+            let mut ips = self.ips.write();
+            let mut nodes = self.ip_to_node.write();
+            if let Some(status) = node.status.as_ref() {
+                if let Some(addresses) = status.addresses.as_ref() {
+                    // Hostname addresses are not IPs and are skipped.
+                    for addr in addresses.iter().filter_map(|a| parse_ip(&a.address)) {
+                        nodes.insert(addr, name.clone());
+                        ips.insert(
+                            addr,
+                            Arc::new(Workload {
+                                name: node.name_any(),
+                                namespace: "node".to_string(),
+                                kind: "Node".to_string(),
+                                ..Default::default()
+                            }),
+                            now,
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn watching_services(&self, mut writer: Writer<Service>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<Service> = Api::all(client);
+
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .modify(|service| {
+                service.metadata.managed_fields = None;
+                service.metadata.annotations = None;
+            });
+        futures::pin_mut!(stream);
+
+        // deleted Services are needed to release their ClusterIPs, the store is
+        // updated last for a relist to be compared with the Services it held
+        while let Some(event) = stream.try_next().await? {
+            let now = monotonic_now()?;
+            match &event {
+                watcher::Event::Applied(service) => self.index_service(service, now),
+                watcher::Event::Deleted(service) => self.release_service(service, now),
+                watcher::Event::Restarted(services) => {
+                    for service in unlisted(self.services.state(), services) {
+                        self.release_service(&service, now);
+                    }
+                    for service in services {
+                        self.index_service(service, now);
+                    }
+                }
+            }
+            writer.apply_watcher_event(&event);
+        }
+
+        Ok(())
+    }
+
+    /// Indexes a Service by its ClusterIPs, and the endpoints of its ports.
+    fn index_service(&self, service: &Service, now: Duration) {
+        let workload = service_workload(service);
+        let cluster_ips = cluster_ips(service);
+        {
+            let mut ips = self.ips.write();
+            for ip in cluster_ips.iter() {
+                ips.insert(*ip, workload.clone(), now);
+            }
+        }
+        if let (Some(uid), Some(spec)) = (service.uid(), service.spec.as_ref()) {
+            let ports = spec
+                .ports
+                .iter()
+                .flatten()
+                .map(|port| (Some(port.port), port.protocol.clone(), port.name.clone()));
+            let endpoints = service_endpoints(workload, &cluster_ips, ports);
+            self.endpoints.write().update(&uid, endpoints);
+        }
+    }
+
+    /// Leaves a tombstone for the ClusterIPs of a deleted Service, unless they
+    /// were already taken by another Service, and drops its endpoints.
+    fn release_service(&self, service: &Service, now: Duration) {
+        let workload = service_workload(service);
+        {
+            let mut ips = self.ips.write();
+            for ip in cluster_ips(service) {
+                if ips.current(ip).as_ref() == Some(&workload) {
+                    ips.remove(ip, now);
+                }
+            }
+        }
+        if let Some(uid) = service.uid() {
+            self.endpoints.write().remove(&uid);
+        }
+    }
+
+    /// Indexes the pod IPs and target ports of the Services, the EndpointSlices
+    /// of all the nodes being watched as the servers are mostly remote.
+    async fn watching_endpoint_slices(
+        &self,
+        mut writer: Writer<EndpointSlice>,
+    ) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<EndpointSlice> = Api::all(client);
+
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .modify(|slice| {
+                slice.metadata.managed_fields = None;
+                slice.metadata.annotations = None;
+            });
+        futures::pin_mut!(stream);
+
+        // deleted slices are needed to drop their endpoints, as for Services
+        while let Some(event) = stream.try_next().await? {
+            match &event {
+                watcher::Event::Applied(slice) => self.index_endpoint_slice(slice),
+                watcher::Event::Deleted(slice) => {
+                    if let Some(uid) = slice.uid() {
+                        self.endpoints.write().remove(&uid);
+                    }
+                }
+                watcher::Event::Restarted(slices) => {
+                    for slice in unlisted(self.endpoint_slices.state(), slices) {
+                        if let Some(uid) = slice.uid() {
+                            self.endpoints.write().remove(&uid);
+                        }
+                    }
+                    for slice in slices {
+                        self.index_endpoint_slice(slice);
+                    }
+                }
+            }
+            writer.apply_watcher_event(&event);
+        }
+
+        Ok(())
+    }
+
+    fn index_endpoint_slice(&self, slice: &EndpointSlice) {
+        // the slices which are not managed for a Service have no service name
+        let (Some(uid), Some(name)) = (slice.uid(), slice.labels().get(SERVICE_NAME_LABEL)) else {
+            return;
+        };
+        let service = Arc::new(Workload {
+            name: name.clone(),
+            namespace: slice.namespace().unwrap_or_default(),
+            kind: "Service".to_string(),
+            ..Default::default()
+        });
+        // FQDN addresses are not IPs and are skipped
+        let addresses = slice
+            .endpoints
+            .iter()
+            .flat_map(|endpoint| &endpoint.addresses);
+        let ips: Vec<IpAddr> = addresses.filter_map(|address| parse_ip(address)).collect();
+        let ports = slice
+            .ports
+            .iter()
+            .flatten()
+            .map(|port| (port.port, port.protocol.clone(), port.name.clone()));
+        let endpoints = service_endpoints(service, &ips, ports);
+        self.endpoints.write().update(&uid, endpoints);
+    }
+
+    async fn watching_replicasets(&self, writer: Writer<ReplicaSet>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<ReplicaSet> = Api::all(client);
+
+        let promoted = self.config.promoted_annotations.clone();
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .modify(move |replicaset| {
+                replicaset.spec = None;
+                replicaset.metadata.managed_fields = None;
+                retain_promoted(&mut replicaset.metadata.annotations, &promoted);
+            })
+            .reflect(writer)
+            .applied_objects()
+            .predicate_filter(predicates::resource_version);
+        futures::pin_mut!(stream);
+
+        stream.for_each(|_| futures::future::ready(())).await;
+
+        Ok(())
+    }
+
+    async fn watching_deployments(&self, writer: Writer<Deployment>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<Deployment> = Api::all(client);
+
+        let promoted = self.config.promoted_annotations.clone();
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .modify(move |deployment| {
+                deployment.spec = None;
+                deployment.metadata.managed_fields = None;
+                retain_promoted(&mut deployment.metadata.annotations, &promoted);
+            })
+            .reflect(writer)
+            .applied_objects()
+            .predicate_filter(predicates::resource_version);
+        futures::pin_mut!(stream);
+        stream.for_each(|_| futures::future::ready(())).await;
+        Ok(())
+    }
+
+    async fn watching_daemonsets(&self, writer: Writer<DaemonSet>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<DaemonSet> = Api::all(client);
+
+        let promoted = self.config.promoted_annotations.clone();
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .modify(move |daemonset| {
+                daemonset.spec = None;
+                daemonset.metadata.managed_fields = None;
+                retain_promoted(&mut daemonset.metadata.annotations, &promoted);
+            })
+            .reflect(writer)
+            .applied_objects()
+            .predicate_filter(predicates::resource_version);
+        futures::pin_mut!(stream);
+        stream.for_each(|_| futures::future::ready(())).await;
+
+        Ok(())
+    }
+
+    async fn watching_statefulsets(&self, writer: Writer<StatefulSet>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<StatefulSet> = Api::all(client);
+
+        let promoted = self.config.promoted_annotations.clone();
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .modify(move |statefulset| {
+                statefulset.spec = None;
+                statefulset.metadata.managed_fields = None;
+                retain_promoted(&mut statefulset.metadata.annotations, &promoted);
+            })
+            .reflect(writer)
+            .applied_objects()
+            .predicate_filter(predicates::resource_version);
+        futures::pin_mut!(stream);
+        stream.for_each(|_| futures::future::ready(())).await;
+        Ok(())
+    }
+
+    async fn watching_jobs(&self, writer: Writer<Job>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<Job> = Api::all(client);
+
+        let promoted = self.config.promoted_annotations.clone();
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .modify(move |job| {
+                job.spec = None;
+                job.metadata.managed_fields = None;
+                retain_promoted(&mut job.metadata.annotations, &promoted);
+            })
+            .reflect(writer)
+            .applied_objects()
+            .predicate_filter(predicates::resource_version);
+        futures::pin_mut!(stream);
+        stream.for_each(|_| futures::future::ready(())).await;
+        Ok(())
+    }
+
+    async fn watching_cronjobs(&self, writer: Writer<CronJob>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<CronJob> = Api::all(client);
+
+        let promoted = self.config.promoted_annotations.clone();
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .modify(move |cronjob| {
+                cronjob.spec = None;
+                cronjob.metadata.managed_fields = None;
+                retain_promoted(&mut cronjob.metadata.annotations, &promoted);
+            })
+            .reflect(writer)
+            .applied_objects()
+            .predicate_filter(predicates::resource_version);
+        futures::pin_mut!(stream);
+        stream.for_each(|_| futures::future::ready(())).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MetadataProvider for KubeMetadata {
+    async fn wait_until_ready(&self) -> anyhow::Result<()> {
+        // the stores of the kinds which are not watched are never ready
+        if self.config.watches(WatchedKind::Pod) {
+            self.pods.wait_until_ready().await?;
+        }
+        if self.config.watches(WatchedKind::Node) {
+            self.nodes.wait_until_ready().await?;
+        }
+        if self.config.watches(WatchedKind::Service) {
+            self.services.wait_until_ready().await?;
+        }
+        if self.config.watches(WatchedKind::EndpointSlice) {
+            self.endpoint_slices.wait_until_ready().await?;
+        }
+        if self.config.watches(WatchedKind::ReplicaSet) {
+            self.replicasets.wait_until_ready().await?;
+        }
+        if self.config.watches(WatchedKind::Deployment) {
+            self.deployments.wait_until_ready().await?;
+        }
+        if self.config.watches(WatchedKind::StatefulSet) {
+            self.statefulsets.wait_until_ready().await?;
+        }
+        if self.config.watches(WatchedKind::DaemonSet) {
+            self.daemonsets.wait_until_ready().await?;
+        }
+        if self.config.watches(WatchedKind::Job) {
+            self.jobs.wait_until_ready().await?;
+        }
+        if self.config.watches(WatchedKind::CronJob) {
+            self.cronjobs.wait_until_ready().await?;
+        }
+
+        info!("Cache sync complete");
+        Ok(())
+    }
+
+    /// Looks up the workload owning an address. IPv4-mapped IPv6 addresses are
+    /// looked up as the IPv4 address they carry. When only the local pods are
+    /// watched, a missed address is looked up in the background and resolves
+    /// on a later call.
+    fn resolve_ip(&self, ip: IpAddr) -> Option<Arc<Workload>> {
+        let ip = ip.to_canonical();
+        let workload = self.ips.read().current(ip);
+        self.look_up_missed(ip, workload)
+    }
+
+    /// Looks up the workload which owned an address at a time on the monotonic
+    /// clock, even when the address was released or taken by another workload
+    /// since.
+    fn resolve_ip_at(&self, ip: IpAddr, at: Duration) -> Option<Arc<Workload>> {
+        let ip = ip.to_canonical();
+        let workload = self.ips.read().lookup(ip, at);
+        self.look_up_missed(ip, workload)
+    }
+
+    fn skip_lookup(&self, ip: IpAddr) {
+        if self.config.node_name.is_some() {
+            self.lookups.lock().skip(ip.to_canonical(), Instant::now());
+        }
+    }
+
+    /// Looks up the pod of a process of the local node, from the container or
+    /// the pod its cgroup belongs to.
+    fn resolve_pid(&self, pid: u32) -> Option<Arc<Workload>> {
+        self.processes.get_or_resolve(pid, |cgroup| {
+            let cgroup_ref = parse_cgroup(cgroup);
+            cgroup_ref
+                .container_id
+                .and_then(|id| self.container_to_workload.read().get(&id).cloned())
+                .or_else(|| {
+                    let uid = cgroup_ref.pod_uid?;
+                    self.pod_uid_to_workload.read().get(&uid).cloned()
+                })
+        })
+    }
+
+    /// Looks up the Service port of a ClusterIP and a port of its Service, or of
+    /// a pod IP and a target port listed by an EndpointSlice of the Service.
+    fn resolve_service(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        protocol: &str,
+    ) -> Option<Arc<ServiceEndpoint>> {
+        let key = EndpointKey {
+            ip: ip.to_canonical(),
+            port,
+            protocol: protocol.to_string(),
+        };
+        self.endpoints.read().get(&key)
+    }
+
+    /// Looks up the zone and region of the node an address belongs to, from
+    /// the topology labels of the node.
+    fn resolve_topology(&self, ip: IpAddr) -> Option<Topology> {
+        let node_name = self.ip_to_node.read().get(&ip.to_canonical()).cloned()?;
+        let node = self.nodes.get(&ObjectRef::new(node_name.as_str()))?;
+        let labels = node.labels();
+        let label = |key: &str, legacy_key: &str| {
+            labels
+                .get(key)
+                .or_else(|| labels.get(legacy_key))
+                .cloned()
+                .unwrap_or_default()
+        };
+        Some(Topology {
+            zone: label(ZONE_LABEL, LEGACY_ZONE_LABEL),
+            region: label(REGION_LABEL, LEGACY_REGION_LABEL),
+        })
+    }
+}
+
+/// The endpoints of the ports of a Service on its addresses, each port being a
+/// number, a protocol and a name.
+fn service_endpoints(
+    service: Arc<Workload>,
+    ips: &[IpAddr],
+    ports: impl Iterator<Item = (Option<i32>, Option<String>, Option<String>)>,
+) -> Vec<(EndpointKey, Arc<ServiceEndpoint>)> {
+    let mut endpoints = Vec::new();
+    for (port, protocol, name) in ports {
+        let Some(port) = port.and_then(|port| u16::try_from(port).ok()) else {
+            continue;
+        };
+        let protocol = protocol.unwrap_or_else(|| DEFAULT_PROTOCOL.to_string());
+        let endpoint = Arc::new(ServiceEndpoint {
+            service: service.clone(),
+            port_name: name.unwrap_or_default(),
+        });
+        for ip in ips {
+            let key = EndpointKey {
+                ip: *ip,
+                port,
+                protocol: protocol.clone(),
+            };
+            endpoints.push((key, endpoint.clone()));
+        }
+    }
+    endpoints
+}
+
+/// The objects of a store missing from a relist, which were deleted while the
+/// watch was down.
+fn unlisted<K: Resource>(stored: Vec<Arc<K>>, listed: &[K]) -> Vec<Arc<K>> {
+    let listed: HashSet<String> = listed.iter().filter_map(|object| object.uid()).collect();
+    stored
+        .into_iter()
+        .filter(|object| object.uid().is_some_and(|uid| !listed.contains(&uid)))
+        .collect()
+}
+
+fn controller_of(metadata: &ObjectMeta) -> Option<OwnerReference> {
+    metadata
+        .owner_references
+        .as_ref()
+        .and_then(|refs| refs.iter().find(|r| r.controller == Some(true)))
+        .cloned()
+}
+
+/// Drops the annotations which are not promoted, they are not needed otherwise.
+fn retain_promoted(annotations: &mut Option<BTreeMap<String, String>>, promoted: &[String]) {
+    if let Some(annotations) = annotations.as_mut() {
+        annotations.retain(|key, _| promoted.contains(key));
+    }
+}
+
+fn is_host_network(pod: &Pod) -> bool {
+    pod.spec
+        .as_ref()
+        .and_then(|spec| spec.host_network)
+        .unwrap_or(false)
+}
+
+fn service_workload(service: &Service) -> Arc<Workload> {
+    Arc::new(Workload {
+        name: service.name_any(),
+        namespace: service.namespace().unwrap_or_default(),
+        kind: "Service".to_string(),
+        ..Default::default()
+    })
+}
+
+/// The ClusterIPs of a Service, none for a headless Service.
+fn cluster_ips(service: &Service) -> Vec<IpAddr> {
+    let cluster_ips = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.cluster_ips.as_ref());
+    cluster_ips
+        .into_iter()
+        .flatten()
+        .filter(|ip| *ip != "None")
+        .filter_map(|ip| {
+            let parsed = parse_ip(ip);
+            if parsed.is_none() {
+                debug!("Failed to parse IP: {:?}, skipping", ip);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// The ids of the containers of a pod, its init containers included.
+fn container_ids(pod: &Pod) -> Vec<String> {
+    let Some(status) = pod.status.as_ref() else {
+        return Vec::new();
+    };
+    let statuses = status.container_statuses.iter().flatten();
+    let init_statuses = status.init_container_statuses.iter().flatten();
+    statuses
+        .chain(init_statuses)
+        .filter_map(|container| container.container_id.as_deref())
+        .map(|id| strip_runtime(id).to_string())
+        .collect()
+}
+
+fn pod_ips(pod: &Pod) -> Vec<IpAddr> {
+    let pod_ips = pod
+        .status
+        .as_ref()
+        .and_then(|status| status.pod_ips.as_ref());
+    pod_ips
+        .into_iter()
+        .flatten()
+        .filter_map(|ip| {
+            let parsed = ip.ip.as_deref().and_then(parse_ip);
+            if parsed.is_none() {
+                debug!("Invalid pod IP {:?}, skipping", ip.ip);
+            }
+            parsed
+        })
+        .collect()
+}
@@ -0,0 +1,190 @@
+//! Metadata of a host which is not part of a Kubernetes cluster.
+//!
+//! Addresses are resolved from a static file of `address namespace/name [kind]`
+//! lines, where the address is an IP or a hostname resolved when the file is
+//! loaded, and `#` starts a comment. The addresses of the host itself resolve
+//! to the host, and processes to the systemd unit their cgroup belongs to, or
+//! to their command name.
+
+use std::fs;
+use std::net::{IpAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use anyhow::Context;
+use log::debug;
+use nix::ifaddrs::getifaddrs;
+
+use crate::managers::cache::process::{cgroup_paths, ProcessCache};
+use crate::managers::cache::{parse_ip, MetadataProvider, Workload};
+
+const PROC_ROOT: &str = "/proc";
+const DEFAULT_KIND: &str = "Host";
+const PROCESS_KIND: &str = "Process";
+const UNIT_KIND: &str = "SystemdUnit";
+const UNIT_SUFFIXES: [&str; 2] = [".service", ".scope"];
+
+#[derive(Debug)]
+pub(crate) struct LocalMetadata {
+    hostname: String,
+    ip_to_workload: AHashMap<IpAddr, Arc<Workload>>,
+    processes: ProcessCache,
+}
+
+impl LocalMetadata {
+    pub(crate) fn new(static_file: Option<&Path>) -> anyhow::Result<LocalMetadata> {
+        let hostname = fs::read_to_string(Path::new(PROC_ROOT).join("sys/kernel/hostname"))
+            .map(|name| name.trim().to_string())
+            .unwrap_or_default();
+        let mut ip_to_workload = AHashMap::new();
+
+        let host = Arc::new(Workload {
+            name: hostname.clone(),
+            namespace: "node".to_string(),
+            kind: "Node".to_string(),
+            ..Default::default()
+        });
+        for ifaddr in getifaddrs()? {
+            let Some(addr) = ifaddr.address else {
+                continue;
+            };
+            let ip = if let Some(sin) = addr.as_sockaddr_in() {
+                IpAddr::V4(*SocketAddrV4::from(*sin).ip())
+            } else if let Some(sin6) = addr.as_sockaddr_in6() {
+                IpAddr::V6(*SocketAddrV6::from(*sin6).ip())
+            } else {
+                continue;
+            };
+            ip_to_workload.insert(ip.to_canonical(), host.clone());
+        }
+
+        if let Some(path) = static_file {
+            let entries = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            for (address, workload) in parse_static_entries(&entries)? {
+                let workload = Arc::new(workload);
+                for ip in resolve_address(&address) {
+                    ip_to_workload.insert(ip, workload.clone());
+                }
+            }
+        }
+
+        Ok(Self {
+            hostname,
+            ip_to_workload,
+            processes: ProcessCache::default(),
+        })
+    }
+}
+
+impl MetadataProvider for LocalMetadata {
+    fn resolve_ip(&self, ip: IpAddr) -> Option<Arc<Workload>> {
+        self.ip_to_workload.get(&ip.to_canonical()).cloned()
+    }
+
+    fn resolve_pid(&self, pid: u32) -> Option<Arc<Workload>> {
+        self.processes.get_or_resolve(pid, |cgroup| {
+            let (name, kind) = match systemd_unit(cgroup) {
+                Some(unit) => (unit.to_string(), UNIT_KIND),
+                None => {
+                    let comm_path = Path::new(PROC_ROOT).join(format!("{}/comm", pid));
+                    let comm = fs::read_to_string(comm_path).ok()?;
+                    (comm.trim().to_string(), PROCESS_KIND)
+                }
+            };
+            Some(Arc::new(Workload {
+                name,
+                namespace: self.hostname.clone(),
+                kind: kind.to_string(),
+                ..Default::default()
+            }))
+        })
+    }
+}
+
+fn parse_static_entries(entries: &str) -> anyhow::Result<Vec<(String, Workload)>> {
+    let mut parsed = Vec::new();
+    for line in entries.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(address), Some(workload)) = (fields.next(), fields.next()) else {
+            return Err(anyhow::anyhow!("Invalid static entry {}", line));
+        };
+        let (namespace, name) = workload
+            .split_once('/')
+            .ok_or(anyhow::anyhow!("Invalid static workload {}", workload))?;
+        parsed.push((
+            address.to_string(),
+            Workload {
+                name: name.to_string(),
+                namespace: namespace.to_string(),
+                kind: fields.next().unwrap_or(DEFAULT_KIND).to_string(),
+                ..Default::default()
+            },
+        ));
+    }
+    Ok(parsed)
+}
+
+fn resolve_address(address: &str) -> Vec<IpAddr> {
+    if let Some(ip) = parse_ip(address) {
+        return vec![ip];
+    }
+    match (address, 0).to_socket_addrs() {
+        Ok(addrs) => addrs.map(|addr| addr.ip().to_canonical()).collect(),
+        Err(e) => {
+            debug!("Failed to resolve {}: {:?}", address, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Finds the systemd unit in the `/proc/<pid>/cgroup` of a process.
+fn systemd_unit(cgroup: &str) -> Option<&str> {
+    cgroup_paths(cgroup).into_iter().find_map(|path| {
+        path.rsplit('/')
+            .find(|segment| UNIT_SUFFIXES.iter().any(|suffix| segment.ends_with(suffix)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_static_entries() {
+        let entries = "\
+# databases
+10.20.0.5   payments/postgres  Database
+db.internal payments/replica
+
+::1 local/loopback # comment";
+        let parsed = parse_static_entries(entries).unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0].0, "10.20.0.5");
+        assert_eq!(parsed[0].1.name, "postgres");
+        assert_eq!(parsed[0].1.namespace, "payments");
+        assert_eq!(parsed[0].1.kind, "Database");
+        assert_eq!(parsed[1].1.kind, DEFAULT_KIND);
+        assert_eq!(parsed[2].1.name, "loopback");
+
+        assert!(parse_static_entries("10.20.0.5").is_err());
+        assert!(parse_static_entries("10.20.0.5 postgres").is_err());
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let v2 = "0::/system.slice/nginx.service\n";
+        assert_eq!(systemd_unit(v2), Some("nginx.service"));
+        let v1 = "12:pids:/user.slice/user-1000.slice/session-2.scope\n\
+                  1:name=systemd:/system.slice/sshd.service\n\
+                  0::/system.slice/sshd.service\n";
+        assert_eq!(systemd_unit(v1), Some("sshd.service"));
+        assert_eq!(systemd_unit("0::/\n"), None);
+        assert_eq!(systemd_unit(""), None);
+    }
+}
@@ -0,0 +1,229 @@
+//! On demand lookups of the addresses of the pods outside the local node.
+//!
+//! When only the pods of the local node are watched, an address `resolve_ip`
+//! missed is queued and looked up on the API server in the background. The pods
+//! found this way are not watched, so they are looked up again after a TTL, and
+//! their address is released once it is no longer a pod IP. Addresses which
+//! were not pod IPs are forgotten after the same delay, to be queued again on
+//! their next miss.
+//!
+//! Only the addresses asked about within the TTL are kept, at most
+//! `MAX_LOOKUPS` of them, so that a scan of the network cannot make the agent
+//! look up addresses on the API server forever.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+
+use crate::managers::cache::Workload;
+
+const LOOKUP_RETRY: Duration = Duration::from_secs(300);
+const MAX_LOOKUPS: usize = 4096;
+
+#[derive(Debug, Clone)]
+enum State {
+    Pending,
+    Resolved(Instant, Arc<Workload>),
+    Missed(Instant),
+}
+
+#[derive(Debug, Clone)]
+struct Lookup {
+    state: State,
+    asked_at: Instant,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct RemoteLookups {
+    lookups: AHashMap<IpAddr, Lookup>,
+}
+
+impl RemoteLookups {
+    /// Queues the lookup of an address, unless it was already looked up or
+    /// too many addresses are.
+    pub(crate) fn queue(&mut self, ip: IpAddr, now: Instant) {
+        if let Some(lookup) = self.lookups.get_mut(&ip) {
+            lookup.asked_at = now;
+        } else if self.lookups.len() < MAX_LOOKUPS {
+            let lookup = Lookup {
+                state: State::Pending,
+                asked_at: now,
+            };
+            self.lookups.insert(ip, lookup);
+        }
+    }
+
+    /// Records that an address resolved, which keeps its lookup refreshed.
+    pub(crate) fn asked(&mut self, ip: IpAddr, now: Instant) {
+        if let Some(lookup) = self.lookups.get_mut(&ip) {
+            lookup.asked_at = now;
+        }
+    }
+
+    /// Cancels the queued lookup of an address known not to be a pod IP, which
+    /// is not queued again until the retry delay passed.
+    pub(crate) fn skip(&mut self, ip: IpAddr, now: Instant) {
+        if let Some(lookup) = self.lookups.get_mut(&ip) {
+            if matches!(lookup.state, State::Pending) {
+                lookup.state = State::Missed(now);
+            }
+        }
+    }
+
+    /// Forgets the misses older than `LOOKUP_RETRY` and the addresses nobody
+    /// asked about for as long. Returns the workloads of the addresses which
+    /// were resolved, for their address to be released.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<(IpAddr, Arc<Workload>)> {
+        let expired = |at: &Instant| now.saturating_duration_since(*at) >= LOOKUP_RETRY;
+        let mut released = Vec::new();
+        self.lookups.retain(|ip, lookup| match &lookup.state {
+            State::Missed(at) => !expired(at),
+            State::Resolved(_, workload) if expired(&lookup.asked_at) => {
+                released.push((*ip, workload.clone()));
+                false
+            }
+            _ => true,
+        });
+        released
+    }
+
+    /// The addresses to look up: the queued ones and the ones resolved longer
+    /// than `LOOKUP_RETRY` ago.
+    pub(crate) fn due(&self, now: Instant) -> Vec<IpAddr> {
+        let expired = |at: &Instant| now.saturating_duration_since(*at) >= LOOKUP_RETRY;
+        self.lookups
+            .iter()
+            .filter(|(_, lookup)| match &lookup.state {
+                State::Pending => true,
+                State::Resolved(at, _) => expired(at),
+                State::Missed(_) => false,
+            })
+            .map(|(ip, _)| *ip)
+            .collect()
+    }
+
+    /// Records the workload of the pod found for an address.
+    pub(crate) fn found(&mut self, ip: IpAddr, workload: Arc<Workload>, now: Instant) {
+        self.set_state(ip, State::Resolved(now, workload), now);
+    }
+
+    /// Records that no pod has an address. Returns the workload the address
+    /// was resolved to before, for its address to be released.
+    pub(crate) fn not_found(&mut self, ip: IpAddr, now: Instant) -> Option<Arc<Workload>> {
+        match self.set_state(ip, State::Missed(now), now) {
+            Some(State::Resolved(_, workload)) => Some(workload),
+            _ => None,
+        }
+    }
+
+    fn set_state(&mut self, ip: IpAddr, state: State, now: Instant) -> Option<State> {
+        match self.lookups.get_mut(&ip) {
+            Some(lookup) => Some(std::mem::replace(&mut lookup.state, state)),
+            None => {
+                let lookup = Lookup {
+                    state,
+                    asked_at: now,
+                };
+                self.lookups.insert(ip, lookup);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workload(name: &str) -> Arc<Workload> {
+        Arc::new(Workload {
+            name: name.to_string(),
+            namespace: "default".to_string(),
+            kind: "Deployment".to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_resolved_ip_expires() {
+        let ip: IpAddr = "10.0.1.1".parse().unwrap();
+        let now = Instant::now();
+        let mut lookups = RemoteLookups::default();
+        lookups.queue(ip, now);
+        assert_eq!(lookups.due(now), vec![ip]);
+
+        lookups.found(ip, workload("a"), now);
+        lookups.queue(ip, now);
+        assert!(lookups.due(now + LOOKUP_RETRY / 2).is_empty());
+
+        // the pod is not watched, it is looked up again once the TTL expired
+        let later = now + LOOKUP_RETRY;
+        lookups.asked(ip, later);
+        assert!(lookups.expire(later).is_empty());
+        assert_eq!(lookups.due(later), vec![ip]);
+        lookups.found(ip, workload("b"), later);
+        assert!(lookups.due(later).is_empty());
+        assert_eq!(lookups.due(later + LOOKUP_RETRY), vec![ip]);
+
+        // the pod is gone, the address is released
+        assert_eq!(
+            lookups.not_found(ip, later + LOOKUP_RETRY),
+            Some(workload("b"))
+        );
+        assert!(lookups.due(later + LOOKUP_RETRY).is_empty());
+    }
+
+    #[test]
+    fn test_missed_ip_retried() {
+        let ip: IpAddr = "10.0.1.2".parse().unwrap();
+        let now = Instant::now();
+        let mut lookups = RemoteLookups::default();
+        lookups.queue(ip, now);
+        assert_eq!(lookups.due(now), vec![ip]);
+        assert_eq!(lookups.not_found(ip, now), None);
+
+        lookups.queue(ip, now);
+        assert!(lookups.due(now + Duration::from_secs(1)).is_empty());
+
+        // the miss is forgotten after the retry delay, the next one is queued
+        assert!(lookups.expire(now + LOOKUP_RETRY).is_empty());
+        assert!(lookups.due(now + LOOKUP_RETRY).is_empty());
+        lookups.queue(ip, now + LOOKUP_RETRY);
+        assert_eq!(lookups.due(now + LOOKUP_RETRY), vec![ip]);
+
+        // an address known not to be a pod IP is not looked up
+        let external: IpAddr = "192.0.2.1".parse().unwrap();
+        lookups.queue(external, now);
+        lookups.skip(external, now);
+        lookups.queue(external, now);
+        assert_eq!(lookups.due(now + LOOKUP_RETRY), vec![ip]);
+    }
+
+    #[test]
+    fn test_unasked_ip_expires() {
+        let ip: IpAddr = "10.0.1.3".parse().unwrap();
+        let now = Instant::now();
+        let mut lookups = RemoteLookups::default();
+        lookups.queue(ip, now);
+        lookups.found(ip, workload("a"), now);
+
+        // nobody asked about the address since it was resolved
+        lookups.asked(ip, now + LOOKUP_RETRY / 2);
+        assert!(lookups.expire(now + LOOKUP_RETRY).is_empty());
+        let later = now + LOOKUP_RETRY / 2 + LOOKUP_RETRY;
+        assert_eq!(lookups.expire(later), vec![(ip, workload("a"))]);
+        assert!(lookups.due(later).is_empty());
+    }
+
+    #[test]
+    fn test_lookups_bounded() {
+        let now = Instant::now();
+        let mut lookups = RemoteLookups::default();
+        for i in 0..MAX_LOOKUPS as u32 + 10 {
+            lookups.queue(IpAddr::from(i.to_be_bytes()), now);
+        }
+        assert_eq!(lookups.due(now).len(), MAX_LOOKUPS);
+    }
+}
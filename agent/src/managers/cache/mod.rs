@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use clap::ValueEnum;
+use log::info;
+use serde::Serialize;
+
+use crate::managers::cache::kubernetes::{KubeConfig, KubeMetadata};
+use crate::managers::cache::local::LocalMetadata;
+
+pub(crate) mod endpoints;
+pub(crate) mod history;
+pub(crate) mod kubernetes;
+pub(crate) mod local;
+pub(crate) mod lookups;
+pub(crate) mod process;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize)]
+pub struct Workload {
+    pub name: String,
+    pub namespace: String,
+    pub kind: String,
+    /// The promoted labels of the workload and its pod, see `KubeConfig`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// The promoted annotations of the workload and its pod.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// Where a node runs, empty when the node has no topology labels.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub(crate) struct Topology {
+    pub zone: String,
+    pub region: String,
+}
+
+/// A port of a Kubernetes Service, the server side of the connections to the
+/// endpoints of the port.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct ServiceEndpoint {
+    pub service: Arc<Workload>,
+    /// The name of the port, empty for the single port of a Service which
+    /// needs no name.
+    pub port_name: String,
+}
+
+/// A source of the workloads the addresses and processes observed by the
+/// programs belong to.
+#[async_trait]
+pub(crate) trait MetadataProvider: Debug + Send + Sync + 'static {
+    /// Waits for the provider to know about the existing workloads.
+    async fn wait_until_ready(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Looks up the workload owning an address. IPv4-mapped IPv6 addresses are
+    /// looked up as the IPv4 address they carry.
+    fn resolve_ip(&self, ip: IpAddr) -> Option<Arc<Workload>>;
+
+    /// Looks up the workload which owned an address when it was observed, at
+    /// a time of the monotonic clock.
+    fn resolve_ip_at(&self, ip: IpAddr, _at: Duration) -> Option<Arc<Workload>> {
+        self.resolve_ip(ip)
+    }
+
+    /// Tells the provider that an address it missed is known not to be a pod
+    /// IP, for it not to be looked up.
+    fn skip_lookup(&self, _ip: IpAddr) {}
+
+    fn resolve_topology(&self, _ip: IpAddr) -> Option<Topology> {
+        None
+    }
+
+    /// Looks up the workload a process of the host belongs to, by its tgid.
+    fn resolve_pid(&self, _pid: u32) -> Option<Arc<Workload>> {
+        None
+    }
+
+    /// Looks up the Service port an address and a port are an endpoint of,
+    /// the protocol being named as by Kubernetes, e.g. `TCP`.
+    fn resolve_service(
+        &self,
+        _ip: IpAddr,
+        _port: u16,
+        _protocol: &str,
+    ) -> Option<Arc<ServiceEndpoint>> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+#[value(rename_all = "lower")]
+pub(crate) enum MetadataSource {
+    Kubernetes,
+    Local,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CacheConfig {
+    pub source: MetadataSource,
+    pub kube: KubeConfig,
+    /// Static `address namespace/name [kind]` entries of the local provider.
+    pub static_file: Option<PathBuf>,
+}
+
+/// The metadata shared by every program, backed by the configured provider.
+#[derive(Clone, Debug)]
+pub(crate) struct CacheManager {
+    provider: Arc<dyn MetadataProvider>,
+}
+
+impl CacheManager {
+    pub(crate) async fn new(config: CacheConfig) -> anyhow::Result<CacheManager> {
+        let provider: Arc<dyn MetadataProvider> = match config.source {
+            MetadataSource::Kubernetes => Arc::new(KubeMetadata::new(config.kube).await?),
+            MetadataSource::Local => Arc::new(LocalMetadata::new(config.static_file.as_deref())?),
+        };
+        info!("Using {:?} metadata provider", config.source);
+        Ok(Self { provider })
+    }
+
+    pub async fn wait_for_cache_sync(&self) -> anyhow::Result<()> {
+        self.provider.wait_until_ready().await
+    }
+
+    pub(crate) fn resolve_ip(&self, ip: IpAddr) -> Option<Arc<Workload>> {
+        self.provider.resolve_ip(ip)
+    }
+
+    pub(crate) fn resolve_ip_at(&self, ip: IpAddr, at: Duration) -> Option<Arc<Workload>> {
+        self.provider.resolve_ip_at(ip, at)
+    }
+
+    pub(crate) fn skip_lookup(&self, ip: IpAddr) {
+        self.provider.skip_lookup(ip)
+    }
+
+    pub(crate) fn resolve_topology(&self, ip: IpAddr) -> Option<Topology> {
+        self.provider.resolve_topology(ip)
+    }
+
+    pub(crate) fn resolve_pid(&self, pid: u32) -> Option<Arc<Workload>> {
+        self.provider.resolve_pid(pid)
+    }
+
+    pub(crate) fn resolve_service(
+        &self,
+        ip: IpAddr,
+        port: u16,
+        protocol: &str,
+    ) -> Option<Arc<ServiceEndpoint>> {
+        self.provider.resolve_service(ip, port, protocol)
+    }
+}
+
+pub(crate) fn parse_ip(ip: &str) -> Option<IpAddr> {
+    ip.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_ip() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(parse_ip("10.0.0.1"), Some(v4));
+        assert_eq!(parse_ip("::ffff:10.0.0.1"), Some(v4));
+        assert_eq!(parse_ip("fd00::1"), Some("fd00::1".parse().unwrap()));
+        assert_eq!(parse_ip("node-1.example.com"), None);
+        assert_eq!(parse_ip("None"), None);
+    }
+}
@@ -0,0 +1,354 @@
+//! Resolution of host processes to the containers they run in.
+//!
+//! The container of a process is found in its `/proc/<pid>/cgroup`, whose
+//! layout depends on the runtime and on the cgroup driver of the kubelet:
+//!
+//! - containerd: `kubepods-besteffort-pod<uid>.slice/cri-containerd-<id>.scope`
+//! - CRI-O: `kubepods-burstable-pod<uid>.slice/crio-<id>.scope`
+//! - docker: `docker-<id>.scope` or `/docker/<id>`
+//! - cgroupfs driver: `/kubepods/burstable/pod<uid>/<id>`
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+
+use crate::managers::cache::Workload;
+
+const PROC_ROOT: &str = "/proc";
+const CONTAINER_ID_LEN: usize = 64;
+const CONTAINER_PREFIXES: [&str; 4] = ["cri-containerd-", "crio-", "docker-", "containerd-"];
+const MAX_PROCESSES: usize = 65536;
+/// How many processes are evicted at once when the cache is full.
+const EVICTED_PROCESSES: usize = MAX_PROCESSES / 8;
+const RESOLVED_TTL: Duration = Duration::from_secs(30);
+const MISSED_TTL: Duration = Duration::from_secs(5);
+
+/// The container and pod a cgroup belongs to, as far as its path tells.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct CgroupRef {
+    pub container_id: Option<String>,
+    pub pod_uid: Option<String>,
+}
+
+/// The paths of the content of `/proc/<pid>/cgroup`, the path of the unified
+/// hierarchy of cgroup v2 first.
+pub(crate) fn cgroup_paths(cgroup: &str) -> Vec<&str> {
+    let mut paths: Vec<(&str, &str)> = cgroup
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let id = fields.next()?;
+            fields.next()?;
+            Some((id, fields.next()?))
+        })
+        .collect();
+    paths.sort_by_key(|(id, _)| *id != "0");
+    paths.into_iter().map(|(_, path)| path).collect()
+}
+
+pub(crate) fn parse_cgroup(cgroup: &str) -> CgroupRef {
+    let mut cgroup_ref = CgroupRef::default();
+    for path in cgroup_paths(cgroup) {
+        for segment in path.split('/') {
+            if let Some(id) = container_id(segment) {
+                cgroup_ref.container_id.get_or_insert(id);
+            } else if let Some(uid) = pod_uid(segment) {
+                cgroup_ref.pod_uid.get_or_insert(uid);
+            }
+        }
+        if cgroup_ref.container_id.is_some() {
+            break;
+        }
+    }
+    cgroup_ref
+}
+
+fn container_id(segment: &str) -> Option<String> {
+    let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+    let id = CONTAINER_PREFIXES
+        .iter()
+        .find_map(|prefix| segment.strip_prefix(prefix))
+        .unwrap_or(segment);
+    (id.len() == CONTAINER_ID_LEN && id.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then(|| id.to_string())
+}
+
+/// The systemd driver escapes the dashes of the uid with underscores.
+fn pod_uid(segment: &str) -> Option<String> {
+    let segment = segment.strip_suffix(".slice").unwrap_or(segment);
+    let (_, uid) = segment.rsplit_once("pod")?;
+    let uid = uid.replace('_', "-");
+    (uid.len() == 36 && uid.bytes().all(|b| b.is_ascii_hexdigit() || b == b'-')).then_some(uid)
+}
+
+/// Strips the `<runtime>://` scheme of the container ids of a pod status.
+pub(crate) fn strip_runtime(container_id: &str) -> &str {
+    container_id
+        .split_once("://")
+        .map_or(container_id, |(_, id)| id)
+}
+
+/// Start time of a process in clock ticks since boot, the 22nd field of
+/// `/proc/<pid>/stat`.
+fn start_time(root: &Path, pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(root.join(format!("{}/stat", pid))).ok()?;
+    parse_start_time(&stat)
+}
+
+fn parse_start_time(stat: &str) -> Option<u64> {
+    // the command name in the second field may contain spaces and parentheses
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[derive(Debug)]
+struct CachedProcess {
+    started_at: u64,
+    checked_at: Instant,
+    /// `None` when the process was not resolved.
+    workload: Option<Arc<Workload>>,
+}
+
+/// Drops the `count` processes checked the longest ago, the most likely ones to
+/// have exited.
+fn evict_oldest(processes: &mut AHashMap<u32, CachedProcess>, count: usize) {
+    let mut checked_at: Vec<Instant> = processes.values().map(|p| p.checked_at).collect();
+    let count = count.min(checked_at.len());
+    if count == 0 {
+        return;
+    }
+    let (_, oldest, _) = checked_at.select_nth_unstable(count - 1);
+    let oldest = *oldest;
+    processes.retain(|_, process| process.checked_at > oldest);
+}
+
+/// Workloads of the processes already resolved, per tgid.
+///
+/// A resolved process is returned without touching `/proc` until it is
+/// `RESOLVED_TTL` old, then its start time is checked again and it is dropped
+/// if the pid was reused. A process which was not resolved, as its pod may not
+/// be known yet, is resolved again after `MISSED_TTL`. Once `MAX_PROCESSES`
+/// are cached, the ones checked the longest ago make room for new ones.
+#[derive(Debug)]
+pub(crate) struct ProcessCache {
+    root: PathBuf,
+    processes: Mutex<AHashMap<u32, CachedProcess>>,
+}
+
+impl Default for ProcessCache {
+    fn default() -> Self {
+        Self::new(PathBuf::from(PROC_ROOT))
+    }
+}
+
+impl ProcessCache {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            processes: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    /// Returns the workload of a process, resolving the content of its cgroup
+    /// file with `resolve` when it is not cached.
+    pub(crate) fn get_or_resolve(
+        &self,
+        pid: u32,
+        resolve: impl FnOnce(&str) -> Option<Arc<Workload>>,
+    ) -> Option<Arc<Workload>> {
+        self.get_or_resolve_at(pid, Instant::now(), resolve)
+    }
+
+    fn get_or_resolve_at(
+        &self,
+        pid: u32,
+        now: Instant,
+        resolve: impl FnOnce(&str) -> Option<Arc<Workload>>,
+    ) -> Option<Arc<Workload>> {
+        let cached = |process: &CachedProcess| {
+            let ttl = match process.workload {
+                Some(_) => RESOLVED_TTL,
+                None => MISSED_TTL,
+            };
+            now.saturating_duration_since(process.checked_at) < ttl
+        };
+        if let Some(process) = self.processes.lock().get(&pid) {
+            if process.workload.is_some() && cached(process) {
+                return process.workload.clone();
+            }
+        }
+
+        let started_at = start_time(&self.root, pid)?;
+        if let Some(process) = self.processes.lock().get_mut(&pid) {
+            if process.started_at == started_at {
+                if process.workload.is_some() {
+                    process.checked_at = now;
+                    return process.workload.clone();
+                }
+                if cached(process) {
+                    return None;
+                }
+            }
+        }
+
+        let cgroup = fs::read_to_string(self.root.join(format!("{}/cgroup", pid))).ok()?;
+        let workload = resolve(&cgroup);
+        let mut processes = self.processes.lock();
+        if processes.len() >= MAX_PROCESSES && !processes.contains_key(&pid) {
+            evict_oldest(&mut processes, EVICTED_PROCESSES);
+        }
+        processes.insert(
+            pid,
+            CachedProcess {
+                started_at,
+                checked_at: now,
+                workload: workload.clone(),
+            },
+        );
+        workload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "4c6ed5d2e1cb9ad2c1bd8fb1e1a1a5ff5b1bb37f6a0f5ed5e6a6f8f7b3a0c1d2";
+    const UID: &str = "0f7e9b8e-2c53-4b5e-9a4c-3b7f4c5d6e7f";
+
+    #[test]
+    fn test_parse_cgroup() {
+        let uid = UID.replace('-', "_");
+        let containerd = format!(
+            "0::/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod{}.slice/cri-containerd-{}.scope\n",
+            uid, ID
+        );
+        let crio = format!(
+            "0::/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{}.slice/crio-{}.scope\n",
+            uid, ID
+        );
+        let cgroupfs = format!(
+            "12:pids:/kubepods/burstable/pod{}/{}\n1:name=systemd:/kubepods/burstable/pod{}/{}\n",
+            UID, ID, UID, ID
+        );
+        for cgroup in [containerd, crio, cgroupfs] {
+            assert_eq!(
+                parse_cgroup(&cgroup),
+                CgroupRef {
+                    container_id: Some(ID.to_string()),
+                    pod_uid: Some(UID.to_string()),
+                }
+            );
+        }
+
+        let docker = format!("0::/system.slice/docker-{}.scope\n", ID);
+        assert_eq!(parse_cgroup(&docker).container_id.as_deref(), Some(ID));
+        assert_eq!(parse_cgroup(&docker).pod_uid, None);
+        let conmon = format!("0::/machine.slice/crio-conmon-{}.scope\n", ID);
+        assert_eq!(parse_cgroup(&conmon), CgroupRef::default());
+        assert_eq!(
+            parse_cgroup("0::/system.slice/sshd.service\n"),
+            CgroupRef::default()
+        );
+    }
+
+    #[test]
+    fn test_strip_runtime() {
+        assert_eq!(strip_runtime(&format!("containerd://{}", ID)), ID);
+        assert_eq!(strip_runtime(&format!("cri-o://{}", ID)), ID);
+        assert_eq!(strip_runtime(ID), ID);
+    }
+
+    #[test]
+    fn test_parse_start_time() {
+        let stat = "1234 (my (weird) cmd) S 1 1234 1234 0 -1 4194560 100 0 0 0 5 3 0 0 20 0 1 0 987654 10000 200";
+        assert_eq!(parse_start_time(stat), Some(987654));
+        assert_eq!(parse_start_time("1234 (cmd) S 1"), None);
+    }
+
+    #[test]
+    fn test_evict_oldest() {
+        let now = Instant::now();
+        let mut processes: AHashMap<u32, CachedProcess> = (0..10)
+            .map(|pid| {
+                let process = CachedProcess {
+                    started_at: 0,
+                    checked_at: now + Duration::from_secs(u64::from(pid % 5)),
+                    workload: None,
+                };
+                (pid, process)
+            })
+            .collect();
+        evict_oldest(&mut processes, 4);
+        let mut pids: Vec<u32> = processes.keys().copied().collect();
+        pids.sort();
+        assert_eq!(pids, vec![2, 3, 4, 7, 8, 9]);
+
+        evict_oldest(&mut processes, 100);
+        assert!(processes.is_empty());
+        evict_oldest(&mut processes, 1);
+    }
+
+    #[test]
+    fn test_cached_process() {
+        let root = std::env::temp_dir().join(format!("agent-proc-{}", std::process::id()));
+        let dir = root.join("42");
+        fs::create_dir_all(&dir).unwrap();
+        let stat = |started_at: u64| {
+            let stat = format!(
+                "42 (cmd) S 1 1 1 0 -1 0 0 0 0 0 0 0 0 0 20 0 1 0 {} 0 0",
+                started_at
+            );
+            fs::write(dir.join("stat"), stat).unwrap();
+        };
+        stat(1000);
+        fs::write(dir.join("cgroup"), "0::/system.slice/app.service\n").unwrap();
+
+        let cache = ProcessCache::new(root.clone());
+        let workload = Arc::new(Workload {
+            name: "app".to_string(),
+            ..Default::default()
+        });
+        let resolves = std::cell::Cell::new(0);
+        let get = |at: Instant, resolved: bool| {
+            cache.get_or_resolve_at(42, at, |_| {
+                resolves.set(resolves.get() + 1);
+                resolved.then(|| workload.clone())
+            })
+        };
+
+        // a miss is resolved again once the pod had time to be known
+        let now = Instant::now();
+        assert_eq!(get(now, false), None);
+        assert_eq!(get(now + Duration::from_secs(1), true), None);
+        assert_eq!(resolves.get(), 1);
+        let now = now + MISSED_TTL;
+        assert_eq!(get(now, true), Some(workload.clone()));
+        assert_eq!(resolves.get(), 2);
+
+        // a hit does not read the stat file until it is checked again
+        fs::remove_file(dir.join("stat")).unwrap();
+        assert_eq!(
+            get(now + Duration::from_secs(1), true),
+            Some(workload.clone())
+        );
+        assert_eq!(get(now + RESOLVED_TTL, true), None);
+        stat(1000);
+        assert_eq!(get(now + RESOLVED_TTL, true), Some(workload.clone()));
+        assert_eq!(resolves.get(), 2);
+
+        // the pid was reused by another process
+        let now = now + RESOLVED_TTL * 2;
+        stat(2000);
+        assert_eq!(get(now, false), None);
+        stat(3000);
+        assert_eq!(get(now, true), Some(workload));
+        assert_eq!(resolves.get(), 4);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,8 +0,0 @@
-#[derive(Clone, Debug)]
-pub(crate) struct ImageManager {}
-
-impl ImageManager {
-    pub(crate) fn new() -> Self {
-        Self {}
-    }
-}
@@ -0,0 +1,741 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Error;
+use flate2::read::GzDecoder;
+use log::{debug, info};
+use oci_distribution::client::{ClientConfig, ClientProtocol};
+use oci_distribution::manifest::{IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE};
+use oci_distribution::secrets::RegistryAuth;
+use oci_distribution::{Client, Reference};
+
+use agent_api::v1::bytecode_location::Location;
+use agent_api::v1::{BytecodeImage, BytecodeLocation};
+use agent_api::ImagePullPolicy;
+
+use crate::managers::image::store::{
+    sha256_digest, verify_digest, Descriptor, ImageStore, Manifest, MEDIA_TYPE_IMAGE_MANIFEST,
+};
+use crate::managers::image::verify::{
+    pinned_digest, signature_tag, SignatureVerifier, VerificationError, SIGNATURE_ANNOTATION,
+    SIMPLE_SIGNING_MEDIA_TYPE, VERIFIED_BY_ANNOTATION,
+};
+
+pub(crate) mod store;
+pub(crate) mod verify;
+
+/// Prefix of image urls pointing at an OCI image layout on the local disk,
+/// e.g. `oci:/path/to/layout:v1`.
+const OCI_LAYOUT_PREFIX: &str = "oci:";
+const DEFAULT_TAG: &str = "latest";
+/// Largest layer pulled, and largest wasm module extracted from a layer. The
+/// sizes are read from the image, nothing is allocated for them up front.
+const MAX_BLOB_SIZE: u64 = 256 * 1024 * 1024;
+
+const WASM_LAYER_MEDIA_TYPES: [&str; 3] = [
+    "application/vnd.wasm.content.layer.v1+wasm",
+    "application/vnd.module.wasm.content.layer.v1+wasm",
+    "application/wasm",
+];
+const TAR_LAYER_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.layer.v1.tar",
+    "application/vnd.docker.image.rootfs.diff.tar",
+];
+const TAR_GZIP_LAYER_MEDIA_TYPES: [&str; 2] = [
+    "application/vnd.oci.image.layer.v1.tar+gzip",
+    "application/vnd.docker.image.rootfs.diff.tar.gzip",
+];
+
+enum ImageSource {
+    Registry {
+        client: Client,
+        reference: Reference,
+        auth: RegistryAuth,
+    },
+    Layout {
+        store: ImageStore,
+        reference: String,
+    },
+}
+
+impl ImageSource {
+    fn new(image: &BytecodeImage) -> Result<Self, Error> {
+        if let Some(location) = image.url.strip_prefix(OCI_LAYOUT_PREFIX) {
+            let (path, reference) = match location.split_once('@') {
+                Some((path, digest)) => (path, digest),
+                None => match location.rsplit_once(':') {
+                    Some((path, tag)) if !tag.contains('/') => (path, tag),
+                    _ => (location, DEFAULT_TAG),
+                },
+            };
+            return Ok(ImageSource::Layout {
+                store: ImageStore::new(path),
+                reference: reference.to_string(),
+            });
+        }
+
+        let reference = Reference::from_str(&image.url)
+            .map_err(|e| anyhow::anyhow!("Invalid image url {}: {}", image.url, e))?;
+        let auth = match (&image.username, &image.password) {
+            (Some(username), Some(password)) => {
+                RegistryAuth::Basic(username.clone(), password.clone())
+            }
+            _ => RegistryAuth::Anonymous,
+        };
+        let client = Client::new(ClientConfig {
+            protocol: ClientProtocol::Https,
+            ..Default::default()
+        });
+        Ok(ImageSource::Registry {
+            client,
+            reference,
+            auth,
+        })
+    }
+
+    /// Returns the raw manifest and its digest.
+    async fn manifest(&self) -> Result<(Vec<u8>, String), Error> {
+        match self {
+            ImageSource::Registry {
+                client,
+                reference,
+                auth,
+            } => {
+                let (manifest, digest) = client
+                    .pull_manifest_raw(
+                        reference,
+                        auth,
+                        &[OCI_IMAGE_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE],
+                    )
+                    .await?;
+                let digest = if digest.is_empty() {
+                    sha256_digest(&manifest)
+                } else {
+                    digest
+                };
+                Ok((manifest, digest))
+            }
+            ImageSource::Layout { store, reference } => {
+                let descriptor = store.resolve(reference)?.ok_or(anyhow::anyhow!(
+                    "Image {} not found in layout {:?}",
+                    reference,
+                    store.root()
+                ))?;
+                let manifest = store.read_blob(&descriptor.digest)?;
+                Ok((manifest, descriptor.digest))
+            }
+        }
+    }
+
+    /// Returns the raw manifest of the cosign signature image for `digest`.
+    async fn signature_manifest(&self, digest: &str) -> Result<Vec<u8>, Error> {
+        match self {
+            ImageSource::Registry {
+                client,
+                reference,
+                auth,
+            } => {
+                let signature = Reference::with_tag(
+                    reference.registry().to_string(),
+                    reference.repository().to_string(),
+                    signature_tag(digest),
+                );
+                let (manifest, _) = client
+                    .pull_manifest_raw(
+                        &signature,
+                        auth,
+                        &[OCI_IMAGE_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE],
+                    )
+                    .await?;
+                Ok(manifest)
+            }
+            ImageSource::Layout { store, .. } => {
+                let tag = signature_tag(digest);
+                let descriptor = store
+                    .resolve(&tag)?
+                    .ok_or(anyhow::anyhow!("Image {} not found", tag))?;
+                store.read_blob(&descriptor.digest)
+            }
+        }
+    }
+
+    async fn blob(&self, descriptor: &Descriptor) -> Result<Vec<u8>, Error> {
+        check_blob_size(&descriptor.digest, descriptor.size)?;
+        match self {
+            ImageSource::Registry {
+                client, reference, ..
+            } => {
+                let mut blob = Vec::new();
+                client
+                    .pull_blob(reference, descriptor.digest.as_str(), &mut blob)
+                    .await?;
+                check_blob_size(&descriptor.digest, blob.len() as u64)?;
+                Ok(blob)
+            }
+            ImageSource::Layout { store, .. } => store.read_blob(&descriptor.digest),
+        }
+    }
+}
+
+fn check_blob_size(name: &str, size: u64) -> Result<(), Error> {
+    if size > MAX_BLOB_SIZE {
+        return Err(anyhow::anyhow!(
+            "{} is {} bytes, more than the maximum of {} bytes",
+            name,
+            size,
+            MAX_BLOB_SIZE
+        ));
+    }
+    Ok(())
+}
+
+/// Picks the layer holding the wasm module. Layers with a wasm media type take
+/// precedence over tarballs, which are searched for a `.wasm` file.
+fn bytecode_layer(manifest: &Manifest) -> Result<&Descriptor, Error> {
+    manifest
+        .layers
+        .iter()
+        .find(|l| WASM_LAYER_MEDIA_TYPES.contains(&l.media_type.as_str()))
+        .or_else(|| {
+            manifest.layers.iter().find(|l| {
+                TAR_LAYER_MEDIA_TYPES.contains(&l.media_type.as_str())
+                    || TAR_GZIP_LAYER_MEDIA_TYPES.contains(&l.media_type.as_str())
+            })
+        })
+        .ok_or(Error::msg("No wasm layer found in image"))
+}
+
+fn extract_bytecode(layer: &Descriptor, blob: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let media_type = layer.media_type.as_str();
+    if WASM_LAYER_MEDIA_TYPES.contains(&media_type) {
+        return Ok(blob);
+    }
+
+    let reader: Box<dyn Read> = if TAR_GZIP_LAYER_MEDIA_TYPES.contains(&media_type) {
+        Box::new(GzDecoder::new(blob.as_slice()))
+    } else {
+        Box::new(blob.as_slice())
+    };
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let is_wasm = entry.path()?.extension().map(|e| e == "wasm") == Some(true);
+        if is_wasm && entry.header().entry_type().is_file() {
+            check_blob_size(&entry.path()?.display().to_string(), entry.size())?;
+            let mut bytecode = Vec::new();
+            entry.read_to_end(&mut bytecode)?;
+            return Ok(bytecode);
+        }
+    }
+    Err(anyhow::anyhow!(
+        "No .wasm file found in layer {}",
+        layer.digest
+    ))
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ImageManager {
+    store: ImageStore,
+    verifier: Option<SignatureVerifier>,
+}
+
+impl ImageManager {
+    pub(crate) fn new(root: impl Into<PathBuf>, verifier: Option<SignatureVerifier>) -> Self {
+        Self {
+            store: ImageStore::new(root),
+            verifier,
+        }
+    }
+
+    /// Makes sure the image is present in the local store according to its
+    /// pull policy and returns the descriptor of its manifest.
+    pub(crate) async fn pull_image(&self, image: &BytecodeImage) -> Result<Descriptor, Error> {
+        let pull_policy: ImagePullPolicy = image.image_pull_policy.try_into()?;
+        let cached = self.store.resolve(&image.url)?;
+
+        match (pull_policy, cached) {
+            (ImagePullPolicy::IfNotPresent | ImagePullPolicy::Never, Some(descriptor))
+                if self.is_trusted(&image.url, &descriptor) =>
+            {
+                debug!("Image {} found in local store", image.url);
+                Ok(descriptor)
+            }
+            (ImagePullPolicy::Never, Some(_)) => {
+                Err(VerificationError::Unverified(image.url.clone()).into())
+            }
+            (ImagePullPolicy::Never, None) => Err(anyhow::anyhow!(
+                "Image {} is not present locally and pull policy is Never",
+                image.url
+            )),
+            _ => self.fetch(image).await,
+        }
+    }
+
+    /// A cached image is only used if it still matches the pinned digest and
+    /// was verified with the currently configured key.
+    fn is_trusted(&self, url: &str, descriptor: &Descriptor) -> bool {
+        if let Some(pinned) = pinned_digest(url) {
+            if pinned != descriptor.digest {
+                return false;
+            }
+        }
+        match &self.verifier {
+            Some(verifier) => {
+                descriptor
+                    .annotations
+                    .get(VERIFIED_BY_ANNOTATION)
+                    .map(|v| v.as_str())
+                    == Some(verifier.fingerprint())
+            }
+            None => true,
+        }
+    }
+
+    async fn verify_signature(&self, source: &ImageSource, digest: &str) -> Result<(), Error> {
+        let Some(verifier) = &self.verifier else {
+            return Ok(());
+        };
+
+        let manifest = source.signature_manifest(digest).await.map_err(|e| {
+            debug!("Failed to fetch signature of {}: {:?}", digest, e);
+            VerificationError::MissingSignature(digest.to_string())
+        })?;
+        let manifest: Manifest = serde_json::from_slice(&manifest)
+            .map_err(|_| VerificationError::MissingSignature(digest.to_string()))?;
+
+        for layer in manifest
+            .layers
+            .iter()
+            .filter(|l| l.media_type == SIMPLE_SIGNING_MEDIA_TYPE)
+        {
+            let Some(signature) = layer.annotations.get(SIGNATURE_ANNOTATION) else {
+                continue;
+            };
+            let payload = match source.blob(layer).await {
+                Ok(payload) => payload,
+                Err(e) => {
+                    debug!(
+                        "Failed to fetch signature payload {}: {:?}",
+                        layer.digest, e
+                    );
+                    continue;
+                }
+            };
+            if verify_digest(&layer.digest, &payload).is_ok()
+                && verifier.verify_payload(digest, &payload, signature)
+            {
+                return Ok(());
+            }
+        }
+
+        Err(VerificationError::InvalidSignature(digest.to_string()).into())
+    }
+
+    async fn fetch(&self, image: &BytecodeImage) -> Result<Descriptor, Error> {
+        let source = ImageSource::new(image)?;
+        let (manifest_bytes, digest) = source.manifest().await?;
+        verify_digest(&digest, &manifest_bytes)?;
+        if let Some(pinned) = pinned_digest(&image.url) {
+            if pinned != digest {
+                return Err(VerificationError::DigestMismatch {
+                    expected: pinned.to_string(),
+                    actual: digest,
+                }
+                .into());
+            }
+        }
+        self.verify_signature(&source, &digest).await?;
+
+        let manifest: Manifest = serde_json::from_slice(&manifest_bytes)
+            .map_err(|e| anyhow::anyhow!("Invalid manifest for image {}: {}", image.url, e))?;
+
+        let layer = bytecode_layer(&manifest)?;
+        if !self.store.has_blob(&layer.digest) {
+            let blob = source.blob(layer).await?;
+            verify_digest(&layer.digest, &blob)?;
+            self.store.write_blob(&blob)?;
+        }
+        if !self.store.has_blob(&manifest.config.digest) {
+            let config = source.blob(&manifest.config).await?;
+            verify_digest(&manifest.config.digest, &config)?;
+            self.store.write_blob(&config)?;
+        }
+        self.store.write_blob(&manifest_bytes)?;
+
+        let mut descriptor = Descriptor {
+            media_type: if manifest.media_type.is_empty() {
+                MEDIA_TYPE_IMAGE_MANIFEST.to_string()
+            } else {
+                manifest.media_type.clone()
+            },
+            digest,
+            size: manifest_bytes.len() as u64,
+            annotations: Default::default(),
+        };
+        if let Some(verifier) = &self.verifier {
+            descriptor.annotations.insert(
+                VERIFIED_BY_ANNOTATION.to_string(),
+                verifier.fingerprint().to_string(),
+            );
+        }
+        self.store.tag(&image.url, &descriptor)?;
+        info!("Pulled image {} ({})", image.url, descriptor.digest);
+
+        Ok(descriptor)
+    }
+
+    pub(crate) async fn get_bytecode(&self, location: &BytecodeLocation) -> Result<Vec<u8>, Error> {
+        match &location.location {
+            Some(Location::Image(image)) => {
+                let descriptor = self.pull_image(image).await?;
+                let manifest = self.store.read_manifest(&descriptor)?;
+                let layer = bytecode_layer(&manifest)?;
+                let blob = self.store.read_blob(&layer.digest)?;
+                extract_bytecode(layer, blob)
+            }
+            Some(Location::File(path)) => {
+                // files carry no signature, so they would bypass verification
+                if self.verifier.is_some() {
+                    return Err(VerificationError::UnsignedFile(path.clone()).into());
+                }
+                if !Path::new(path).is_absolute() {
+                    return Err(anyhow::anyhow!(
+                        "Bytecode file path {} must be absolute",
+                        path
+                    ));
+                }
+                tokio::fs::read(path)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to read bytecode from {}: {}", path, e))
+            }
+            None => Err(Error::msg("Bytecode location is empty")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use sigstore::crypto::signing_key::SigStoreSigner;
+    use sigstore::crypto::SigningScheme;
+
+    use super::store::ANNOTATION_REF_NAME;
+    use super::*;
+
+    const WASM: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-image-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn write_layout(root: &PathBuf, tag: &str, wasm: &[u8]) -> String {
+        let layout = ImageStore::new(root);
+        let layer = layout.write_blob(wasm).unwrap();
+        let config = layout.write_blob(b"{}").unwrap();
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MEDIA_TYPE_IMAGE_MANIFEST,
+            "config": {
+                "mediaType": "application/vnd.wasm.config.v1+json",
+                "digest": config,
+                "size": 2,
+            },
+            "layers": [{
+                "mediaType": WASM_LAYER_MEDIA_TYPES[0],
+                "digest": layer,
+                "size": wasm.len(),
+            }],
+        });
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        let digest = layout.write_blob(&manifest).unwrap();
+        layout
+            .tag(
+                tag,
+                &Descriptor {
+                    media_type: MEDIA_TYPE_IMAGE_MANIFEST.to_string(),
+                    digest: digest.clone(),
+                    size: manifest.len() as u64,
+                    annotations: HashMap::new(),
+                },
+            )
+            .unwrap();
+        digest
+    }
+
+    fn signer() -> SigStoreSigner {
+        SigningScheme::default().create_signer().unwrap()
+    }
+
+    fn verifier(signer: &SigStoreSigner) -> SignatureVerifier {
+        let pem = signer
+            .to_sigstore_keypair()
+            .unwrap()
+            .public_key_to_pem()
+            .unwrap();
+        SignatureVerifier::from_pem(pem.as_bytes()).unwrap()
+    }
+
+    /// Stores a cosign signature of the manifest `digest` in a layout, the way
+    /// `cosign sign` pushes it to a registry.
+    fn sign_layout(root: &Path, digest: &str, signer: &SigStoreSigner) {
+        let layout = ImageStore::new(root);
+        let payload = serde_json::json!({
+            "critical": {
+                "identity": {"docker-reference": "example.com/prog"},
+                "image": {"docker-manifest-digest": digest},
+                "type": "cosign container image signature",
+            },
+            "optional": null,
+        });
+        let payload = serde_json::to_vec(&payload).unwrap();
+        let signature = BASE64.encode(signer.sign(&payload).unwrap());
+        let layer = layout.write_blob(&payload).unwrap();
+        let config = layout.write_blob(b"{}").unwrap();
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": MEDIA_TYPE_IMAGE_MANIFEST,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": config,
+                "size": 2,
+            },
+            "layers": [{
+                "mediaType": SIMPLE_SIGNING_MEDIA_TYPE,
+                "digest": layer,
+                "size": payload.len(),
+                "annotations": {SIGNATURE_ANNOTATION: signature},
+            }],
+        });
+        let manifest = serde_json::to_vec(&manifest).unwrap();
+        let manifest_digest = layout.write_blob(&manifest).unwrap();
+        layout
+            .tag(
+                &signature_tag(digest),
+                &Descriptor {
+                    media_type: MEDIA_TYPE_IMAGE_MANIFEST.to_string(),
+                    digest: manifest_digest,
+                    size: manifest.len() as u64,
+                    annotations: HashMap::new(),
+                },
+            )
+            .unwrap();
+    }
+
+    fn image(url: String, pull_policy: ImagePullPolicy) -> BytecodeLocation {
+        BytecodeLocation {
+            location: Some(Location::Image(BytecodeImage {
+                url,
+                image_pull_policy: pull_policy.into(),
+                username: None,
+                password: None,
+            })),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_from_oci_layout() {
+        let source = temp_dir("source");
+        let digest = write_layout(&source, "v1", WASM);
+        let manager = ImageManager::new(temp_dir("store"), None);
+        let url = format!("oci:{}:v1", source.display());
+
+        let never = image(url.clone(), ImagePullPolicy::Never);
+        assert!(manager.get_bytecode(&never).await.is_err());
+
+        let if_not_present = image(url.clone(), ImagePullPolicy::IfNotPresent);
+        assert_eq!(manager.get_bytecode(&if_not_present).await.unwrap(), WASM);
+        let cached = manager.store.resolve(&url).unwrap().unwrap();
+        assert_eq!(cached.digest, digest);
+        assert_eq!(cached.annotations[ANNOTATION_REF_NAME], url);
+
+        // Once cached, the source is no longer needed unless pulling always.
+        fs::remove_dir_all(&source).unwrap();
+        assert_eq!(manager.get_bytecode(&never).await.unwrap(), WASM);
+        assert_eq!(manager.get_bytecode(&if_not_present).await.unwrap(), WASM);
+        let always = image(url, ImagePullPolicy::Always);
+        assert!(manager.get_bytecode(&always).await.is_err());
+
+        fs::remove_dir_all(manager.store.root()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_from_file() {
+        let dir = temp_dir("file");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("prog.wasm");
+        fs::write(&path, WASM).unwrap();
+        let manager = ImageManager::new(temp_dir("file-store"), None);
+
+        let file = BytecodeLocation {
+            location: Some(Location::File(path.display().to_string())),
+        };
+        assert_eq!(manager.get_bytecode(&file).await.unwrap(), WASM);
+
+        let relative = BytecodeLocation {
+            location: Some(Location::File("prog.wasm".to_string())),
+        };
+        assert!(manager.get_bytecode(&relative).await.is_err());
+
+        let manager = ImageManager::new(temp_dir("file-store"), Some(verifier(&signer())));
+        let err = manager.get_bytecode(&file).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VerificationError>(),
+            Some(VerificationError::UnsignedFile(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_oversized_blob() {
+        let mut header = tar::Header::new_gnu();
+        header.set_path("prog.wasm").unwrap();
+        header.set_size(MAX_BLOB_SIZE + 1);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_cksum();
+        // the header claims more bytes than the layer has
+        let mut blob = header.as_bytes().to_vec();
+        blob.extend_from_slice(&[0; 1024]);
+        let layer = Descriptor {
+            media_type: TAR_LAYER_MEDIA_TYPES[0].to_string(),
+            digest: sha256_digest(&blob),
+            size: blob.len() as u64,
+            annotations: HashMap::new(),
+        };
+        let err = extract_bytecode(&layer, blob).unwrap_err();
+        assert!(err.to_string().contains("more than the maximum"));
+
+        let source = ImageSource::Layout {
+            store: ImageStore::new(temp_dir("oversized")),
+            reference: DEFAULT_TAG.to_string(),
+        };
+        let layer = Descriptor {
+            size: MAX_BLOB_SIZE + 1,
+            ..layer
+        };
+        let err = source.blob(&layer).await.unwrap_err();
+        assert!(err.to_string().contains("more than the maximum"));
+    }
+
+    #[tokio::test]
+    async fn test_pull_rejects_corrupted_blob() {
+        let source = temp_dir("corrupted");
+        write_layout(&source, DEFAULT_TAG, WASM);
+        let layer = sha256_digest(WASM);
+        let path = source
+            .join("blobs/sha256")
+            .join(layer.trim_start_matches("sha256:"));
+        fs::write(path, b"not wasm").unwrap();
+
+        let manager = ImageManager::new(temp_dir("corrupted-store"), None);
+        let always = image(format!("oci:{}", source.display()), ImagePullPolicy::Always);
+        let err = manager.get_bytecode(&always).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VerificationError>(),
+            Some(VerificationError::DigestMismatch { .. })
+        ));
+
+        fs::remove_dir_all(&source).unwrap();
+        let _ = fs::remove_dir_all(manager.store.root());
+    }
+
+    #[tokio::test]
+    async fn test_verify_signature() {
+        let key = signer();
+        let manager = ImageManager::new(temp_dir("signed-store"), Some(verifier(&key)));
+
+        let signed = temp_dir("signed");
+        let digest = write_layout(&signed, DEFAULT_TAG, WASM);
+        sign_layout(&signed, &digest, &key);
+        let url = format!("oci:{}", signed.display());
+        let always = image(url.clone(), ImagePullPolicy::Always);
+        assert_eq!(manager.get_bytecode(&always).await.unwrap(), WASM);
+        let cached = manager.store.resolve(&url).unwrap().unwrap();
+        assert_eq!(
+            cached.annotations[VERIFIED_BY_ANNOTATION],
+            manager.verifier.as_ref().unwrap().fingerprint()
+        );
+
+        let unsigned = temp_dir("unsigned");
+        write_layout(&unsigned, DEFAULT_TAG, WASM);
+        let always = image(
+            format!("oci:{}", unsigned.display()),
+            ImagePullPolicy::Always,
+        );
+        let err = manager.get_bytecode(&always).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VerificationError>(),
+            Some(VerificationError::MissingSignature(_))
+        ));
+
+        let wrongly_signed = temp_dir("wrongly-signed");
+        let digest = write_layout(&wrongly_signed, DEFAULT_TAG, WASM);
+        sign_layout(&wrongly_signed, &digest, &signer());
+        let url = format!("oci:{}", wrongly_signed.display());
+        let always = image(url.clone(), ImagePullPolicy::Always);
+        let err = manager.get_bytecode(&always).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VerificationError>(),
+            Some(VerificationError::InvalidSignature(_))
+        ));
+        assert!(manager.store.resolve(&url).unwrap().is_none());
+
+        for dir in [
+            signed.as_path(),
+            unsigned.as_path(),
+            wrongly_signed.as_path(),
+            manager.store.root(),
+        ] {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_cached_image_again() {
+        let key = signer();
+        let store = temp_dir("reverify-store");
+        let source = temp_dir("reverify");
+        let digest = write_layout(&source, DEFAULT_TAG, WASM);
+        let url = format!("oci:{}", source.display());
+        let never = image(url.clone(), ImagePullPolicy::Never);
+        let if_not_present = image(url.clone(), ImagePullPolicy::IfNotPresent);
+
+        // cached while no key was configured, so without the annotation
+        let unverified = ImageManager::new(&store, None);
+        assert_eq!(
+            unverified.get_bytecode(&if_not_present).await.unwrap(),
+            WASM
+        );
+        let cached = unverified.store.resolve(&url).unwrap().unwrap();
+        assert!(!cached.annotations.contains_key(VERIFIED_BY_ANNOTATION));
+
+        let manager = ImageManager::new(&store, Some(verifier(&key)));
+        let err = manager.get_bytecode(&never).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VerificationError>(),
+            Some(VerificationError::Unverified(_))
+        ));
+        // the source has no signature yet, so pulling it again fails
+        let err = manager.get_bytecode(&if_not_present).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<VerificationError>(),
+            Some(VerificationError::MissingSignature(_))
+        ));
+
+        sign_layout(&source, &digest, &key);
+        assert_eq!(manager.get_bytecode(&if_not_present).await.unwrap(), WASM);
+        let cached = manager.store.resolve(&url).unwrap().unwrap();
+        assert!(cached.annotations.contains_key(VERIFIED_BY_ANNOTATION));
+        assert_eq!(manager.get_bytecode(&never).await.unwrap(), WASM);
+
+        fs::remove_dir_all(&source).unwrap();
+        fs::remove_dir_all(&store).unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+//! A content-addressed image store laid out as an OCI image layout.
+//!
+//! Blobs live under `blobs/sha256/<hex>` and `index.json` maps reference names
+//! to manifest descriptors. Since the format is the standard one, the same code
+//! reads local layout directories used as a pull source.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Error;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::managers::image::verify::VerificationError;
+
+pub(crate) const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+pub(crate) const MEDIA_TYPE_IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub(crate) const MEDIA_TYPE_IMAGE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const OCI_LAYOUT_CONTENT: &str = r#"{"imageLayoutVersion":"1.0.0"}"#;
+const INDEX_FILE: &str = "index.json";
+const BLOBS_DIR: &str = "blobs";
+const SHA256_PREFIX: &str = "sha256:";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Descriptor {
+    #[serde(default)]
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Index {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub media_type: String,
+    #[serde(default)]
+    pub manifests: Vec<Descriptor>,
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self {
+            schema_version: 2,
+            media_type: MEDIA_TYPE_IMAGE_INDEX.to_string(),
+            manifests: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Manifest {
+    #[serde(default)]
+    pub media_type: String,
+    pub config: Descriptor,
+    #[serde(default)]
+    pub layers: Vec<Descriptor>,
+}
+
+pub(crate) fn sha256_digest(bytes: &[u8]) -> String {
+    format!("{}{}", SHA256_PREFIX, hex::encode(Sha256::digest(bytes)))
+}
+
+/// Checks that `bytes` hash to `digest`. Only sha256 digests are supported.
+pub(crate) fn verify_digest(digest: &str, bytes: &[u8]) -> Result<(), VerificationError> {
+    let actual = sha256_digest(bytes);
+    if actual != digest {
+        return Err(VerificationError::DigestMismatch {
+            expected: digest.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ImageStore {
+    root: PathBuf,
+    index_lock: Arc<Mutex<()>>,
+}
+
+impl ImageStore {
+    pub(crate) fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn blob_path(&self, digest: &str) -> Result<PathBuf, Error> {
+        let hex = digest
+            .strip_prefix(SHA256_PREFIX)
+            .filter(|h| h.len() == 64 && h.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or(anyhow::anyhow!("Unsupported digest {}", digest))?;
+        Ok(self.root.join(BLOBS_DIR).join("sha256").join(hex))
+    }
+
+    pub(crate) fn has_blob(&self, digest: &str) -> bool {
+        self.blob_path(digest).map(|p| p.is_file()).unwrap_or(false)
+    }
+
+    /// Reads a blob and checks its content against the digest it is stored
+    /// under, so a corrupted store never hands out bytecode.
+    pub(crate) fn read_blob(&self, digest: &str) -> Result<Vec<u8>, Error> {
+        let path = self.blob_path(digest)?;
+        let bytes = fs::read(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read blob {}: {}", digest, e))?;
+        verify_digest(digest, &bytes)?;
+        Ok(bytes)
+    }
+
+    pub(crate) fn write_blob(&self, bytes: &[u8]) -> Result<String, Error> {
+        let digest = sha256_digest(bytes);
+        let path = self.blob_path(&digest)?;
+        if path.is_file() {
+            return Ok(digest);
+        }
+
+        self.init_layout()?;
+        let dir = path.parent().ok_or(anyhow::anyhow!("Invalid blob path"))?;
+        fs::create_dir_all(dir)?;
+        // Write to a temporary file first so readers never see partial blobs.
+        let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &path)?;
+        Ok(digest)
+    }
+
+    fn init_layout(&self) -> Result<(), Error> {
+        fs::create_dir_all(&self.root)?;
+        let layout = self.root.join(OCI_LAYOUT_FILE);
+        if !layout.exists() {
+            fs::write(layout, OCI_LAYOUT_CONTENT)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_index(&self) -> Result<Index, Error> {
+        match fs::read(self.root.join(INDEX_FILE)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Index::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write_index(&self, index: &Index) -> Result<(), Error> {
+        self.init_layout()?;
+        let path = self.root.join(INDEX_FILE);
+        let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+        fs::write(&tmp, serde_json::to_vec_pretty(index)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Looks up a manifest either by digest or by its reference name.
+    pub(crate) fn resolve(&self, reference: &str) -> Result<Option<Descriptor>, Error> {
+        let index = self.read_index()?;
+        let found = index.manifests.into_iter().find(|m| {
+            m.digest == reference
+                || m.annotations.get(ANNOTATION_REF_NAME).map(|r| r.as_str()) == Some(reference)
+        });
+        if found.is_some() || !reference.starts_with(SHA256_PREFIX) {
+            return Ok(found);
+        }
+
+        // Manifests which are only referenced by digest need not be listed in
+        // the index.
+        if !self.has_blob(reference) {
+            return Ok(None);
+        }
+        let size = fs::metadata(self.blob_path(reference)?)?.len();
+        Ok(Some(Descriptor {
+            media_type: MEDIA_TYPE_IMAGE_MANIFEST.to_string(),
+            digest: reference.to_string(),
+            size,
+            annotations: HashMap::new(),
+        }))
+    }
+
+    /// Points `reference` at the given manifest, replacing any previous one.
+    pub(crate) fn tag(&self, reference: &str, manifest: &Descriptor) -> Result<(), Error> {
+        let _guard = self.index_lock.lock();
+        let mut index = self.read_index()?;
+        index.manifests.retain(|m| {
+            m.annotations.get(ANNOTATION_REF_NAME).map(|r| r.as_str()) != Some(reference)
+        });
+
+        let mut manifest = manifest.clone();
+        manifest
+            .annotations
+            .insert(ANNOTATION_REF_NAME.to_string(), reference.to_string());
+        index.manifests.push(manifest);
+        self.write_index(&index)
+    }
+
+    pub(crate) fn read_manifest(&self, descriptor: &Descriptor) -> Result<Manifest, Error> {
+        let bytes = self.read_blob(&descriptor.digest)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
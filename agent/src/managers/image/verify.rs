@@ -0,0 +1,109 @@
+//! Verification of pulled images against pinned digests and cosign signatures.
+//!
+//! Signatures are looked up the way cosign stores them: as an image tagged
+//! `sha256-<hex>.sig` in the same repository, whose simple signing layers carry
+//! a base64 signature annotation over a payload naming the signed manifest.
+
+use std::fmt;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use serde::Deserialize;
+use sigstore::crypto::{CosignVerificationKey, Signature, SigningScheme};
+use thiserror::Error;
+
+use crate::managers::image::store::sha256_digest;
+
+pub(crate) const SIMPLE_SIGNING_MEDIA_TYPE: &str =
+    "application/vnd.dev.cosign.simplesigning.v1+json";
+pub(crate) const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+/// Set on index entries of the local store once an image was verified, so a
+/// cached image is only trusted for the key it was verified with.
+pub(crate) const VERIFIED_BY_ANNOTATION: &str = "io.conductor.image.verified-by";
+
+#[derive(Error, Debug)]
+pub(crate) enum VerificationError {
+    #[error("Digest mismatch: expected {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+    #[error("No signature found for {0}")]
+    MissingSignature(String),
+    #[error("No valid signature found for {0}")]
+    InvalidSignature(String),
+    #[error("Image {0} has not been verified and pull policy is Never")]
+    Unverified(String),
+    #[error("Bytecode file {0} can not be verified, only signed images are allowed")]
+    UnsignedFile(String),
+}
+
+#[derive(Deserialize)]
+struct SimpleSigning {
+    critical: Critical,
+}
+
+#[derive(Deserialize)]
+struct Critical {
+    image: CriticalImage,
+}
+
+#[derive(Deserialize)]
+struct CriticalImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+/// Returns the digest pinned in an image url, e.g. `quay.io/foo/bar@sha256:...`.
+pub(crate) fn pinned_digest(url: &str) -> Option<&str> {
+    url.rsplit_once('@')
+        .map(|(_, digest)| digest)
+        .filter(|digest| digest.starts_with("sha256:"))
+}
+
+/// Returns the tag cosign stores the signature of `digest` under.
+pub(crate) fn signature_tag(digest: &str) -> String {
+    format!("{}.sig", digest.replacen(':', "-", 1))
+}
+
+#[derive(Clone)]
+pub(crate) struct SignatureVerifier {
+    key: Arc<CosignVerificationKey>,
+    fingerprint: String,
+}
+
+impl Debug for SignatureVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignatureVerifier")
+            .field("fingerprint", &self.fingerprint)
+            .finish()
+    }
+}
+
+impl SignatureVerifier {
+    pub(crate) fn from_pem(pem: &[u8]) -> Result<Self, anyhow::Error> {
+        let key = CosignVerificationKey::from_pem(pem, &SigningScheme::default())
+            .map_err(|e| anyhow::anyhow!("Invalid public key: {}", e))?;
+        Ok(Self {
+            key: Arc::new(key),
+            fingerprint: sha256_digest(pem),
+        })
+    }
+
+    pub(crate) fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Checks a simple signing payload and its base64 signature, and that the
+    /// payload refers to the manifest with the given digest.
+    pub(crate) fn verify_payload(&self, digest: &str, payload: &[u8], signature: &str) -> bool {
+        if self
+            .key
+            .verify_signature(Signature::Base64Encoded(signature.as_bytes()), payload)
+            .is_err()
+        {
+            return false;
+        }
+        match serde_json::from_slice::<SimpleSigning>(payload) {
+            Ok(payload) => payload.critical.image.docker_manifest_digest == digest,
+            Err(_) => false,
+        }
+    }
+}
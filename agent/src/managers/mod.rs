@@ -2,3 +2,5 @@ pub(crate) mod cache;
 pub(crate) mod image;
 pub(crate) mod prog;
 pub(crate) mod registry;
+pub(crate) mod state;
+pub(crate) mod supervisor;
@@ -1,19 +1,29 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
 
+use bpfman_api::v1::bpfman_client::BpfmanClient;
 use log::{debug, error, info};
 use parking_lot::Mutex;
 use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
+use tokio::time;
+use tonic::transport::Channel;
+use tonic::Request;
 
+use agent_api::v1::{BytecodeLocation, ProgramInfo};
 use agent_api::ProgramState;
 use agent_api::ProgramType;
 
 use crate::common::types::ListFilter;
-use crate::managers::cache::CacheManager;
+use crate::managers::cache::{CacheConfig, CacheManager};
 use crate::managers::image::ImageManager;
 use crate::managers::registry::RegistryManager;
+use crate::managers::supervisor::{
+    shutdown_received, wait_for_shutdown, RestartConfig, Supervisor, MAX_BACKOFF,
+};
 use crate::progs::types::{Program, ShutdownSignal};
+use crate::progs::wasm::program::WasmProgram;
 
 #[derive(Debug, Clone)]
 pub(crate) struct ProgManager {
@@ -21,24 +31,69 @@ pub(crate) struct ProgManager {
     pub image_manager: ImageManager,
     pub registry_manager: RegistryManager,
     pub program_handles: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
+    pub supervisor: Supervisor,
     pub shutdown_tx: broadcast::Sender<ShutdownSignal>,
+    pub bpf_client: BpfmanClient<Channel>,
+    /// The eBPF programs owning the maps of each program, by map name, for
+    /// their ids to be resolved again when the program restarts.
+    pub map_programs: Arc<Mutex<HashMap<String, HashMap<String, String>>>>,
 }
 
 impl ProgManager {
     pub(crate) async fn new(
         shutdown_tx: broadcast::Sender<ShutdownSignal>,
+        image_manager: ImageManager,
+        cache_config: CacheConfig,
+        bpf_client: BpfmanClient<Channel>,
     ) -> anyhow::Result<ProgManager> {
-        let cache_manager = CacheManager::new().await?;
+        let cache_manager = CacheManager::new(cache_config).await?;
         cache_manager.wait_for_cache_sync().await?;
         Ok(Self {
             cache_manager,
-            image_manager: ImageManager::new(),
+            image_manager,
             registry_manager: RegistryManager::new(),
             program_handles: Arc::new(Mutex::new(HashMap::new())),
+            supervisor: Supervisor::new(),
             shutdown_tx,
+            bpf_client,
+            map_programs: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 
+    /// Resolves the eBPF programs owning maps to the ids bpfman currently
+    /// knows them by.
+    pub(crate) async fn get_prog_ids_for_maps(
+        &self,
+        map_to_prog_name: HashMap<String, String>,
+    ) -> Result<HashMap<String, u32>, anyhow::Error> {
+        if map_to_prog_name.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let req = Request::new(bpfman_api::v1::ListRequest {
+            program_type: None,
+            bpfman_programs_only: None,
+            match_metadata: Default::default(),
+        });
+        let mut bpf_client = self.bpf_client.clone();
+        let response = bpf_client.list(req).await?.into_inner();
+        let loaded_ebpf_progs = response
+            .results
+            .iter()
+            .filter_map(|prog| prog.kernel_info.as_ref())
+            .map(|info| (info.name.clone(), info.id))
+            .collect::<HashMap<String, u32>>();
+
+        let mut map_to_prog_id = HashMap::new();
+        for (map_name, prog_name) in map_to_prog_name {
+            let prog_id = loaded_ebpf_progs.get(&prog_name).ok_or(anyhow::anyhow!(
+                "Required eBPF program {} not loaded",
+                prog_name
+            ))?;
+            map_to_prog_id.insert(map_name, *prog_id);
+        }
+        Ok(map_to_prog_id)
+    }
+
     pub(crate) async fn pre_load(
         &self,
         program_name: String,
@@ -76,6 +131,60 @@ impl ProgManager {
         Ok(prog)
     }
 
+    pub(crate) async fn register_wasm(
+        &self,
+        program_name: String,
+        bytecode: Vec<u8>,
+        location: BytecodeLocation,
+    ) -> Result<Arc<dyn Program>, anyhow::Error> {
+        if self.get(program_name.clone(), None).await.is_some() {
+            let err_msg = format!("Program {} already exists.", program_name);
+            error!("{}", &err_msg);
+            return Err(anyhow::Error::msg(err_msg));
+        }
+
+        let prog: Arc<dyn Program> =
+            Arc::new(WasmProgram::new(&program_name, &bytecode, location)?);
+        self.registry_manager
+            .insert_program(&program_name, prog.clone(), Some(ProgramType::Wasm))
+            .map_err(anyhow::Error::msg)?;
+        info!("Program {} registered as wasm program.", program_name);
+        Ok(prog)
+    }
+
+    /// Makes sure a builtin program instance named `program_name` of the given
+    /// kind is registered, and returns whether a new instance was created.
+    /// The kind defaults to the program name.
+    pub(crate) async fn register_builtin(
+        &self,
+        program_name: String,
+        kind: String,
+    ) -> Result<bool, anyhow::Error> {
+        let kind = if kind.is_empty() {
+            program_name.clone()
+        } else {
+            kind
+        };
+        if let Some(prog) = self.get(program_name.clone(), None).await {
+            if prog.get_kind() != kind {
+                let err_msg = format!(
+                    "Program {} already exists and is not a {} program.",
+                    program_name, kind
+                );
+                error!("{}", &err_msg);
+                return Err(anyhow::Error::msg(err_msg));
+            }
+            return Ok(false);
+        }
+
+        self.registry_manager
+            .builtin
+            .instantiate(&kind, &program_name)
+            .map_err(anyhow::Error::msg)?;
+        info!("Program {} registered as {} instance.", program_name, kind);
+        Ok(true)
+    }
+
     pub(crate) async fn get(
         &self,
         program_name: String,
@@ -92,25 +201,11 @@ impl ProgManager {
     pub(crate) async fn load(&self, prog: Arc<dyn Program>) -> Result<(), anyhow::Error> {
         match prog.get_state() {
             ProgramState::Initialized => {
-                let shutdown_rx = self.shutdown_tx.subscribe();
+                let config = RestartConfig::from_metadata(&prog.get_metadata())?;
+                self.supervisor.start(&prog.get_name());
+                let manager = self.clone();
                 let p = prog.clone();
-                let handle = tokio::spawn(async move {
-                    p.set_state(ProgramState::Running);
-                    match p.start(shutdown_rx).await {
-                        Ok(_) => {
-                            p.set_state(ProgramState::Stopped);
-                            info!("Program {} completed.", p.get_name())
-                        }
-                        Err(e) => {
-                            p.set_state(ProgramState::Failed);
-                            error!(
-                                "Program {} encountered an error during execution: {:?}",
-                                p.get_name(),
-                                e
-                            )
-                        }
-                    }
-                });
+                let handle = tokio::spawn(async move { manager.supervise(p, config).await });
 
                 let mut handlers = self.program_handles.lock();
                 handlers.insert(prog.get_name(), handle);
@@ -129,6 +224,160 @@ impl ProgManager {
         Ok(())
     }
 
+    /// Runs a program and restarts it according to its restart policy until it
+    /// is unloaded or the agent shuts down.
+    async fn supervise(&self, prog: Arc<dyn Program>, config: RestartConfig) {
+        let name = prog.get_name();
+        let mut retries = 0;
+        let mut restarting = false;
+        loop {
+            // Subscribe before checking whether the program is being unloaded,
+            // so the shutdown signal which follows cannot be missed.
+            let shutdown_rx = self.shutdown_tx.subscribe();
+            let mut control_rx = shutdown_rx.resubscribe();
+            if self.supervisor.is_stopping(&name) {
+                break;
+            }
+
+            let started = Instant::now();
+            let result = if restarting {
+                match self.reinit(&prog).await {
+                    Ok(()) => {
+                        self.registry_manager.metrics.record_restart(&name);
+                        self.run(&prog, shutdown_rx).await
+                    }
+                    Err(e) => Err(e),
+                }
+            } else {
+                self.run(&prog, shutdown_rx).await
+            };
+            let failed = match result {
+                Ok(()) => {
+                    prog.set_state(ProgramState::Stopped);
+                    info!("Program {} completed.", name);
+                    false
+                }
+                Err(e) => {
+                    prog.set_state(ProgramState::Failed);
+                    error!(
+                        "Program {} encountered an error during execution: {:?}",
+                        name, e
+                    );
+                    self.supervisor.set_last_error(&name, e.to_string());
+                    true
+                }
+            };
+
+            if started.elapsed() >= MAX_BACKOFF {
+                retries = 0;
+            }
+            if self.supervisor.is_stopping(&name)
+                || shutdown_received(&mut control_rx, &name)
+                || !config.should_restart(failed, retries)
+            {
+                break;
+            }
+
+            let backoff = config.backoff(retries);
+            retries += 1;
+            info!(
+                "Restarting program {} in {:?} (retry {}).",
+                name, backoff, retries
+            );
+            tokio::select! {
+                _ = time::sleep(backoff) => {}
+                _ = wait_for_shutdown(&mut control_rx, &name) => break,
+            }
+            restarting = true;
+        }
+    }
+
+    async fn run(
+        &self,
+        prog: &Arc<dyn Program>,
+        shutdown_rx: broadcast::Receiver<ShutdownSignal>,
+    ) -> Result<(), anyhow::Error> {
+        prog.set_state(ProgramState::Running);
+        prog.start(shutdown_rx).await
+    }
+
+    /// Brings a stopped or failed program back to a state it can be started
+    /// from, with the configuration it was running with. The eBPF programs
+    /// owning its maps are resolved again, as they may have been reloaded.
+    async fn reinit(&self, prog: &Arc<dyn Program>) -> Result<(), anyhow::Error> {
+        let info = prog.get_program_info()?;
+        let map_to_prog_name = self.map_programs.lock().get(&info.name).cloned();
+        let ebpf_maps = match map_to_prog_name {
+            Some(map_to_prog_name) => self.get_prog_ids_for_maps(map_to_prog_name).await?,
+            None => info.ebpf_maps,
+        };
+        prog.stop().await?;
+        prog.init(info.metadata, self.cache_manager.clone(), ebpf_maps)
+            .await?;
+        prog.set_state(ProgramState::Initialized);
+        Ok(())
+    }
+
+    /// Returns the program info together with the supervision status.
+    pub(crate) fn program_info(
+        &self,
+        prog: &Arc<dyn Program>,
+    ) -> Result<ProgramInfo, anyhow::Error> {
+        let mut info = prog.get_program_info()?;
+        info.kind = prog.get_kind();
+        info.restart_count = self.registry_manager.metrics.restart_count(&info.name) as u32;
+        info.last_error = self.supervisor.last_error(&info.name).unwrap_or_default();
+        Ok(info)
+    }
+
+    /// Applies new metadata and bytecode to a loaded program in place. Metadata
+    /// entries are merged into the current metadata, empty values remove a key.
+    pub(crate) async fn update(
+        &self,
+        program_name: String,
+        metadata: HashMap<String, String>,
+        bytecode: Option<(Vec<u8>, BytecodeLocation)>,
+    ) -> Result<Arc<dyn Program>, anyhow::Error> {
+        let prog = self
+            .registry_manager
+            .get_program(program_name.as_str(), None)
+            .ok_or(anyhow::Error::msg(format!(
+                "Failed to get program {} for updating.",
+                program_name
+            )))?;
+        if let ProgramState::Uninitialized = prog.get_state() {
+            return Err(anyhow::Error::msg(format!(
+                "Program {} is not loaded.",
+                program_name
+            )));
+        }
+
+        if let Some((bytecode, location)) = bytecode {
+            prog.update_bytecode(bytecode, location).await?;
+            info!("Program {} bytecode updated.", program_name);
+        }
+
+        if !metadata.is_empty() {
+            let previous = prog.get_metadata();
+            let mut updated = previous.clone();
+            for (key, value) in metadata {
+                if value.is_empty() {
+                    updated.remove(&key);
+                } else {
+                    updated.insert(key, value);
+                }
+            }
+            prog.set_metadata(updated);
+            if let Err(e) = prog.on_update() {
+                prog.set_metadata(previous);
+                return Err(e);
+            }
+            info!("Program {} metadata updated.", program_name);
+        }
+
+        Ok(prog)
+    }
+
     pub(crate) async fn unload(&self, program_name: String) -> Result<(), anyhow::Error> {
         let program = self
             .registry_manager
@@ -138,6 +387,8 @@ impl ProgManager {
                 program_name
             )))?;
 
+        self.supervisor.stop(&program_name);
+        self.map_programs.lock().remove(&program_name);
         program.stop().await?;
 
         self.shutdown_tx
@@ -166,8 +417,174 @@ impl ProgManager {
             }
         };
         program.set_state(ProgramState::Uninitialized);
+        let keep_registered = match program.get_type() {
+            ProgramType::Builtin => self
+                .registry_manager
+                .builtin
+                .is_default_instance(program_name.as_str()),
+            ProgramType::Wasm => false,
+        };
+        if !keep_registered {
+            self.registry_manager
+                .remove_program(program_name.as_str(), Some(program.get_type()));
+            self.registry_manager.metrics.remove(program_name.as_str());
+            self.supervisor.remove(program_name.as_str());
+        }
         info!("Program {} unloaded successfully.", program_name);
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+    use tonic::transport::Endpoint;
+
+    use super::*;
+    use crate::collector::Collector;
+    use crate::managers::cache::MetadataSource;
+    use crate::progs::service_map::program::ServiceMap;
+
+    /// Reports the number of times it was polled as `guest_polls`.
+    const GUEST: &str = r#"
+        (module
+          (import "conductor" "emit_metric"
+            (func $emit_metric (param i32 i32 i32 i32 i32 i32 i32 f64) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "guest_polls")
+          (data (i32.const 16) "Number of polls")
+          (global $polls (mut i32) (i32.const 0))
+          (func (export "poll") (result i32)
+            (global.set $polls (i32.add (global.get $polls) (i32.const 1)))
+            (i32.const 0))
+          (func (export "collect") (result i32)
+            (call $emit_metric
+              (i32.const 0)
+              (i32.const 0) (i32.const 11)
+              (i32.const 16) (i32.const 15)
+              (i32.const 0) (i32.const 0)
+              (f64.convert_i32_u (global.get $polls)))))
+    "#;
+
+    async fn manager() -> ProgManager {
+        let (shutdown_tx, _) = broadcast::channel(16);
+        let images = std::env::temp_dir().join(format!("agent-prog-{}", std::process::id()));
+        ProgManager::new(
+            shutdown_tx,
+            ImageManager::new(images, None),
+            CacheConfig {
+                source: MetadataSource::Local,
+                kube: Default::default(),
+                static_file: None,
+            },
+            BpfmanClient::new(Endpoint::from_static("http://localhost").connect_lazy()),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn polls(manager: &ProgManager) -> f64 {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(Collector::new(manager.registry_manager.clone())));
+        let mut buf = String::new();
+        encode(&mut buf, &registry).unwrap();
+        buf.lines()
+            .find_map(|line| line.strip_prefix("guest_polls "))
+            .map(|value| value.parse().unwrap())
+            .unwrap_or_default()
+    }
+
+    /// Waits until the counter program polled at least `count` times, failing
+    /// if it takes longer than `limit`.
+    async fn wait_for_polls(manager: &ProgManager, count: f64, limit: Duration) {
+        time::timeout(limit, async {
+            while polls(manager) < count {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("{} polls not reached in {:?}", count, limit));
+    }
+
+    #[tokio::test]
+    async fn test_update_interval() {
+        let manager = manager().await;
+        let name = "counter".to_string();
+        manager
+            .register_wasm(name.clone(), GUEST.as_bytes().to_vec(), Default::default())
+            .await
+            .unwrap();
+        let prog = manager
+            .pre_load(
+                name.clone(),
+                ProgramType::Wasm,
+                HashMap::new(),
+                manager.cache_manager.clone(),
+                HashMap::new(),
+            )
+            .await
+            .unwrap();
+        manager.load(prog.clone()).await.unwrap();
+
+        // the first tick is immediate, the next one of the default interval
+        // would only come after the limits below
+        wait_for_polls(&manager, 1.0, Duration::from_secs(5)).await;
+
+        let metadata = HashMap::from([("interval".to_string(), "1".to_string())]);
+        let prog = manager.update(name.clone(), metadata, None).await.unwrap();
+        assert_eq!(prog.get_metadata()["interval"], "1");
+        wait_for_polls(&manager, 2.0, Duration::from_secs(5)).await;
+
+        // a zero or unparseable interval is rejected, the previous one is kept
+        for interval in ["0", "soon"] {
+            let metadata = HashMap::from([("interval".to_string(), interval.to_string())]);
+            let err = manager
+                .update(name.clone(), metadata, None)
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("Invalid interval"));
+            assert_eq!(prog.get_metadata()["interval"], "1");
+        }
+        wait_for_polls(&manager, 3.0, Duration::from_secs(5)).await;
+
+        // an empty value removes the key
+        let metadata = HashMap::from([("interval".to_string(), String::new())]);
+        let prog = manager.update(name.clone(), metadata, None).await.unwrap();
+        assert!(prog.get_metadata().is_empty());
+
+        manager.unload(name).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_update_rejected() {
+        let manager = manager().await;
+        let bytecode = Some((GUEST.as_bytes().to_vec(), BytecodeLocation::default()));
+
+        let err = manager
+            .update("missing".to_string(), HashMap::new(), bytecode.clone())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Failed to get program missing"));
+
+        // builtin programs are registered but not loaded until asked to
+        let name = ServiceMap::KIND.to_string();
+        let prog = manager.get(name.clone(), None).await.unwrap();
+        assert!(manager
+            .update(name.clone(), HashMap::new(), None)
+            .await
+            .is_err());
+
+        prog.set_state(ProgramState::Initialized);
+        let err = manager
+            .update(name.clone(), HashMap::new(), bytecode)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("does not support bytecode updates"));
+    }
+}
@@ -5,11 +5,21 @@ use parking_lot::RwLock;
 
 use agent_api::ProgramType;
 
+use crate::collector::metrics::ProgramMetrics;
 use crate::common::types::ListFilter;
 use crate::progs::service_map::program::ServiceMap;
 use crate::progs::socket_tracer::program::SocketTracer;
 use crate::progs::types::Program;
 
+type BuiltinConstructor = fn(&str) -> Arc<dyn Program>;
+
+/// The builtin programs which can be instantiated, an instance named after
+/// each kind is always registered.
+const BUILTIN_KINDS: &[(&str, BuiltinConstructor)] = &[
+    (ServiceMap::KIND, |name| Arc::new(ServiceMap::new(name))),
+    (SocketTracer::KIND, |name| Arc::new(SocketTracer::new(name))),
+];
+
 #[derive(Debug, Clone)]
 pub struct BuiltinRegistry {
     inner: Arc<RwLock<AHashMap<String, Arc<dyn Program>>>>,
@@ -25,8 +35,30 @@ impl BuiltinRegistry {
 
     pub fn register_builtin_progs(&self) {
         let mut inner = self.inner.write();
-        inner.insert("service_map".to_string(), Arc::new(ServiceMap::new()));
-        inner.insert("socket_tracer".to_string(), Arc::new(SocketTracer::new()));
+        for (kind, constructor) in BUILTIN_KINDS {
+            inner.insert(kind.to_string(), constructor(kind));
+        }
+    }
+
+    /// Creates and registers a new instance of a builtin program.
+    pub fn instantiate(&self, kind: &str, name: &str) -> Result<Arc<dyn Program>, String> {
+        let (_, constructor) = BUILTIN_KINDS
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .ok_or(format!("Unknown builtin program kind {}.", kind))?;
+        let mut inner = self.inner.write();
+        if inner.contains_key(name) {
+            return Err(format!("Program {} already exists.", name));
+        }
+        let program = constructor(name);
+        inner.insert(name.to_string(), program.clone());
+        Ok(program)
+    }
+
+    /// Whether `name` is the instance registered for one of the builtin kinds,
+    /// which stays registered when it is unloaded.
+    pub fn is_default_instance(&self, name: &str) -> bool {
+        BUILTIN_KINDS.iter().any(|(kind, _)| *kind == name)
     }
 
     pub fn get(&self, name: &str) -> Option<Arc<dyn Program>> {
@@ -87,6 +119,7 @@ impl WasmRegistry {
 pub(crate) struct RegistryManager {
     pub builtin: BuiltinRegistry,
     pub wasm: WasmRegistry,
+    pub metrics: ProgramMetrics,
 }
 
 impl RegistryManager {
@@ -94,6 +127,7 @@ impl RegistryManager {
         let reg_mgr = Self {
             builtin: BuiltinRegistry::new(),
             wasm: WasmRegistry::new(),
+            metrics: ProgramMetrics::new(),
         };
         reg_mgr.builtin.register_builtin_progs();
         reg_mgr
@@ -158,3 +192,84 @@ impl RegistryManager {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::collector::Collector;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::encoding::DescriptorEncoder;
+    use prometheus_client::registry::Registry;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct MetricsCollector(RegistryManager);
+
+    impl Collector for MetricsCollector {
+        fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+            let progs = self.0.list_programs(ListFilter::default());
+            self.0.metrics.encode(&mut encoder, &progs)
+        }
+    }
+
+    #[test]
+    fn test_instantiate() {
+        let registry = RegistryManager::new();
+        let err = registry.builtin.instantiate("unknown", "foo").unwrap_err();
+        assert!(err.contains("Unknown builtin program kind unknown"));
+        assert!(registry.get_program("foo", None).is_none());
+
+        let a = registry
+            .builtin
+            .instantiate(ServiceMap::KIND, "service_map_a")
+            .unwrap();
+        let b = registry
+            .builtin
+            .instantiate(ServiceMap::KIND, "service_map_b")
+            .unwrap();
+        assert_eq!(
+            (a.get_name(), a.get_kind()),
+            ("service_map_a".to_string(), ServiceMap::KIND.to_string())
+        );
+        assert_eq!(
+            (b.get_name(), b.get_kind()),
+            ("service_map_b".to_string(), ServiceMap::KIND.to_string())
+        );
+        assert!(registry
+            .builtin
+            .instantiate(ServiceMap::KIND, "service_map_a")
+            .is_err());
+        assert!(registry
+            .builtin
+            .instantiate(SocketTracer::KIND, ServiceMap::KIND)
+            .is_err());
+
+        let mut prometheus = Registry::default();
+        prometheus.register_collector(Box::new(MetricsCollector(registry.clone())));
+        let mut buf = String::new();
+        encode(&mut buf, &prometheus).unwrap();
+        for name in [ServiceMap::KIND, "service_map_a", "service_map_b"] {
+            let series = format!(
+                "program_restarts_total{{program=\"{}\",program_type=\"builtin\"}} 0\n",
+                name
+            );
+            assert!(buf.contains(&series), "{} missing from\n{}", series, buf);
+        }
+    }
+
+    #[test]
+    fn test_default_instance() {
+        let registry = RegistryManager::new();
+        for kind in [ServiceMap::KIND, SocketTracer::KIND] {
+            assert!(registry.builtin.is_default_instance(kind));
+            assert_eq!(registry.get_program(kind, None).unwrap().get_kind(), kind);
+        }
+
+        registry
+            .builtin
+            .instantiate(ServiceMap::KIND, "service_map_a")
+            .unwrap();
+        assert!(!registry.builtin.is_default_instance("service_map_a"));
+        assert!(!registry.builtin.is_default_instance("unknown"));
+    }
+}
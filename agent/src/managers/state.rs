@@ -0,0 +1,181 @@
+//! Persistence of the programs loaded through the agent, so they can be loaded
+//! again when the agent restarts.
+//!
+//! Every program is stored as its protobuf encoded `LoadRequest` in
+//! `<dir>/<name>.pb`. Map program names are kept unresolved, since the program
+//! IDs change whenever bpfman reloads the eBPF programs. The requests may hold
+//! registry credentials, so the files are only readable by the agent.
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use log::warn;
+use prost::Message;
+
+use agent_api::v1::LoadRequest;
+
+const STATE_FILE_EXTENSION: &str = "pb";
+const STATE_FILE_MODE: u32 = 0o600;
+
+#[derive(Clone, Debug)]
+pub(crate) struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(anyhow::anyhow!("Invalid program name {:?}", name));
+        }
+        Ok(self.dir.join(format!("{}.{}", name, STATE_FILE_EXTENSION)))
+    }
+
+    pub(crate) fn save(&self, request: &LoadRequest) -> Result<(), Error> {
+        let path = self.path(&request.name)?;
+        fs::create_dir_all(&self.dir)?;
+        let tmp = path.with_extension(format!("tmp-{}", std::process::id()));
+        let _ = fs::remove_file(&tmp);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(STATE_FILE_MODE)
+            .open(&tmp)?;
+        file.write_all(&request.encode_to_vec())?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<Option<LoadRequest>, Error> {
+        match fs::read(self.path(name)?) {
+            Ok(bytes) => Ok(Some(LoadRequest::decode(bytes.as_slice())?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path(name)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns all stored requests ordered by name. Unreadable entries are
+    /// skipped so one corrupted file does not prevent the others from loading.
+    pub(crate) fn list(&self) -> Result<Vec<LoadRequest>, Error> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut requests = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(STATE_FILE_EXTENSION) {
+                continue;
+            }
+            match fs::read(&path)
+                .map_err(Error::from)
+                .and_then(|bytes| Ok(LoadRequest::decode(bytes.as_slice())?))
+            {
+                Ok(request) => requests.push(request),
+                Err(e) => warn!("Skipping program state {}: {}", path.display(), e),
+            }
+        }
+        requests.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(requests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
+
+    use agent_api::v1::bytecode_location::Location;
+    use agent_api::v1::{BytecodeImage, BytecodeLocation};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agent-state-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_save_list_remove() {
+        let store = StateStore::new(temp_dir("roundtrip"));
+        assert!(store.list().unwrap().is_empty());
+
+        let request = LoadRequest {
+            bytecode: None,
+            name: "service_map".to_string(),
+            program_type: 0,
+            ebpf_maps: HashMap::from([("conns".to_string(), "conn_tracer".to_string())]),
+            metadata: HashMap::from([("interval".to_string(), "5".to_string())]),
+            kind: "service_map".to_string(),
+        };
+        store.save(&request).unwrap();
+        store
+            .save(&LoadRequest {
+                name: "a".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        fs::write(store.dir().join("broken.pb"), b"\xff\xff").unwrap();
+
+        let requests = store.list().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].name, "a");
+        assert_eq!(requests[1], request);
+        assert_eq!(store.get("service_map").unwrap(), Some(request));
+
+        store.remove("service_map").unwrap();
+        store.remove("service_map").unwrap();
+        assert!(store.get("service_map").unwrap().is_none());
+        assert!(store.save(&LoadRequest::default()).is_err());
+
+        fs::remove_dir_all(store.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_credentials_not_readable() {
+        let store = StateStore::new(temp_dir("credentials"));
+        let request = LoadRequest {
+            name: "private".to_string(),
+            bytecode: Some(BytecodeLocation {
+                location: Some(Location::Image(BytecodeImage {
+                    url: "registry.example.com/private:v1".to_string(),
+                    image_pull_policy: 0,
+                    username: Some("user".to_string()),
+                    password: Some("secret".to_string()),
+                })),
+            }),
+            ..Default::default()
+        };
+        store.save(&request).unwrap();
+        // saved again over an existing file
+        store.save(&request).unwrap();
+
+        let path = store.path("private").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, STATE_FILE_MODE);
+        assert_eq!(store.get("private").unwrap(), Some(request));
+
+        fs::remove_dir_all(store.dir()).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+//! Restart policies of loaded programs.
+//!
+//! A policy is configured through the program metadata, e.g.
+//! `restart_policy=OnFailure restart_max_retries=5 restart_backoff=2`. Restarts
+//! back off exponentially from `restart_backoff` seconds, and a program which
+//! ran for longer than the maximum backoff gets its retries reset.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Error;
+use parking_lot::Mutex;
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::broadcast::Receiver;
+
+use crate::progs::types::ShutdownSignal;
+
+pub(crate) const RESTART_POLICY_KEY: &str = "restart_policy";
+pub(crate) const RESTART_MAX_RETRIES_KEY: &str = "restart_max_retries";
+pub(crate) const RESTART_BACKOFF_KEY: &str = "restart_backoff";
+
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl TryFrom<&str> for RestartPolicy {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Ok(match value {
+            "Never" => RestartPolicy::Never,
+            "OnFailure" => RestartPolicy::OnFailure,
+            "Always" => RestartPolicy::Always,
+            policy => return Err(anyhow::anyhow!("Invalid restart policy: {}", policy)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RestartConfig {
+    pub policy: RestartPolicy,
+    pub max_retries: Option<u32>,
+    pub backoff: Duration,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        Self {
+            policy: RestartPolicy::Never,
+            max_retries: None,
+            backoff: DEFAULT_BACKOFF,
+        }
+    }
+}
+
+impl RestartConfig {
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self, Error> {
+        let mut config = RestartConfig::default();
+        if let Some(policy) = metadata.get(RESTART_POLICY_KEY) {
+            config.policy = policy.as_str().try_into()?;
+        }
+        if let Some(retries) = metadata.get(RESTART_MAX_RETRIES_KEY) {
+            config.max_retries = Some(retries.parse().map_err(|_| {
+                anyhow::anyhow!("Invalid {}: {}", RESTART_MAX_RETRIES_KEY, retries)
+            })?);
+        }
+        if let Some(backoff) = metadata.get(RESTART_BACKOFF_KEY) {
+            let secs = backoff
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid {}: {}", RESTART_BACKOFF_KEY, backoff))?;
+            config.backoff = Duration::from_secs(secs);
+        }
+        Ok(config)
+    }
+
+    /// Whether a program should be restarted after it already was `retries`
+    /// times in a row.
+    pub(crate) fn should_restart(&self, failed: bool, retries: u32) -> bool {
+        let restart = match self.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        };
+        restart && self.max_retries.map_or(true, |max| retries < max)
+    }
+
+    pub(crate) fn backoff(&self, retries: u32) -> Duration {
+        self.backoff
+            .checked_mul(2u32.saturating_pow(retries))
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProgramStatus {
+    stopping: bool,
+    last_error: Option<String>,
+}
+
+/// Keeps track of the supervised programs, so that a program which is being
+/// unloaded is not restarted, and of the last error each program ran into.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Supervisor {
+    inner: Arc<Mutex<HashMap<String, ProgramStatus>>>,
+}
+
+impl Supervisor {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn start(&self, name: &str) {
+        let mut inner = self.inner.lock();
+        inner.entry(name.to_string()).or_default().stopping = false;
+    }
+
+    pub(crate) fn stop(&self, name: &str) {
+        let mut inner = self.inner.lock();
+        inner.entry(name.to_string()).or_default().stopping = true;
+    }
+
+    pub(crate) fn is_stopping(&self, name: &str) -> bool {
+        let inner = self.inner.lock();
+        inner.get(name).map_or(false, |status| status.stopping)
+    }
+
+    pub(crate) fn set_last_error(&self, name: &str, error: String) {
+        let mut inner = self.inner.lock();
+        inner.entry(name.to_string()).or_default().last_error = Some(error);
+    }
+
+    pub(crate) fn last_error(&self, name: &str) -> Option<String> {
+        let inner = self.inner.lock();
+        inner.get(name).and_then(|status| status.last_error.clone())
+    }
+
+    pub(crate) fn remove(&self, name: &str) {
+        let mut inner = self.inner.lock();
+        inner.remove(name);
+    }
+}
+
+fn is_shutdown(signal: &ShutdownSignal, name: &str) -> bool {
+    match signal {
+        ShutdownSignal::All => true,
+        ShutdownSignal::ProgramName(n) => n == name,
+    }
+}
+
+/// Returns whether a shutdown signal for the program was already received.
+pub(crate) fn shutdown_received(shutdown_rx: &mut Receiver<ShutdownSignal>, name: &str) -> bool {
+    loop {
+        match shutdown_rx.try_recv() {
+            Ok(signal) if is_shutdown(&signal, name) => return true,
+            Ok(_) | Err(TryRecvError::Lagged(_)) => continue,
+            Err(TryRecvError::Empty) => return false,
+            Err(TryRecvError::Closed) => return true,
+        }
+    }
+}
+
+/// Waits for a shutdown signal for the program.
+pub(crate) async fn wait_for_shutdown(shutdown_rx: &mut Receiver<ShutdownSignal>, name: &str) {
+    while let Ok(signal) = shutdown_rx.recv().await {
+        if is_shutdown(&signal, name) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_config_from_metadata() {
+        let config = RestartConfig::from_metadata(&HashMap::new()).unwrap();
+        assert_eq!(config, RestartConfig::default());
+
+        let metadata = HashMap::from([
+            (RESTART_POLICY_KEY.to_string(), "OnFailure".to_string()),
+            (RESTART_MAX_RETRIES_KEY.to_string(), "3".to_string()),
+            (RESTART_BACKOFF_KEY.to_string(), "2".to_string()),
+        ]);
+        let config = RestartConfig::from_metadata(&metadata).unwrap();
+        assert_eq!(config.policy, RestartPolicy::OnFailure);
+        assert_eq!(config.max_retries, Some(3));
+        assert_eq!(config.backoff, Duration::from_secs(2));
+
+        let metadata = HashMap::from([(RESTART_POLICY_KEY.to_string(), "Sometimes".to_string())]);
+        assert!(RestartConfig::from_metadata(&metadata).is_err());
+    }
+
+    #[test]
+    fn test_should_restart() {
+        let mut config = RestartConfig::default();
+        assert!(!config.should_restart(true, 0));
+
+        config.policy = RestartPolicy::OnFailure;
+        assert!(config.should_restart(true, 0));
+        assert!(!config.should_restart(false, 0));
+
+        config.policy = RestartPolicy::Always;
+        config.max_retries = Some(2);
+        assert!(config.should_restart(false, 1));
+        assert!(!config.should_restart(true, 2));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = RestartConfig::default();
+        assert_eq!(config.backoff(0), Duration::from_secs(1));
+        assert_eq!(config.backoff(3), Duration::from_secs(8));
+        assert_eq!(config.backoff(20), MAX_BACKOFF);
+        assert_eq!(config.backoff(100), MAX_BACKOFF);
+    }
+}
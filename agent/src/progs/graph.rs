@@ -0,0 +1,252 @@
+//! The service map as a graph document.
+//!
+//! Nodes are workloads and edges the connections observed between them. An
+//! edge is reported once for each side a connection was observed from, like
+//! the `role` label of the metrics. The graph renders either as the node and
+//! edge frames of Grafana's node graph panel, or as Graphviz DOT.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde_json::{json, Value};
+
+use crate::common::utils::fnv_hash;
+use crate::managers::cache::Workload;
+
+/// Identifies a workload the same way as the `client_id` and `server_id`
+/// labels of the metrics.
+pub(crate) fn workload_id(workload: &Workload) -> String {
+    format!(
+        "{:x}",
+        fnv_hash(&format!("{}{}", workload.name, workload.namespace))
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct GraphEdge {
+    pub source: String,
+    pub target: String,
+    pub server_port: u32,
+    /// The name of the Service port, empty when the server is not a Service.
+    pub server_port_name: String,
+    pub role: u32,
+    pub protocol: String,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub connections_opened: u64,
+    pub connections_closed: u64,
+    pub connections_active: i64,
+}
+
+impl GraphEdge {
+    fn id(&self) -> String {
+        format!(
+            "{}-{}-{}-{}-{}",
+            self.source, self.target, self.protocol, self.server_port, self.role
+        )
+    }
+
+    /// The port as shown on the edge, named when the server is a Service.
+    fn port(&self) -> String {
+        match self.server_port_name.as_str() {
+            "" => self.server_port.to_string(),
+            name => name.to_string(),
+        }
+    }
+
+    fn merge(&mut self, other: &GraphEdge) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.connections_opened += other.connections_opened;
+        self.connections_closed += other.connections_closed;
+        self.connections_active += other.connections_active;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ServiceGraph {
+    nodes: BTreeMap<String, Workload>,
+    edges: BTreeMap<String, GraphEdge>,
+}
+
+impl ServiceGraph {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an edge from `client` to `server`. The stats of an edge which is
+    /// already part of the graph are added up.
+    pub(crate) fn add_edge(&mut self, client: &Workload, server: &Workload, mut edge: GraphEdge) {
+        edge.source = workload_id(client);
+        edge.target = workload_id(server);
+        self.nodes.insert(edge.source.clone(), client.clone());
+        self.nodes.insert(edge.target.clone(), server.clone());
+        match self.edges.get_mut(&edge.id()) {
+            Some(existing) => existing.merge(&edge),
+            None => {
+                self.edges.insert(edge.id(), edge);
+            }
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: ServiceGraph) {
+        self.nodes.extend(other.nodes);
+        for (id, edge) in other.edges {
+            match self.edges.get_mut(&id) {
+                Some(existing) => existing.merge(&edge),
+                None => {
+                    self.edges.insert(id, edge);
+                }
+            }
+        }
+    }
+
+    /// Renders the graph with the fields of Grafana's node graph panel.
+    pub(crate) fn to_node_graph(&self) -> Value {
+        let nodes: Vec<Value> = self
+            .nodes
+            .iter()
+            .map(|(id, workload)| {
+                json!({
+                    "id": id,
+                    "title": workload.name,
+                    "subTitle": workload.namespace,
+                    "mainStat": workload.kind,
+                    "detail__kind": workload.kind,
+                })
+            })
+            .collect();
+        let edges: Vec<Value> = self
+            .edges
+            .iter()
+            .map(|(id, edge)| {
+                json!({
+                    "id": id,
+                    "source": edge.source,
+                    "target": edge.target,
+                    "mainStat": format!("{}/{}", edge.protocol, edge.port()),
+                    "secondaryStat": edge.bytes_sent,
+                    "detail__server_port": edge.server_port,
+                    "detail__server_port_name": edge.server_port_name,
+                    "detail__protocol": edge.protocol,
+                    "detail__role": edge.role,
+                    "detail__bytes_sent": edge.bytes_sent,
+                    "detail__bytes_received": edge.bytes_received,
+                    "detail__connections_opened": edge.connections_opened,
+                    "detail__connections_closed": edge.connections_closed,
+                    "detail__connections_active": edge.connections_active,
+                })
+            })
+            .collect();
+        json!({ "nodes": nodes, "edges": edges })
+    }
+
+    pub(crate) fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph service_map {\n");
+        for (id, workload) in self.nodes.iter() {
+            let _ = writeln!(
+                dot,
+                "  \"{}\" [label=\"{}\\n{}\\n{}\"];",
+                id,
+                escape(&workload.name),
+                escape(&workload.namespace),
+                escape(&workload.kind)
+            );
+        }
+        for edge in self.edges.values() {
+            let _ = writeln!(
+                dot,
+                "  \"{}\" -> \"{}\" [label=\"{}/{}\", bytes_sent={}, bytes_received={}, connections_active={}];",
+                edge.source,
+                edge.target,
+                escape(&edge.protocol),
+                escape(&edge.port()),
+                edge.bytes_sent,
+                edge.bytes_received,
+                edge.connections_active
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workload(name: &str) -> Workload {
+        Workload {
+            name: name.to_string(),
+            namespace: "default".to_string(),
+            kind: "Deployment".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn edge(bytes_sent: u64) -> GraphEdge {
+        GraphEdge {
+            source: String::new(),
+            target: String::new(),
+            server_port: 80,
+            server_port_name: String::new(),
+            role: 1,
+            protocol: "tcp".to_string(),
+            bytes_sent,
+            bytes_received: 0,
+            connections_opened: 1,
+            connections_closed: 0,
+            connections_active: 1,
+        }
+    }
+
+    #[test]
+    fn test_service_graph() {
+        let (frontend, backend) = (workload("frontend"), workload("backend"));
+        let mut graph = ServiceGraph::new();
+        graph.add_edge(&frontend, &backend, edge(10));
+        let mut other = ServiceGraph::new();
+        other.add_edge(&frontend, &backend, edge(5));
+        graph.merge(other);
+
+        let node_graph = graph.to_node_graph();
+        assert_eq!(node_graph["nodes"].as_array().unwrap().len(), 2);
+        let edges = node_graph["edges"].as_array().unwrap();
+        assert_eq!(edges.len(), 1);
+        assert_eq!(edges[0]["source"], workload_id(&frontend));
+        assert_eq!(edges[0]["target"], workload_id(&backend));
+        assert_eq!(edges[0]["mainStat"], "tcp/80");
+        assert_eq!(edges[0]["detail__bytes_sent"], 15);
+        assert_eq!(edges[0]["detail__connections_active"], 2);
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph service_map {\n"));
+        assert!(dot.contains(&format!(
+            "\"{}\" -> \"{}\" [label=\"tcp/80\", bytes_sent=15",
+            workload_id(&frontend),
+            workload_id(&backend)
+        )));
+        assert!(dot.contains("[label=\"backend\\ndefault\\nDeployment\"]"));
+    }
+
+    #[test]
+    fn test_named_port() {
+        let checkout = Workload {
+            kind: "Service".to_string(),
+            ..workload("checkout")
+        };
+        let mut graph = ServiceGraph::new();
+        let edge = GraphEdge {
+            server_port: 50051,
+            server_port_name: "grpc".to_string(),
+            ..edge(1)
+        };
+        graph.add_edge(&workload("frontend"), &checkout, edge);
+        assert_eq!(graph.to_node_graph()["edges"][0]["mainStat"], "tcp/grpc");
+        assert!(graph.to_dot().contains("[label=\"tcp/grpc\""));
+    }
+}
@@ -1,3 +1,5 @@
+pub(crate) mod graph;
 pub(crate) mod service_map;
 pub(crate) mod socket_tracer;
 pub(crate) mod types;
+pub(crate) mod wasm;
@@ -0,0 +1,166 @@
+//! Classification of the endpoints which are not workloads of the cluster.
+//!
+//! Named networks are configured as `name=cidr` pairs separated by commas, e.g.
+//! `rds-prod=10.20.0.0/16,internet=0.0.0.0/0`. An address belongs to the
+//! network with the longest matching prefix. Addresses outside every network
+//! are either dropped or all grouped into a single `unknown` workload.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Error;
+
+use crate::managers::cache::Workload;
+
+const EXTERNAL_KIND: &str = "External";
+const EXTERNAL_NAMESPACE: &str = "external";
+const UNKNOWN_NAME: &str = "unknown";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s
+            .split_once('/')
+            .ok_or(anyhow::anyhow!("Invalid CIDR {}: missing prefix length", s))?;
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid CIDR {}: bad address", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len: u8 = prefix_len
+            .parse()
+            .ok()
+            .filter(|len| *len <= max_len)
+            .ok_or(anyhow::anyhow!("Invalid CIDR {}: bad prefix length", s))?;
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExternalNetworks {
+    /// Ordered by decreasing prefix length, so the first match is the longest.
+    networks: Vec<(Cidr, Arc<Workload>)>,
+    unknown: Option<Arc<Workload>>,
+}
+
+impl ExternalNetworks {
+    pub(crate) fn parse(spec: &str, group_unknown: bool) -> Result<Self, Error> {
+        let mut networks = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, cidr) = entry
+                .split_once('=')
+                .ok_or(anyhow::anyhow!("Invalid external network {}", entry))?;
+            networks.push((cidr.trim().parse::<Cidr>()?, external_workload(name.trim())));
+        }
+        networks.sort_by(|(a, _), (b, _)| b.prefix_len.cmp(&a.prefix_len));
+        Ok(Self {
+            networks,
+            unknown: group_unknown.then(|| external_workload(UNKNOWN_NAME)),
+        })
+    }
+
+    /// Whether an address is in a configured network, which tells it is not a
+    /// pod IP unless the network is a default route.
+    pub(crate) fn is_outside_cluster(&self, ip: IpAddr) -> bool {
+        self.networks
+            .iter()
+            .any(|(cidr, _)| cidr.prefix_len > 0 && cidr.contains(ip))
+    }
+
+    /// Returns the workload standing for an address which is not part of the
+    /// cluster, if it is to be kept.
+    pub(crate) fn classify(&self, ip: IpAddr) -> Option<Arc<Workload>> {
+        self.networks
+            .iter()
+            .find(|(cidr, _)| cidr.contains(ip))
+            .map(|(_, workload)| workload.clone())
+            .or_else(|| self.unknown.clone())
+    }
+}
+
+fn external_workload(name: &str) -> Arc<Workload> {
+    Arc::new(Workload {
+        name: name.to_string(),
+        namespace: EXTERNAL_NAMESPACE.to_string(),
+        kind: EXTERNAL_KIND.to_string(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.20.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("10.20.3.4")));
+        assert!(cidr.contains(ip("::ffff:10.20.3.4")));
+        assert!(!cidr.contains(ip("10.21.0.1")));
+        assert!(!cidr.contains(ip("fd00::1")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(ip("fd12::1")));
+        assert!("10.0.0.1/32"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(ip("10.0.0.1")));
+
+        assert!("10.0.0.0".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("host/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_classify() {
+        let networks =
+            ExternalNetworks::parse("internet=0.0.0.0/0, rds-prod=10.20.0.0/16", false).unwrap();
+        assert_eq!(networks.classify(ip("10.20.0.5")).unwrap().name, "rds-prod");
+        let internet = networks.classify(ip("8.8.8.8")).unwrap();
+        assert_eq!(internet.name, "internet");
+        assert_eq!(internet.kind, EXTERNAL_KIND);
+        assert!(networks.classify(ip("2001:db8::1")).is_none());
+        assert!(networks.is_outside_cluster(ip("10.20.0.5")));
+        assert!(!networks.is_outside_cluster(ip("8.8.8.8")));
+
+        let networks = ExternalNetworks::parse("", true).unwrap();
+        assert_eq!(
+            networks.classify(ip("2001:db8::1")).unwrap().name,
+            "unknown"
+        );
+
+        assert!(ExternalNetworks::parse("rds-prod", false).is_err());
+        assert!(ExternalNetworks::parse("rds-prod=10.20.0.0", false).is_err());
+    }
+}
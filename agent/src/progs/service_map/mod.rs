@@ -1 +1,2 @@
+pub(crate) mod external;
 pub(crate) mod program;
@@ -1,9 +1,11 @@
 use std::cmp::PartialEq;
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::net::{IpAddr, Ipv6Addr};
 use std::path::Path;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Error;
 use async_trait::async_trait;
@@ -11,24 +13,33 @@ use aya::maps::{HashMap as AyaHashMap, Map, MapData};
 use log::debug;
 use parking_lot::RwLock;
 use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
 use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
 use prometheus_client::registry::Unit;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, Notify};
 use tokio::time;
 
 use agent_api::{ProgramState, ProgramType};
 use agent_api::v1::{BytecodeLocation, ProgramInfo};
 use conn_tracer_common::{
     CONNECTION_ROLE_CLIENT, CONNECTION_ROLE_SERVER, CONNECTION_ROLE_UNKNOWN, ConnectionKey,
-    ConnectionStats,
+    ConnectionStats, IPPROTO_TCP, IPPROTO_UDP, NatKey, NatTarget,
 };
 
 use crate::common::constants::DEFAULT_INTERVAL;
 use crate::common::constants::directories::RTDIR_FS_MAPS;
-use crate::common::utils::fnv_hash;
-use crate::managers::cache::{CacheManager, Workload};
-use crate::progs::types::{Program, ShutdownSignal};
+use crate::common::utils::{fnv_hash, monotonic_now};
+use crate::managers::cache::{CacheManager, Topology, Workload};
+use crate::progs::graph::{workload_id, GraphEdge, ServiceGraph};
+use crate::progs::service_map::external::ExternalNetworks;
+use crate::progs::types::{positive_metadata, Program, ShutdownSignal};
+
+/// UDP flows without a datagram for this many seconds are considered closed.
+const DEFAULT_UDP_IDLE_TIMEOUT: u64 = 60;
+const DEFAULT_EDGE_TTL: u64 = 3600;
+const DEFAULT_MAX_EDGES: usize = 10000;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct Connection {
@@ -36,6 +47,122 @@ pub(crate) struct Connection {
     server: Arc<Workload>,
     role: u32,
     server_port: u32,
+    /// The name of the Service port, when the server is an endpoint of a
+    /// Service.
+    server_port_name: String,
+    /// The workload the destination of the client was translated to, e.g. the
+    /// pod behind a Service ClusterIP.
+    server_backend: Option<Arc<Workload>>,
+    protocol: u32,
+    client_topology: Topology,
+    server_topology: Topology,
+}
+
+impl Connection {
+    /// Only connections between two known and different zones are cross-zone.
+    fn is_cross_zone(&self) -> bool {
+        let (client, server) = (&self.client_topology.zone, &self.server_topology.zone);
+        !client.is_empty() && !server.is_empty() && client != server
+    }
+}
+
+/// Totals of all the connections observed between two workloads.
+#[derive(Debug, Clone)]
+struct EdgeStats {
+    bytes_sent: u64,
+    bytes_received: u64,
+    opened: u64,
+    closed: u64,
+    active: i64,
+    retransmits: u64,
+    resets: u64,
+    /// Smoothed RTTs of the open TCP connections, to average them.
+    srtt_us_sum: u64,
+    srtt_samples: u64,
+    last_seen: Instant,
+}
+
+impl EdgeStats {
+    fn new(now: Instant) -> Self {
+        Self {
+            bytes_sent: 0,
+            bytes_received: 0,
+            opened: 0,
+            closed: 0,
+            active: 0,
+            retransmits: 0,
+            resets: 0,
+            srtt_us_sum: 0,
+            srtt_samples: 0,
+            last_seen: now,
+        }
+    }
+}
+
+/// Bounds the edges kept once their connections are closed. Edges with open
+/// connections are never evicted, so the counters of an exported edge only
+/// ever grow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Retention {
+    ttl: Duration,
+    max_edges: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EvictionReason {
+    Ttl,
+    Capacity,
+}
+
+impl EvictionReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EvictionReason::Ttl => "ttl",
+            EvictionReason::Capacity => "capacity",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct EvictionLabels {
+    program: String,
+    reason: String,
+}
+
+type LifetimeFamily = Family<Labels, Histogram, fn() -> Histogram>;
+type NatMap = AyaHashMap<MapData, NatKey, NatTarget>;
+
+/// Where the destination the DNAT of a connection translated its destination
+/// to is looked up.
+trait NatLookup: Debug + Send + Sync {
+    fn nat_target(&self, key: &ConnectionKey) -> Option<NatTarget>;
+}
+
+impl NatLookup for NatMap {
+    fn nat_target(&self, key: &ConnectionKey) -> Option<NatTarget> {
+        let nat_key = NatKey {
+            src_addr: key.src_addr,
+            src_port: key.src_port,
+            dest_addr: key.dest_addr,
+            dest_port: key.dest_port,
+            protocol: key.protocol,
+        };
+        self.get(&nat_key, 0).ok()
+    }
+}
+
+fn new_lifetime_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.001, 4.0, 12))
+}
+
+/// A connection already counted on an edge. Its close is folded into the same
+/// edge, even if its peers would resolve differently by then, and it is folded
+/// with the stats it was last seen with if the kernel drops it from its LRU
+/// map.
+#[derive(Debug)]
+struct OpenConnection {
+    connection: Connection,
+    stats: ConnectionStats,
 }
 
 #[derive(Debug)]
@@ -46,65 +173,177 @@ struct Inner {
     ebpf_maps: HashMap<String, u32>,
     metadata: HashMap<String, String>,
     current_conns_map: Option<AyaHashMap<MapData, ConnectionKey, ConnectionStats>>,
-    past_conns_map: HashMap<Connection, u64>,
+    nat_map: Option<Box<dyn NatLookup>>,
+    past_conns_map: HashMap<Connection, EdgeStats>,
+    open_conns: HashMap<ConnectionKey, OpenConnection>,
+    /// The edges as of the last poll, which scrapes and graph requests read
+    /// without polling.
+    edges: Arc<HashMap<Connection, EdgeStats>>,
+    conn_lifetimes: LifetimeFamily,
+    evictions: Family<EvictionLabels, Counter>,
+    /// Bytes by zone pair, counted as they are observed so that evicting the
+    /// edges they were transferred on does not decrease them.
+    zone_sent: Family<ZoneLabels, Counter>,
+    zone_received: Family<ZoneLabels, Counter>,
+    external_networks: ExternalNetworks,
     cache_mgr: Option<CacheManager>,
 }
 
 impl Inner {
-    fn new() -> Self {
+    fn new(name: &str) -> Self {
         Self {
-            name: "service_map".to_string(),
+            name: name.to_string(),
             program_type: ProgramType::Builtin,
             program_state: ProgramState::Uninitialized,
             ebpf_maps: HashMap::new(),
             metadata: HashMap::new(),
             current_conns_map: None,
+            nat_map: None,
             past_conns_map: HashMap::new(),
+            open_conns: HashMap::new(),
+            edges: Arc::default(),
+            conn_lifetimes: LifetimeFamily::new_with_constructor(new_lifetime_histogram),
+            evictions: Family::default(),
+            zone_sent: Family::default(),
+            zone_received: Family::default(),
+            external_networks: ExternalNetworks::default(),
             cache_mgr: None,
         }
     }
+
+    /// Counts the bytes a connection transferred since it was `last_seen`
+    /// towards the totals of its zone pair.
+    fn count_zone_bytes(
+        &self,
+        connection: &Connection,
+        stats: &ConnectionStats,
+        last_seen: Option<&ConnectionStats>,
+    ) {
+        let (sent, received) =
+            last_seen.map_or((0, 0), |last| (last.bytes_sent, last.bytes_received));
+        let labels = ZoneLabels::new(&self.name, connection);
+        self.zone_sent
+            .get_or_create(&labels)
+            .inc_by(stats.bytes_sent.saturating_sub(sent));
+        self.zone_received
+            .get_or_create(&labels)
+            .inc_by(stats.bytes_received.saturating_sub(received));
+    }
 }
 
 #[derive(Debug)]
 pub struct ServiceMap {
     inner: Arc<RwLock<Inner>>,
+    updated: Arc<Notify>,
 }
 
 impl ServiceMap {
-    pub fn new() -> Self {
+    pub(crate) const KIND: &'static str = "service_map";
+
+    pub fn new(name: &str) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(Inner::new())),
+            inner: Arc::new(RwLock::new(Inner::new(name))),
+            updated: Arc::new(Notify::new()),
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        let interval = positive_metadata(&self.get_metadata(), "interval")
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_INTERVAL);
+        Duration::from_secs(interval)
+    }
+
+    fn udp_idle_timeout(&self) -> Duration {
+        let timeout = positive_metadata(&self.get_metadata(), "udp_idle_timeout")
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_UDP_IDLE_TIMEOUT);
+        Duration::from_secs(timeout)
+    }
+
+    fn retention(&self) -> Retention {
+        let metadata = self.get_metadata();
+        let ttl = positive_metadata(&metadata, "edge_ttl")
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_EDGE_TTL);
+        let max_edges = positive_metadata(&metadata, "max_edges")
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_MAX_EDGES);
+        Retention {
+            ttl: Duration::from_secs(ttl),
+            max_edges,
         }
     }
 
     async fn reset(&self) {
         let mut inner = self.inner.write();
         inner.current_conns_map = None;
+        inner.nat_map = None;
         inner.past_conns_map.clear();
+        inner.open_conns.clear();
+        inner.edges = Arc::default();
+        inner.conn_lifetimes.clear();
+        inner.evictions.clear();
+        inner.zone_sent.clear();
+        inner.zone_received.clear();
+        inner.external_networks = ExternalNetworks::default();
         inner.metadata.clear();
         inner.ebpf_maps.clear();
     }
 
-    fn poll(&self) -> Result<HashMap<Connection, u64>, Error> {
-        let inner = self.inner.read();
-        let tcp_conns_map = inner
+    fn poll(&self) -> Result<(), Error> {
+        let idle_since = monotonic_now()?.saturating_sub(self.udp_idle_timeout());
+        let retention = self.retention();
+        let now = Instant::now();
+        let mut guard = self.inner.write();
+        let inner = &mut *guard;
+        let conns = inner
             .current_conns_map
             .as_ref()
-            .ok_or(Error::msg("No current connections map"))?;
-        let past_conns_map = inner.past_conns_map.clone();
+            .ok_or(Error::msg("No current connections map"))?
+            .iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (edges, closed) = self.update_edges(inner, conns, idle_since, retention, now)?;
+        if let Some(conns_map) = inner.current_conns_map.as_mut() {
+            for key in closed {
+                if let Err(e) = conns_map.remove(&key) {
+                    debug!("Failed to remove a closed connection: {:?}", e);
+                }
+            }
+        }
+        inner.edges = Arc::new(edges);
+        Ok(())
+    }
+
+    /// Folds the connections read from the connections map into the edges,
+    /// and returns the edges with the open connections added in, along with
+    /// the closed connections to remove from the map.
+    fn update_edges(
+        &self,
+        inner: &mut Inner,
+        conns: Vec<(ConnectionKey, ConnectionStats)>,
+        idle_since: Duration,
+        retention: Retention,
+        now: Instant,
+    ) -> Result<(HashMap<Connection, EdgeStats>, Vec<ConnectionKey>), Error> {
         let cache_mgr = inner
             .cache_mgr
             .as_ref()
             .ok_or(Error::msg("No cache manager"))?
             .clone();
 
-        let mut keys_to_remove = Vec::new();
-        let mut current_conns: HashMap<Connection, u64> = HashMap::new();
-
-        for item in tcp_conns_map.iter() {
-            let (key, stats) = item?;
-            if stats.is_active != 1 {
-                keys_to_remove.push(key);
+        let mut inactive_conns = Vec::new();
+        let mut active_conns = Vec::new();
+        let mut live_keys = HashSet::new();
+        for (key, stats) in conns {
+            live_keys.insert(key);
+            if stats.is_active != 1 || is_idle(&key, &stats, idle_since) {
+                inactive_conns.push((key, stats));
                 continue;
             }
             if key.src_addr == key.dest_addr || self.is_loopback_address(key.dest_addr) {
@@ -113,99 +352,499 @@ impl ServiceMap {
             if key.role == CONNECTION_ROLE_UNKNOWN {
                 continue;
             }
+            active_conns.push((key, stats));
+        }
+        // Connections the kernel dropped from its LRU map never report their
+        // close, so they are folded with the stats they were last seen with.
+        let dropped_conns: Vec<_> = inner
+            .open_conns
+            .iter()
+            .filter(|(key, _)| !live_keys.contains(*key))
+            .map(|(key, open)| (*key, open.stats))
+            .collect();
 
-            if let Ok(connection) = self.build_connection(key, &cache_mgr) {
-                current_conns
-                    .entry(connection.clone())
-                    .and_modify(|e| *e += stats.bytes_sent)
-                    .or_insert(stats.bytes_sent);
+        // A closed connection which was never counted and whose peers are
+        // unknown is only removed from the map.
+        let mut closed = Vec::new();
+        for (key, stats) in inactive_conns {
+            closed.push(key);
+            if let Err(e) = self.handle_inactive_connection(key, stats, inner, &cache_mgr, now) {
+                debug!("Failed to fold a closed connection: {:?}", e);
             }
         }
+        for (key, stats) in dropped_conns {
+            let _ = self.handle_inactive_connection(key, stats, inner, &cache_mgr, now);
+        }
 
-        for (conn, bytes_sent) in past_conns_map.iter() {
-            current_conns
-                .entry(conn.clone())
-                .and_modify(|e| *e += *bytes_sent)
-                .or_insert(*bytes_sent);
+        let mut active_edges = Vec::new();
+        for (key, stats) in active_conns {
+            let last_seen = inner.open_conns.get(&key).map(|open| open.stats);
+            let connection = match inner.open_conns.get(&key) {
+                Some(open) => open.connection.clone(),
+                None => match self.build_connection(
+                    key,
+                    &stats,
+                    &cache_mgr,
+                    &inner.external_networks,
+                    inner.nat_map.as_deref(),
+                ) {
+                    Ok(connection) => connection,
+                    Err(_) => continue,
+                },
+            };
+            inner.count_zone_bytes(&connection, &stats, last_seen.as_ref());
+            inner.open_conns.insert(
+                key,
+                OpenConnection {
+                    connection: connection.clone(),
+                    stats,
+                },
+            );
+            let edge = inner
+                .past_conns_map
+                .entry(connection.clone())
+                .or_insert_with(|| EdgeStats::new(now));
+            edge.last_seen = now;
+            if last_seen.is_none() {
+                edge.opened += 1;
+            }
+            active_edges.push((connection, stats));
         }
 
-        // Release the read lock before removing inactive connections
-        drop(inner);
+        let active: HashSet<&Connection> = active_edges.iter().map(|(conn, _)| conn).collect();
+        for (conn, reason) in evict_edges(&mut inner.past_conns_map, &active, retention, now) {
+            let _ = inner
+                .conn_lifetimes
+                .remove(&Labels::new(&inner.name, &conn));
+            inner
+                .evictions
+                .get_or_create(&EvictionLabels {
+                    program: inner.name.clone(),
+                    reason: reason.as_str().to_string(),
+                })
+                .inc();
+        }
 
-        let mut inner = self.inner.write();
-        for key in keys_to_remove {
-            let _ = self.handle_inactive_connection(key, &mut inner, &cache_mgr);
+        let mut edges = inner.past_conns_map.clone();
+        for (conn, stats) in active_edges {
+            if let Some(edge) = edges.get_mut(&conn) {
+                edge.bytes_sent += stats.bytes_sent;
+                edge.bytes_received += stats.bytes_received;
+                edge.active += 1;
+                edge.retransmits += stats.retransmits;
+                edge.resets += stats.resets;
+                if stats.srtt_us > 0 {
+                    edge.srtt_us_sum += stats.srtt_us;
+                    edge.srtt_samples += 1;
+                }
+            }
         }
 
-        Ok(current_conns)
+        Ok((edges, closed))
     }
 
-    fn resolve_ip(&self, ip: u32, cache_mgr_ref: &CacheManager) -> Option<Arc<Workload>> {
-        let ip_to_workload_lock = cache_mgr_ref.ip_to_workload.clone();
-        let ip_to_workload = ip_to_workload_lock.read();
-        let ip_addr = Ipv4Addr::from(ip);
-        let ip_string = ip_addr.to_string();
-        ip_to_workload.get(&ip_string).cloned()
+    /// Resolves an address to the workload it belonged to at `seen_ns`, falling
+    /// back to the configured external networks for addresses outside the
+    /// cluster.
+    fn resolve_ip(
+        &self,
+        ip: [u8; 16],
+        seen_ns: u64,
+        cache_mgr_ref: &CacheManager,
+        external_networks: &ExternalNetworks,
+    ) -> Option<Arc<Workload>> {
+        let ip = to_ip_addr(ip);
+        let workload = match seen_ns {
+            0 => cache_mgr_ref.resolve_ip(ip),
+            ns => cache_mgr_ref.resolve_ip_at(ip, Duration::from_nanos(ns)),
+        };
+        if workload.is_none() && external_networks.is_outside_cluster(ip) {
+            cache_mgr_ref.skip_lookup(ip);
+        }
+        workload.or_else(|| external_networks.classify(ip))
     }
 
     fn build_connection(
         &self,
         key: ConnectionKey,
+        stats: &ConnectionStats,
         cache_mgr_ref: &CacheManager,
+        external_networks: &ExternalNetworks,
+        nat_map: Option<&dyn NatLookup>,
     ) -> Result<Connection, Error> {
-        let client_workload = self
-            .resolve_ip(key.src_addr, cache_mgr_ref)
+        // The peer of a connection is the owner of its address when it started,
+        // even if the address was reassigned since.
+        let seen_ns = match stats.start_ns {
+            0 => stats.last_seen_ns,
+            start_ns => start_ns,
+        };
+        // The process owning the socket tells the local workload apart from
+        // the other pods sharing the host network.
+        let client_workload = (key.pid != 0)
+            .then(|| cache_mgr_ref.resolve_pid(key.pid))
+            .flatten()
+            .or_else(|| self.resolve_ip(key.src_addr, seen_ns, cache_mgr_ref, external_networks))
             .ok_or(Error::msg(format!(
                 "Unknown IP: {}",
-                Ipv4Addr::from(key.src_addr)
+                to_ip_addr(key.src_addr)
             )))?;
         let server_workload = self
-            .resolve_ip(key.dest_addr, cache_mgr_ref)
+            .resolve_ip(key.dest_addr, seen_ns, cache_mgr_ref, external_networks)
             .ok_or(Error::msg(format!(
                 "Unknown IP: {}",
-                Ipv4Addr::from(key.dest_addr)
+                to_ip_addr(key.dest_addr)
             )))?;
 
-        let (client, server, port) = match key.role {
-            CONNECTION_ROLE_CLIENT => (client_workload, server_workload, key.dest_port),
-            CONNECTION_ROLE_SERVER => (server_workload, client_workload, key.src_port),
+        let topology = |ip| {
+            cache_mgr_ref
+                .resolve_topology(to_ip_addr(ip))
+                .unwrap_or_default()
+        };
+        let (client_topology, server_topology) = (topology(key.src_addr), topology(key.dest_addr));
+
+        let (client, server, port, client_topology, server_topology) = match key.role {
+            CONNECTION_ROLE_CLIENT => (
+                client_workload,
+                server_workload,
+                key.dest_port,
+                client_topology,
+                server_topology,
+            ),
+            CONNECTION_ROLE_SERVER => (
+                server_workload,
+                client_workload,
+                key.src_port,
+                server_topology,
+                client_topology,
+            ),
             _ => return Err(Error::msg("Unknown connection role")),
         };
 
+        // A server which is an endpoint of a Service is reported as the Service,
+        // with the name of the port.
+        let server_addr = match key.role {
+            CONNECTION_ROLE_CLIENT => key.dest_addr,
+            _ => key.src_addr,
+        };
+        let service = u16::try_from(port).ok().and_then(|port| {
+            let protocol = protocol_name(key.protocol).to_uppercase();
+            cache_mgr_ref.resolve_service(to_ip_addr(server_addr), port, &protocol)
+        });
+        let (server, server_port_name) = match service {
+            Some(endpoint) => (endpoint.service.clone(), endpoint.port_name.clone()),
+            None => (server, String::new()),
+        };
+
+        // The conntrack of the client node knows the backend the DNAT of a
+        // ClusterIP picked, the socket only knows the ClusterIP.
+        let server_backend = match key.role {
+            CONNECTION_ROLE_CLIENT => nat_map.and_then(|nat_map| nat_map.nat_target(&key)),
+            _ => None,
+        }
+        .and_then(|target| {
+            self.resolve_ip(target.dest_addr, seen_ns, cache_mgr_ref, external_networks)
+        });
+
         Ok(Connection {
             client,
             server,
             role: key.role,
             server_port: port,
+            server_port_name,
+            server_backend,
+            protocol: key.protocol,
+            client_topology,
+            server_topology,
         })
     }
 
     fn handle_inactive_connection(
         &self,
         key: ConnectionKey,
+        stats: ConnectionStats,
         inner: &mut Inner,
         cache_mgr_ref: &CacheManager,
+        now: Instant,
     ) -> Result<(), Error> {
-        let throughput = match inner.current_conns_map.as_mut().unwrap().get(&key, 0) {
-            Ok(stats) => stats.bytes_sent,
-            Err(_) => 0,
+        let open = inner.open_conns.remove(&key);
+        let last_seen = open.as_ref().map(|open| open.stats);
+        let connection = match open {
+            Some(open) => open.connection,
+            None => self.build_connection(
+                key,
+                &stats,
+                cache_mgr_ref,
+                &inner.external_networks,
+                inner.nat_map.as_deref(),
+            )?,
         };
-        inner.current_conns_map.as_mut().unwrap().remove(&key)?;
-        let connection = self.build_connection(key, cache_mgr_ref)?;
-        inner
+
+        if stats.start_ns != 0 {
+            let lifetime = Duration::from_nanos(stats.last_seen_ns.saturating_sub(stats.start_ns));
+            inner
+                .conn_lifetimes
+                .get_or_create(&Labels::new(&inner.name, &connection))
+                .observe(lifetime.as_secs_f64());
+        }
+        inner.count_zone_bytes(&connection, &stats, last_seen.as_ref());
+
+        let edge = inner
             .past_conns_map
             .entry(connection)
-            .and_modify(|e| *e += throughput)
-            .or_insert(throughput);
+            .or_insert_with(|| EdgeStats::new(now));
+        edge.last_seen = now;
+        edge.bytes_sent += stats.bytes_sent;
+        edge.bytes_received += stats.bytes_received;
+        edge.retransmits += stats.retransmits;
+        edge.resets += stats.resets;
+        if last_seen.is_none() {
+            edge.opened += 1;
+        }
+        edge.closed += 1;
         Ok(())
     }
 
-    fn is_loopback_address(&self, addr: u32) -> bool {
-        let ip_addr = Ipv4Addr::from(addr);
-        ip_addr.is_loopback()
+    fn encode_edges(
+        &self,
+        encoder: &mut DescriptorEncoder,
+        edges: &HashMap<Connection, EdgeStats>,
+    ) -> Result<(), Error> {
+        let program = self.get_name();
+        let conn_metric = Family::<Labels, Gauge>::default();
+        let sent_metric = Family::<Labels, Counter>::default();
+        let received_metric = Family::<Labels, Counter>::default();
+        let opened_metric = Family::<Labels, Counter>::default();
+        let closed_metric = Family::<Labels, Counter>::default();
+        let active_metric = Family::<Labels, Gauge>::default();
+        let rtt_metric = Family::<Labels, Gauge<f64, AtomicU64>>::default();
+        let retransmits_metric = Family::<Labels, Counter>::default();
+        let resets_metric = Family::<Labels, Counter>::default();
+        for (conn, edge) in edges {
+            let labels = Labels::new(&program, conn);
+            conn_metric
+                .get_or_create(&labels)
+                .set(edge.bytes_sent as i64);
+            sent_metric.get_or_create(&labels).inc_by(edge.bytes_sent);
+            received_metric
+                .get_or_create(&labels)
+                .inc_by(edge.bytes_received);
+            opened_metric.get_or_create(&labels).inc_by(edge.opened);
+            closed_metric.get_or_create(&labels).inc_by(edge.closed);
+            active_metric.get_or_create(&labels).set(edge.active);
+            retransmits_metric
+                .get_or_create(&labels)
+                .inc_by(edge.retransmits);
+            resets_metric.get_or_create(&labels).inc_by(edge.resets);
+            if edge.srtt_samples > 0 {
+                let srtt = edge.srtt_us_sum as f64 / edge.srtt_samples as f64;
+                rtt_metric.get_or_create(&labels).set(srtt / 1_000_000.0);
+            }
+        }
+        let edges_metric = Gauge::<i64>::default();
+        edges_metric.set(edges.len() as i64);
+        let (lifetime_metric, evictions_metric, zone_sent_metric, zone_received_metric) = {
+            let inner = self.inner.read();
+            (
+                inner.conn_lifetimes.clone(),
+                inner.evictions.clone(),
+                inner.zone_sent.clone(),
+                inner.zone_received.clone(),
+            )
+        };
+
+        encode_metric(
+            encoder,
+            "connection_observed",
+            "total bytes_sent value of connections observed",
+            Some(&Unit::Bytes),
+            &conn_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connection_sent",
+            "bytes sent over the connections between two workloads",
+            Some(&Unit::Bytes),
+            &sent_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connection_received",
+            "bytes received over the connections between two workloads",
+            Some(&Unit::Bytes),
+            &received_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connections_opened",
+            "connections opened between two workloads",
+            None,
+            &opened_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connections_closed",
+            "connections closed between two workloads",
+            None,
+            &closed_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connections_active",
+            "connections currently open between two workloads",
+            None,
+            &active_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connection_lifetime",
+            "lifetime of the closed connections between two workloads",
+            Some(&Unit::Seconds),
+            &lifetime_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connection_rtt",
+            "mean smoothed round trip time of the open TCP connections between two workloads",
+            Some(&Unit::Seconds),
+            &rtt_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connection_retransmits",
+            "TCP segments retransmitted on the connections between two workloads",
+            None,
+            &retransmits_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connection_resets",
+            "TCP resets sent on the connections between two workloads",
+            None,
+            &resets_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "zone_sent",
+            "bytes sent over the connections between two topology zones",
+            Some(&Unit::Bytes),
+            &zone_sent_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "zone_received",
+            "bytes received over the connections between two topology zones",
+            Some(&Unit::Bytes),
+            &zone_received_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connection_edges",
+            "edges between two workloads currently retained",
+            None,
+            &edges_metric,
+        )?;
+        encode_metric(
+            encoder,
+            "connection_edges_evicted",
+            "edges between two workloads evicted from the retained history",
+            None,
+            &evictions_metric,
+        )?;
+
+        Ok(())
+    }
+
+    fn is_loopback_address(&self, addr: [u8; 16]) -> bool {
+        to_ip_addr(addr).is_loopback()
+    }
+}
+
+/// Rejects the numeric settings which are not positive numbers.
+fn validate_metadata(metadata: &HashMap<String, String>) -> Result<(), Error> {
+    for key in ["interval", "udp_idle_timeout", "edge_ttl"] {
+        positive_metadata::<u64>(metadata, key)?;
+    }
+    positive_metadata::<usize>(metadata, "max_edges")?;
+    Ok(())
+}
+
+fn external_networks(metadata: &HashMap<String, String>) -> Result<ExternalNetworks, Error> {
+    ExternalNetworks::parse(
+        metadata.get("external_cidrs").map_or("", |c| c.as_str()),
+        metadata.get("group_unknown").map_or(false, |g| g == "true"),
+    )
+}
+
+/// Evicts the edges without open connections which were not seen for the
+/// retention TTL, then the least recently seen ones until at most `max_edges`
+/// edges remain.
+fn evict_edges(
+    edges: &mut HashMap<Connection, EdgeStats>,
+    active: &HashSet<&Connection>,
+    retention: Retention,
+    now: Instant,
+) -> Vec<(Connection, EvictionReason)> {
+    let mut evicted = Vec::new();
+    let mut idle: Vec<(Connection, Instant)> = Vec::new();
+    for (conn, edge) in edges.iter() {
+        if active.contains(conn) {
+            continue;
+        }
+        if now.saturating_duration_since(edge.last_seen) >= retention.ttl {
+            evicted.push((conn.clone(), EvictionReason::Ttl));
+        } else {
+            idle.push((conn.clone(), edge.last_seen));
+        }
+    }
+
+    let excess = (edges.len() - evicted.len()).saturating_sub(retention.max_edges);
+    if excess > 0 {
+        idle.sort_by_key(|(_, last_seen)| *last_seen);
+        evicted.extend(
+            idle.into_iter()
+                .take(excess)
+                .map(|(conn, _)| (conn, EvictionReason::Capacity)),
+        );
+    }
+
+    for (conn, _) in evicted.iter() {
+        edges.remove(conn);
+    }
+    evicted
+}
+
+fn encode_metric(
+    encoder: &mut DescriptorEncoder,
+    name: &str,
+    help: &str,
+    unit: Option<&Unit>,
+    metric: &impl EncodeMetric,
+) -> Result<(), Error> {
+    let metric_encoder = encoder.encode_descriptor(name, help, unit, metric.metric_type())?;
+    metric.encode(metric_encoder)?;
+    Ok(())
+}
+
+/// Whether a UDP flow saw no datagram since `idle_since`. TCP connections are
+/// only closed by their state changes.
+fn is_idle(key: &ConnectionKey, stats: &ConnectionStats, idle_since: Duration) -> bool {
+    key.protocol == IPPROTO_UDP && Duration::from_nanos(stats.last_seen_ns) < idle_since
+}
+
+fn protocol_name(protocol: u32) -> String {
+    match protocol {
+        IPPROTO_TCP => "tcp".to_string(),
+        IPPROTO_UDP => "udp".to_string(),
+        p => p.to_string(),
     }
 }
 
+/// Converts an address as stored by conn-tracer, where IPv4 addresses are
+/// IPv4-mapped, back to an IPv4 or IPv6 address.
+fn to_ip_addr(addr: [u8; 16]) -> IpAddr {
+    Ipv6Addr::from(addr).to_canonical()
+}
+
 #[async_trait]
 impl Program for ServiceMap {
     async fn init(
@@ -214,7 +853,9 @@ impl Program for ServiceMap {
         cache_manager: CacheManager,
         maps: HashMap<String, u32>,
     ) -> Result<(), Error> {
+        validate_metadata(&metadata)?;
         let mut inner = self.inner.write();
+        inner.external_networks = external_networks(&metadata)?;
         inner.ebpf_maps = maps.clone();
         inner.metadata = metadata;
         inner.cache_mgr = Some(cache_manager);
@@ -237,19 +878,31 @@ impl Program for ServiceMap {
                 .map_err(|_| anyhow::anyhow!("Failed to convert map"))?;
         inner.current_conns_map = Some(tcp_conns_map);
 
+        // programs built without the conntrack probe have no NAT map
+        let nat_map_name = "NAT_TRANSLATIONS";
+        inner.nat_map = match maps.get(nat_map_name) {
+            Some(prog_id) => {
+                let map_pin_path = bpfman_maps.join(format!("{}/{}", prog_id, nat_map_name));
+                let map_data = MapData::from_pin(map_pin_path)
+                    .map_err(|_| anyhow::anyhow!("No maps named NAT_TRANSLATIONS"))?;
+                let nat_map: NatMap = Map::HashMap(map_data)
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Failed to convert map"))?;
+                Some(Box::new(nat_map))
+            }
+            None => {
+                debug!("No map named NAT_TRANSLATIONS, DNAT is not resolved");
+                None
+            }
+        };
+
         Ok(())
     }
     async fn start(
         &self,
         mut shutdown_rx: broadcast::Receiver<ShutdownSignal>,
     ) -> Result<(), Error> {
-        let metadata = self.get_metadata();
-        let interval = metadata
-            .get("interval")
-            .and_then(|i| i.parse::<u64>().ok())
-            .unwrap_or(DEFAULT_INTERVAL);
-
-        let mut interval = time::interval(Duration::from_secs(interval));
+        let mut interval = time::interval(self.interval());
         loop {
             tokio::select! {
                 _ = interval.tick() => {
@@ -258,6 +911,13 @@ impl Program for ServiceMap {
                         return Err(e.into());
                     }
                 }
+                _ = self.updated.notified() => {
+                    let period = self.interval();
+                    if period != interval.period() {
+                        debug!("Changing poll interval to {:?}", period);
+                        interval = time::interval(period);
+                    }
+                }
                 Ok(signal) = shutdown_rx.recv() => {
                     match signal {
                         ShutdownSignal::All => {
@@ -282,49 +942,33 @@ impl Program for ServiceMap {
     }
 
     fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
-        let conns = self.poll()?;
-        let conn_metric = Family::<Labels, Gauge>::default();
-        for (conn, value) in conns.iter() {
-            let labels = Labels {
-                conn_id: format!(
-                    "{:x}",
-                    fnv_hash(&format!(
-                        "{}{}{}{}",
-                        conn.client.name,
-                        conn.client.namespace,
-                        conn.server.name,
-                        conn.server.namespace
-                    ))
-                ),
-                client_id: format!(
-                    "{:x}",
-                    fnv_hash(&format!("{}{}", conn.client.name, conn.client.namespace))
-                ),
-                client_name: conn.client.name.clone(),
-                client_namespace: conn.client.namespace.clone(),
-                client_kind: conn.client.kind.clone(),
-                server_id: format!(
-                    "{:x}",
-                    fnv_hash(&format!("{}{}", conn.server.name, conn.server.namespace))
-                ),
-                server_name: conn.server.name.clone(),
-                server_namespace: conn.server.namespace.clone(),
-                server_kind: conn.server.kind.clone(),
-                server_port: conn.server_port.to_string(),
-                role: conn.role.to_string(),
-            };
-            conn_metric.get_or_create(&labels).set(*value as i64);
-        }
-
-        let metric_encoder = encoder.encode_descriptor(
-            "connection_observed",
-            "total bytes_sent value of connections observed",
-            Some(&Unit::Bytes),
-            conn_metric.metric_type(),
-        )?;
-        conn_metric.encode(metric_encoder)?;
+        let edges = self.inner.read().edges.clone();
+        self.encode_edges(encoder, &edges)
+    }
 
-        Ok(())
+    fn service_graph(&self) -> Result<Option<ServiceGraph>, Error> {
+        let edges = self.inner.read().edges.clone();
+        let mut graph = ServiceGraph::new();
+        for (conn, edge) in edges.iter() {
+            graph.add_edge(
+                &conn.client,
+                &conn.server,
+                GraphEdge {
+                    source: String::new(),
+                    target: String::new(),
+                    server_port: conn.server_port,
+                    server_port_name: conn.server_port_name.clone(),
+                    role: conn.role,
+                    protocol: protocol_name(conn.protocol),
+                    bytes_sent: edge.bytes_sent,
+                    bytes_received: edge.bytes_received,
+                    connections_opened: edge.opened,
+                    connections_closed: edge.closed,
+                    connections_active: edge.active,
+                },
+            );
+        }
+        Ok(Some(graph))
     }
 
     fn get_name(&self) -> String {
@@ -347,6 +991,10 @@ impl Program for ServiceMap {
         inner.program_type.clone()
     }
 
+    fn get_kind(&self) -> String {
+        Self::KIND.to_string()
+    }
+
     fn get_metadata(&self) -> HashMap<String, String> {
         let inner = self.inner.read();
         inner.metadata.clone()
@@ -367,12 +1015,23 @@ impl Program for ServiceMap {
             bytecode: None,
             ebpf_maps: self.inner.read().ebpf_maps.clone(),
             metadata: self.get_metadata(),
+            ..Default::default()
         })
     }
+
+    fn on_update(&self) -> Result<(), Error> {
+        let metadata = self.get_metadata();
+        validate_metadata(&metadata)?;
+        let external_networks = external_networks(&metadata)?;
+        self.inner.write().external_networks = external_networks;
+        self.updated.notify_one();
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 struct Labels {
+    program: String,
     conn_id: String,
     client_id: String,
     client_name: String,
@@ -383,5 +1042,717 @@ struct Labels {
     server_namespace: String,
     server_kind: String,
     server_port: String,
+    server_port_name: String,
+    server_backend_name: String,
+    server_backend_namespace: String,
+    server_backend_kind: String,
+    role: String,
+    protocol: String,
+    client_zone: String,
+    client_region: String,
+    server_zone: String,
+    server_region: String,
+    cross_zone: String,
+    /// The promoted labels and annotations of both workloads.
+    #[prometheus(flatten)]
+    promoted: Vec<(String, String)>,
+}
+
+impl Labels {
+    fn new(program: &str, conn: &Connection) -> Self {
+        let backend = conn.server_backend.as_deref();
+        Self {
+            program: program.to_string(),
+            conn_id: format!(
+                "{:x}",
+                fnv_hash(&format!(
+                    "{}{}{}{}",
+                    conn.client.name,
+                    conn.client.namespace,
+                    conn.server.name,
+                    conn.server.namespace
+                ))
+            ),
+            client_id: workload_id(&conn.client),
+            client_name: conn.client.name.clone(),
+            client_namespace: conn.client.namespace.clone(),
+            client_kind: conn.client.kind.clone(),
+            server_id: workload_id(&conn.server),
+            server_name: conn.server.name.clone(),
+            server_namespace: conn.server.namespace.clone(),
+            server_kind: conn.server.kind.clone(),
+            server_port: conn.server_port.to_string(),
+            server_port_name: conn.server_port_name.clone(),
+            server_backend_name: backend.map(|b| b.name.clone()).unwrap_or_default(),
+            server_backend_namespace: backend.map(|b| b.namespace.clone()).unwrap_or_default(),
+            server_backend_kind: backend.map(|b| b.kind.clone()).unwrap_or_default(),
+            role: conn.role.to_string(),
+            protocol: protocol_name(conn.protocol),
+            client_zone: conn.client_topology.zone.clone(),
+            client_region: conn.client_topology.region.clone(),
+            server_zone: conn.server_topology.zone.clone(),
+            server_region: conn.server_topology.region.clone(),
+            cross_zone: conn.is_cross_zone().to_string(),
+            promoted: promoted_labels(conn),
+        }
+    }
+}
+
+/// The promoted labels and annotations of the workloads as metric labels, e.g.
+/// `client_label_app_kubernetes_io_team` for the `app.kubernetes.io/team` label
+/// of the client.
+fn promoted_labels(conn: &Connection) -> Vec<(String, String)> {
+    let mut promoted = Vec::new();
+    for (side, workload) in [("client", &conn.client), ("server", &conn.server)] {
+        let values = [
+            ("label", &workload.labels),
+            ("annotation", &workload.annotations),
+        ];
+        for (source, values) in values {
+            for (key, value) in values {
+                let key: String = key
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect();
+                promoted.push((format!("{}_{}_{}", side, source, key), value.clone()));
+            }
+        }
+    }
+    promoted
+}
+
+/// Aggregates the edges per pair of zones, for the cost of the traffic between
+/// zones to be attributed without the cardinality of the workloads.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ZoneLabels {
+    program: String,
+    client_zone: String,
+    client_region: String,
+    server_zone: String,
+    server_region: String,
     role: String,
+    cross_zone: String,
+}
+
+impl ZoneLabels {
+    fn new(program: &str, conn: &Connection) -> Self {
+        Self {
+            program: program.to_string(),
+            client_zone: conn.client_topology.zone.clone(),
+            client_region: conn.client_topology.region.clone(),
+            server_zone: conn.server_topology.zone.clone(),
+            server_region: conn.server_topology.region.clone(),
+            role: conn.role.to_string(),
+            cross_zone: conn.is_cross_zone().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::net::Ipv4Addr;
+
+    use prometheus_client::collector::Collector;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+
+    use super::*;
+    use crate::managers::cache::{CacheConfig, MetadataSource};
+
+    const RETENTION: Retention = Retention {
+        ttl: Duration::from_secs(DEFAULT_EDGE_TTL),
+        max_edges: DEFAULT_MAX_EDGES,
+    };
+
+    /// A service map resolving addresses from static `address namespace/name`
+    /// entries.
+    async fn service_map(name: &str, entries: &str) -> ServiceMap {
+        let path =
+            std::env::temp_dir().join(format!("agent-service-map-{}-{}", name, std::process::id()));
+        fs::write(&path, entries).unwrap();
+        let cache_mgr = CacheManager::new(CacheConfig {
+            source: MetadataSource::Local,
+            kube: Default::default(),
+            static_file: Some(path.clone()),
+        })
+        .await
+        .unwrap();
+        fs::remove_file(path).unwrap();
+
+        let service_map = ServiceMap::new(name);
+        service_map.inner.write().cache_mgr = Some(cache_mgr);
+        service_map
+    }
+
+    fn addr(ip: &str) -> [u8; 16] {
+        ip.parse::<Ipv4Addr>().unwrap().to_ipv6_mapped().octets()
+    }
+
+    fn secs(secs: u64) -> u64 {
+        Duration::from_secs(secs).as_nanos() as u64
+    }
+
+    /// The DNAT of the conntrack of the node, by connection.
+    #[derive(Debug)]
+    struct NatTargets(HashMap<ConnectionKey, NatTarget>);
+
+    impl NatLookup for NatTargets {
+        fn nat_target(&self, key: &ConnectionKey) -> Option<NatTarget> {
+            self.0.get(key).copied()
+        }
+    }
+
+    #[derive(Debug)]
+    struct EdgesCollector(Arc<ServiceMap>, HashMap<Connection, EdgeStats>);
+
+    impl Collector for EdgesCollector {
+        fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+            self.0
+                .encode_edges(&mut encoder, &self.1)
+                .map_err(|_| std::fmt::Error)
+        }
+    }
+
+    fn scrape(service_map: &Arc<ServiceMap>, edges: &HashMap<Connection, EdgeStats>) -> String {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(EdgesCollector(service_map.clone(), edges.clone())));
+        let mut buf = String::new();
+        encode(&mut buf, &registry).unwrap();
+        buf
+    }
+
+    #[derive(Debug)]
+    struct ProgramCollector(Arc<ServiceMap>);
+
+    impl Collector for ProgramCollector {
+        fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+            self.0.collect(&mut encoder).map_err(|_| std::fmt::Error)
+        }
+    }
+
+    /// The value of the only sample of `metric`.
+    fn sample(text: &str, metric: &str) -> f64 {
+        let prefix = format!("{}{{", metric);
+        let samples: Vec<&str> = text.lines().filter(|l| l.starts_with(&prefix)).collect();
+        assert_eq!(samples.len(), 1, "{}", text);
+        samples[0].rsplit(' ').next().unwrap().parse().unwrap()
+    }
+
+    fn connection(client: &str, server: &str) -> Connection {
+        let workload = |name: &str| {
+            Arc::new(Workload {
+                name: name.to_string(),
+                namespace: "default".to_string(),
+                kind: "Deployment".to_string(),
+                ..Default::default()
+            })
+        };
+        Connection {
+            client: workload(client),
+            server: workload(server),
+            role: CONNECTION_ROLE_CLIENT,
+            server_port: 80,
+            server_port_name: String::new(),
+            server_backend: None,
+            protocol: IPPROTO_TCP,
+            client_topology: Topology::default(),
+            server_topology: Topology::default(),
+        }
+    }
+
+    #[test]
+    fn test_cross_zone() {
+        let topology = |zone: &str| Topology {
+            zone: zone.to_string(),
+            region: "eu-west-1".to_string(),
+        };
+        let mut conn = connection("a", "b");
+        assert!(!conn.is_cross_zone());
+        conn.client_topology = topology("eu-west-1a");
+        assert!(!conn.is_cross_zone());
+        conn.server_topology = topology("eu-west-1a");
+        assert!(!conn.is_cross_zone());
+        conn.server_topology = topology("eu-west-1b");
+        assert!(conn.is_cross_zone());
+        assert_eq!(Labels::new("p", &conn).cross_zone, "true");
+    }
+
+    #[test]
+    fn test_backend_labels() {
+        let mut conn = connection("frontend", "checkout");
+        let labels = Labels::new("p", &conn);
+        assert_eq!(labels.server_backend_name, "");
+        conn.server_backend = Some(Arc::new(Workload {
+            name: "checkout-v2".to_string(),
+            namespace: "shop".to_string(),
+            kind: "Deployment".to_string(),
+            ..Default::default()
+        }));
+        let labels = Labels::new("p", &conn);
+        assert_eq!(labels.server_name, "checkout");
+        assert_eq!(labels.server_backend_name, "checkout-v2");
+        assert_eq!(labels.server_backend_namespace, "shop");
+    }
+
+    #[test]
+    fn test_promoted_labels() {
+        let mut conn = connection("a", "b");
+        let mut client = (*conn.client).clone();
+        client
+            .labels
+            .insert("app.kubernetes.io/team".to_string(), "payments".to_string());
+        client
+            .annotations
+            .insert("owner".to_string(), "alice".to_string());
+        conn.client = Arc::new(client);
+        let mut server = (*conn.server).clone();
+        server
+            .labels
+            .insert("version".to_string(), "v2".to_string());
+        conn.server = Arc::new(server);
+
+        assert_eq!(
+            Labels::new("p", &conn).promoted,
+            vec![
+                (
+                    "client_label_app_kubernetes_io_team".to_string(),
+                    "payments".to_string()
+                ),
+                ("client_annotation_owner".to_string(), "alice".to_string()),
+                ("server_label_version".to_string(), "v2".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_evict_edges() {
+        let now = Instant::now() + Duration::from_secs(120);
+        let retention = Retention {
+            ttl: Duration::from_secs(60),
+            max_edges: 2,
+        };
+        let expired = connection("a", "b");
+        let oldest = connection("a", "c");
+        let recent = connection("a", "d");
+        let active = connection("a", "e");
+
+        let mut edges = HashMap::new();
+        for (conn, age) in [(&expired, 90), (&oldest, 30), (&recent, 10), (&active, 120)] {
+            edges.insert(conn.clone(), EdgeStats::new(now - Duration::from_secs(age)));
+        }
+
+        let evicted = evict_edges(&mut edges, &HashSet::from([&active]), retention, now);
+        assert_eq!(evicted.len(), 2);
+        assert!(evicted.contains(&(expired, EvictionReason::Ttl)));
+        assert!(evicted.contains(&(oldest, EvictionReason::Capacity)));
+        assert!(edges.contains_key(&recent));
+        assert!(edges.contains_key(&active));
+
+        let evicted = evict_edges(&mut edges, &HashSet::new(), retention, now);
+        assert_eq!(evicted, vec![(active, EvictionReason::Ttl)]);
+        assert_eq!(edges.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_connection() {
+        let service_map =
+            service_map("lru", "10.0.0.1 default/client\n10.0.0.2 default/server\n").await;
+        let key = ConnectionKey {
+            src_addr: addr("10.0.0.1"),
+            src_port: 40000,
+            dest_addr: addr("10.0.0.2"),
+            dest_port: 8080,
+            role: CONNECTION_ROLE_CLIENT,
+            protocol: IPPROTO_TCP,
+            ..Default::default()
+        };
+        let mut stats = ConnectionStats {
+            bytes_sent: 100,
+            bytes_received: 300,
+            is_active: 1,
+            start_ns: secs(100),
+            last_seen_ns: secs(110),
+            ..Default::default()
+        };
+        let poll = |conns: Vec<(ConnectionKey, ConnectionStats)>| {
+            let mut inner = service_map.inner.write();
+            let (edges, closed) = service_map
+                .update_edges(&mut inner, conns, Duration::ZERO, RETENTION, Instant::now())
+                .unwrap();
+            assert!(closed.is_empty());
+            let edge = edges.values().next().unwrap().clone();
+            (
+                edge.bytes_sent,
+                edge.bytes_received,
+                edge.opened,
+                edge.closed,
+                edge.active,
+            )
+        };
+
+        assert_eq!(poll(vec![(key, stats)]), (100, 300, 1, 0, 1));
+        stats.bytes_sent = 150;
+        stats.bytes_received = 400;
+        assert_eq!(poll(vec![(key, stats)]), (150, 400, 1, 0, 1));
+
+        // the kernel evicted the connection from its map, its bytes are kept
+        assert_eq!(poll(vec![]), (150, 400, 1, 1, 0));
+        assert_eq!(poll(vec![]), (150, 400, 1, 1, 0));
+        assert!(service_map.inner.read().open_conns.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_zone_totals() {
+        let service_map = Arc::new(
+            service_map("zone", "10.0.0.1 default/client\n10.0.0.2 default/server\n").await,
+        );
+        let key = ConnectionKey {
+            src_addr: addr("10.0.0.1"),
+            src_port: 40000,
+            dest_addr: addr("10.0.0.2"),
+            dest_port: 8080,
+            role: CONNECTION_ROLE_CLIENT,
+            protocol: IPPROTO_TCP,
+            ..Default::default()
+        };
+        let mut stats = ConnectionStats {
+            bytes_sent: 100,
+            bytes_received: 300,
+            is_active: 1,
+            ..Default::default()
+        };
+        let poll = |conns: Vec<(ConnectionKey, ConnectionStats)>, now: Instant| {
+            let (edges, _) = service_map
+                .update_edges(
+                    &mut service_map.inner.write(),
+                    conns,
+                    Duration::ZERO,
+                    RETENTION,
+                    now,
+                )
+                .unwrap();
+            let text = scrape(&service_map, &edges);
+            (
+                sample(&text, "zone_sent_bytes_total"),
+                sample(&text, "zone_received_bytes_total"),
+            )
+        };
+
+        let now = Instant::now();
+        assert_eq!(poll(vec![(key, stats)], now), (100.0, 300.0));
+        stats.bytes_sent = 150;
+        assert_eq!(poll(vec![(key, stats)], now), (150.0, 300.0));
+        stats.is_active = 0;
+        stats.bytes_received = 400;
+        assert_eq!(poll(vec![(key, stats)], now), (150.0, 400.0));
+
+        // the edge outlived its TTL
+        let later = now + RETENTION.ttl + Duration::from_secs(1);
+        assert_eq!(poll(vec![], later), (150.0, 400.0));
+        assert!(service_map.inner.read().past_conns_map.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cluster_ip_backend() {
+        let service_map = service_map(
+            "dnat",
+            "10.0.0.1 default/frontend\n\
+             10.96.0.10 shop/checkout Service\n\
+             10.0.0.3 shop/checkout-v2\n",
+        )
+        .await;
+        let key = |src_port: u32| ConnectionKey {
+            src_addr: addr("10.0.0.1"),
+            src_port,
+            dest_addr: addr("10.96.0.10"),
+            dest_port: 80,
+            role: CONNECTION_ROLE_CLIENT,
+            protocol: IPPROTO_TCP,
+            ..Default::default()
+        };
+        let (natted, unknown) = (key(40000), key(40001));
+        let target = NatTarget {
+            dest_addr: addr("10.0.0.3"),
+            dest_port: 8080,
+        };
+        service_map.inner.write().nat_map =
+            Some(Box::new(NatTargets(HashMap::from([(natted, target)]))));
+
+        let stats = ConnectionStats {
+            is_active: 1,
+            ..Default::default()
+        };
+        let (edges, _) = service_map
+            .update_edges(
+                &mut service_map.inner.write(),
+                vec![(natted, stats), (unknown, stats)],
+                Duration::ZERO,
+                RETENTION,
+                Instant::now(),
+            )
+            .unwrap();
+        let mut labels: Vec<Labels> = edges.keys().map(|conn| Labels::new("p", conn)).collect();
+        labels.sort_by(|a, b| a.server_backend_name.cmp(&b.server_backend_name));
+        assert_eq!(labels.len(), 2);
+
+        // the connection without a NAT entry stays on the Service
+        for labels in labels.iter() {
+            assert_eq!(labels.client_name, "frontend");
+            assert_eq!(
+                (labels.server_name.as_str(), labels.server_kind.as_str()),
+                ("checkout", "Service")
+            );
+            assert_eq!(labels.server_port, "80");
+        }
+        assert_eq!(labels[0].server_backend_name, "");
+        assert_eq!(labels[1].server_backend_name, "checkout-v2");
+        assert_eq!(labels[1].server_backend_namespace, "shop");
+    }
+
+    #[tokio::test]
+    async fn test_close_on_same_edge() {
+        let service_map = service_map(
+            "close",
+            "10.0.0.1 default/frontend\n\
+             10.96.0.10 shop/checkout Service\n\
+             10.0.0.3 shop/checkout-v2\n",
+        )
+        .await;
+        let key = ConnectionKey {
+            src_addr: addr("10.0.0.1"),
+            src_port: 40000,
+            dest_addr: addr("10.96.0.10"),
+            dest_port: 80,
+            role: CONNECTION_ROLE_CLIENT,
+            protocol: IPPROTO_TCP,
+            ..Default::default()
+        };
+        let target = NatTarget {
+            dest_addr: addr("10.0.0.3"),
+            dest_port: 8080,
+        };
+        service_map.inner.write().nat_map =
+            Some(Box::new(NatTargets(HashMap::from([(key, target)]))));
+        let mut stats = ConnectionStats {
+            bytes_sent: 100,
+            bytes_received: 300,
+            is_active: 1,
+            ..Default::default()
+        };
+        let poll = |stats: ConnectionStats| {
+            let (edges, _) = service_map
+                .update_edges(
+                    &mut service_map.inner.write(),
+                    vec![(key, stats)],
+                    Duration::ZERO,
+                    RETENTION,
+                    Instant::now(),
+                )
+                .unwrap();
+            assert_eq!(edges.len(), 1);
+            let (conn, edge) = edges.into_iter().next().unwrap();
+            let backend = conn.server_backend.map(|backend| backend.name.clone());
+            (backend, edge.bytes_sent, edge.opened, edge.closed)
+        };
+        let backend = Some("checkout-v2".to_string());
+        assert_eq!(poll(stats), (backend.clone(), 100, 1, 0));
+
+        // the NAT entry was evicted before the connection closed
+        service_map.inner.write().nat_map = Some(Box::new(NatTargets(HashMap::new())));
+        stats.is_active = 0;
+        stats.bytes_sent = 150;
+        assert_eq!(poll(stats), (backend, 150, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_collect_snapshot() {
+        let service_map = Arc::new(
+            service_map("snap", "10.0.0.1 default/client\n10.0.0.2 default/server\n").await,
+        );
+        let key = ConnectionKey {
+            src_addr: addr("10.0.0.1"),
+            src_port: 40000,
+            dest_addr: addr("10.0.0.2"),
+            dest_port: 8080,
+            role: CONNECTION_ROLE_CLIENT,
+            protocol: IPPROTO_TCP,
+            ..Default::default()
+        };
+        let stats = ConnectionStats {
+            bytes_sent: 100,
+            is_active: 1,
+            ..Default::default()
+        };
+        let (edges, _) = service_map
+            .update_edges(
+                &mut service_map.inner.write(),
+                vec![(key, stats)],
+                Duration::ZERO,
+                RETENTION,
+                Instant::now(),
+            )
+            .unwrap();
+        service_map.inner.write().edges = Arc::new(edges);
+
+        // scrapes and graph requests read the last poll, without a map to read
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(service_map.clone())));
+        let scrape = || {
+            let mut buf = String::new();
+            encode(&mut buf, &registry).unwrap();
+            buf
+        };
+        let text = scrape();
+        assert_eq!(sample(&text, "connection_sent_bytes_total"), 100.0);
+        assert_eq!(scrape(), text);
+        let graph = service_map.service_graph().unwrap().unwrap();
+        assert!(graph.to_dot().contains("bytes_sent=100"));
+        assert_eq!(service_map.inner.read().open_conns.len(), 1);
+    }
+
+    #[test]
+    fn test_update_metadata() {
+        let service_map = ServiceMap::new("update");
+        for (key, value) in [
+            ("interval", "0"),
+            ("udp_idle_timeout", "-1"),
+            ("edge_ttl", "1h"),
+            ("max_edges", "0"),
+        ] {
+            service_map.set_metadata(HashMap::from([(key.to_string(), value.to_string())]));
+            let err = service_map.on_update().unwrap_err();
+            assert_eq!(err.to_string(), format!("Invalid {}: {}", key, value));
+        }
+
+        service_map.set_metadata(HashMap::from([
+            ("interval".to_string(), "5".to_string()),
+            ("max_edges".to_string(), "10".to_string()),
+        ]));
+        service_map.on_update().unwrap();
+        assert_eq!(service_map.interval(), Duration::from_secs(5));
+        assert_eq!(service_map.retention().max_edges, 10);
+    }
+
+    #[tokio::test]
+    async fn test_udp_idle_expiry() {
+        let service_map =
+            service_map("udp", "10.0.0.1 default/client\n10.0.0.2 kube-system/dns\n").await;
+        service_map.set_metadata(HashMap::from([(
+            "udp_idle_timeout".to_string(),
+            "30".to_string(),
+        )]));
+        let idle_timeout = service_map.udp_idle_timeout();
+        assert_eq!(idle_timeout, Duration::from_secs(30));
+
+        let key = ConnectionKey {
+            src_addr: addr("10.0.0.1"),
+            src_port: 40000,
+            dest_addr: addr("10.0.0.2"),
+            dest_port: 53,
+            role: CONNECTION_ROLE_CLIENT,
+            protocol: IPPROTO_UDP,
+            ..Default::default()
+        };
+        let stats = ConnectionStats {
+            bytes_sent: 100,
+            bytes_received: 300,
+            is_active: 1,
+            start_ns: secs(100),
+            last_seen_ns: secs(110),
+            ..Default::default()
+        };
+        let poll = |now_secs: u64| {
+            let idle_since = Duration::from_secs(now_secs) - idle_timeout;
+            let mut inner = service_map.inner.write();
+            service_map
+                .update_edges(
+                    &mut inner,
+                    vec![(key, stats)],
+                    idle_since,
+                    RETENTION,
+                    Instant::now(),
+                )
+                .unwrap()
+        };
+
+        // a datagram was seen within the idle timeout
+        let (edges, closed) = poll(120);
+        assert!(closed.is_empty());
+        let (conn, edge) = edges.iter().next().unwrap();
+        assert_eq!(
+            (conn.client.name.as_str(), conn.server.name.as_str()),
+            ("client", "dns")
+        );
+        assert_eq!((conn.protocol, conn.server_port), (IPPROTO_UDP, 53));
+        assert_eq!((edge.opened, edge.closed, edge.active), (1, 0, 1));
+        assert_eq!((edge.bytes_sent, edge.bytes_received), (100, 300));
+
+        // the flow went idle, it is closed and removed from the map
+        let (edges, closed) = poll(141);
+        assert_eq!(closed, vec![key]);
+        let edge = edges.values().next().unwrap();
+        assert_eq!((edge.opened, edge.closed, edge.active), (1, 1, 0));
+        assert_eq!((edge.bytes_sent, edge.bytes_received), (100, 300));
+        assert!(service_map.inner.read().open_conns.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_collect_tcp_stats() {
+        let service_map = Arc::new(
+            service_map("tcp", "10.0.0.1 default/client\n10.0.0.2 default/server\n").await,
+        );
+        let key = ConnectionKey {
+            src_addr: addr("10.0.0.1"),
+            src_port: 40000,
+            dest_addr: addr("10.0.0.2"),
+            dest_port: 8080,
+            role: CONNECTION_ROLE_CLIENT,
+            protocol: IPPROTO_TCP,
+            ..Default::default()
+        };
+        let mut stats = ConnectionStats {
+            bytes_sent: 100,
+            bytes_received: 300,
+            is_active: 1,
+            start_ns: secs(100),
+            last_seen_ns: secs(110),
+            srtt_us: 2500,
+            retransmits: 3,
+            resets: 0,
+            ..Default::default()
+        };
+        let poll = |stats: ConnectionStats| {
+            let mut inner = service_map.inner.write();
+            let (edges, _) = service_map
+                .update_edges(
+                    &mut inner,
+                    vec![(key, stats)],
+                    Duration::ZERO,
+                    RETENTION,
+                    Instant::now(),
+                )
+                .unwrap();
+            edges
+        };
+
+        let text = scrape(&service_map, &poll(stats));
+        assert_eq!(sample(&text, "connection_rtt_seconds"), 0.0025);
+        assert_eq!(sample(&text, "connection_retransmits_total"), 3.0);
+        assert_eq!(sample(&text, "connection_resets_total"), 0.0);
+        assert!(!text.contains("connection_lifetime_seconds_count{"));
+
+        // the connection was reset 11.5 seconds after it started
+        stats.is_active = 0;
+        stats.last_seen_ns = secs(111) + secs(1) / 2;
+        stats.retransmits = 4;
+        stats.resets = 1;
+        let text = scrape(&service_map, &poll(stats));
+        assert_eq!(sample(&text, "connection_lifetime_seconds_count"), 1.0);
+        assert_eq!(sample(&text, "connection_lifetime_seconds_sum"), 11.5);
+        assert_eq!(sample(&text, "connection_retransmits_total"), 4.0);
+        assert_eq!(sample(&text, "connection_resets_total"), 1.0);
+        // only open connections have a round trip time
+        assert!(!text.contains("connection_rtt_seconds{"));
+    }
 }
@@ -9,7 +9,6 @@ use async_trait::async_trait;
 use aya::maps::{AsyncPerfEventArray, Map, MapData};
 use aya::util::online_cpus;
 use bytes::BytesMut;
-use lazy_static::lazy_static;
 use log::{debug, info};
 use parking_lot::RwLock;
 use prometheus_client::encoding::DescriptorEncoder;
@@ -31,16 +30,12 @@ use super::tracker_manager::ConnTrackerManager;
 pub(crate) struct Inner {
     data: ProgramData,
     cache_mgr: Option<CacheManager>,
-    conn_mgr: Option<ConnTrackerManager>,
+    conn_mgr: Option<Arc<ConnTrackerManager>>,
     ctrl_events: Option<AsyncPerfEventArray<MapData>>,
     data_events: Option<AsyncPerfEventArray<MapData>>,
     conn_events: Option<AsyncPerfEventArray<MapData>>,
 }
 
-lazy_static! {
-    static ref CONN_TRACKER_MANAGER: ConnTrackerManager = ConnTrackerManager::new();
-}
-
 impl Inner {
     fn new(name: &str) -> Self {
         Self {
@@ -66,9 +61,11 @@ pub struct SocketTracer {
 }
 
 impl SocketTracer {
-    pub(crate) fn new() -> Self {
+    pub(crate) const KIND: &'static str = "socket_tracer";
+
+    pub(crate) fn new(name: &str) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(Inner::new("socket_tracer"))),
+            inner: Arc::new(RwLock::new(Inner::new(name))),
         }
     }
 
@@ -89,7 +86,11 @@ impl SocketTracer {
         Ok(perf_event)
     }
 
-    fn handle_ctrl_event(event: &SocketControlEvent) {
+    fn handle_ctrl_event(
+        conn_mgr: &ConnTrackerManager,
+        cache_mgr: &CacheManager,
+        event: &SocketControlEvent,
+    ) {
         let local_addr = convert_src_to_socket_addr(&event);
         // if local_addr.is_none() {
         //     return;
@@ -98,9 +99,11 @@ impl SocketTracer {
         // if remote_addr.is_none() {
         //     return;
         // }
+        let workload = cache_mgr.resolve_pid(event.id.uid.tgid as u32);
         info!(
-            "SocketControlEvent: tgid {:?}, event type {:?}, sa_family: {:?}, local addr {:?}, remote addr {:?}, source func {:?}, read_bytes {:?}, write_bytes {:?}",
+            "SocketControlEvent: tgid {:?}, workload {:?}, event type {:?}, sa_family: {:?}, local addr {:?}, remote addr {:?}, source func {:?}, read_bytes {:?}, write_bytes {:?}",
             event.id.uid.tgid,
+            workload,
             event.event_type,
             event.sa_family,
             local_addr,
@@ -109,7 +112,7 @@ impl SocketTracer {
             event.read_bytes,
             event.write_bytes
         );
-        let tracker = CONN_TRACKER_MANAGER.get_or_create_conn_tracker(event.id);
+        let tracker = conn_mgr.get_or_create_conn_tracker(event.id);
         let _ = tracker.add_event(event);
     }
 
@@ -181,9 +184,17 @@ impl SocketTracer {
     ) -> anyhow::Result<Vec<JoinHandle<()>>> {
         let mut join_handles = Vec::new();
 
-        let ctrl_events = {
+        let (ctrl_events, conn_mgr, cache_mgr) = {
             let mut inner = self.inner.write();
-            inner.ctrl_events.take()
+            let conn_mgr = inner
+                .conn_mgr
+                .clone()
+                .ok_or(Error::msg("No connection tracker manager"))?;
+            let cache_mgr = inner
+                .cache_mgr
+                .clone()
+                .ok_or(Error::msg("No cache manager"))?;
+            (inner.ctrl_events.take(), conn_mgr, cache_mgr)
         };
 
         if let Some(mut ctrl_events) = ctrl_events {
@@ -191,8 +202,8 @@ impl SocketTracer {
                 .process_event(
                     &mut ctrl_events,
                     shutdown_rx.resubscribe(),
-                    Arc::new(|e: &SocketControlEvent| {
-                        SocketTracer::handle_ctrl_event(e);
+                    Arc::new(move |e: &SocketControlEvent| {
+                        SocketTracer::handle_ctrl_event(&conn_mgr, &cache_mgr, e);
                     }),
                 )
                 .await?;
@@ -249,6 +260,7 @@ impl Program for SocketTracer {
         inner.data.metadata = metadata.clone();
         inner.data.ebpf_maps = maps.clone();
         inner.cache_mgr = Some(cache_manager);
+        inner.conn_mgr = Some(Arc::new(ConnTrackerManager::new()));
 
         if let Some(prog_id) = maps.get("sk_ctrl_events") {
             inner.ctrl_events =
@@ -330,6 +342,10 @@ impl Program for SocketTracer {
         inner.data.program_type.clone()
     }
 
+    fn get_kind(&self) -> String {
+        Self::KIND.to_string()
+    }
+
     fn get_metadata(&self) -> HashMap<String, String> {
         let inner = self.inner.read();
         inner.data.metadata.clone()
@@ -351,6 +367,7 @@ impl Program for SocketTracer {
             bytecode: None,
             ebpf_maps,
             metadata: self.get_metadata(),
+            ..Default::default()
         })
     }
 }
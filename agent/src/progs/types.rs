@@ -1,14 +1,16 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::str::FromStr;
 
 use async_trait::async_trait;
 use prometheus_client::encoding::DescriptorEncoder;
 use tokio::sync::broadcast::Receiver;
 
 use agent_api::{ProgramState, ProgramType};
-use agent_api::v1::ProgramInfo;
+use agent_api::v1::{BytecodeLocation, ProgramInfo};
 
 use crate::managers::cache::CacheManager;
+use crate::progs::graph::ServiceGraph;
 
 #[derive(Debug, Clone)]
 pub enum ShutdownSignal {
@@ -32,9 +34,38 @@ pub trait Program: Debug + Send + Sync + 'static {
     fn get_state(&self) -> ProgramState;
     fn set_state(&self, state: ProgramState);
     fn get_type(&self) -> ProgramType;
+    /// The builtin program this program is an instance of, empty for programs
+    /// which are not builtin.
+    fn get_kind(&self) -> String {
+        String::new()
+    }
     fn get_metadata(&self) -> HashMap<String, String>;
     fn set_metadata(&self, metadata: HashMap<String, String>);
     fn get_program_info(&self) -> Result<ProgramInfo, anyhow::Error>;
+
+    /// Called after `set_metadata` on a loaded program so it can apply the new
+    /// configuration without being restarted.
+    fn on_update(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+
+    /// The connections between workloads observed by the program, `None` for
+    /// programs which do not observe any.
+    fn service_graph(&self) -> Result<Option<ServiceGraph>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Replaces the bytecode of a loaded program while it keeps running.
+    async fn update_bytecode(
+        &self,
+        _bytecode: Vec<u8>,
+        _location: BytecodeLocation,
+    ) -> Result<(), anyhow::Error> {
+        Err(anyhow::anyhow!(
+            "Program {} does not support bytecode updates",
+            self.get_name()
+        ))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -57,3 +88,21 @@ impl ProgramData {
         }
     }
 }
+
+/// Parses the value of a numeric metadata key, which must be positive when it
+/// is set.
+pub(crate) fn positive_metadata<T>(
+    metadata: &HashMap<String, String>,
+    key: &str,
+) -> Result<Option<T>, anyhow::Error>
+where
+    T: FromStr + PartialOrd + Default,
+{
+    let Some(value) = metadata.get(key) else {
+        return Ok(None);
+    };
+    match value.parse::<T>() {
+        Ok(parsed) if parsed > T::default() => Ok(Some(parsed)),
+        _ => Err(anyhow::anyhow!("Invalid {}: {}", key, value)),
+    }
+}
@@ -0,0 +1,385 @@
+//! Host functions exposed to wasm programs under the `conductor` module.
+//!
+//! Strings and buffers are passed as `(ptr, len)` pairs into the guest's
+//! exported `memory`. Functions that fill a guest buffer return the number of
+//! bytes the full result needs, so a guest can retry with a larger buffer when
+//! the returned length exceeds the one it passed. Negative return values are
+//! one of the `ERR_*` codes below.
+//!
+//! | function         | signature                                                         |
+//! |------------------|-------------------------------------------------------------------|
+//! | `log`            | `(level, msg_ptr, msg_len)`                                       |
+//! | `metadata_get`   | `(key_ptr, key_len, buf_ptr, buf_len) -> len`                     |
+//! | `map_lookup`     | `(map_ptr, map_len, key_ptr, key_len, value_ptr, value_len) -> 0` |
+//! | `map_next_key`   | `(map_ptr, map_len, key_ptr, key_len, next_ptr, next_len) -> 0`   |
+//! | `resolve_ip`     | `(ip_ptr, ip_len, buf_ptr, buf_len) -> len`                       |
+//! | `resolve_pid`    | `(pid, buf_ptr, buf_len) -> len`                                  |
+//! | `emit_metric`    | `(kind, name_ptr, name_len, help_ptr, help_len, labels_ptr, labels_len, value)` |
+//!
+//! The key and value lengths passed to `map_lookup` and `map_next_key` must be
+//! the sizes of the map, other lengths are an `ERR_INVALID_ARGUMENT`.
+//! `map_next_key` takes a zero `key_len` to start an iteration. `resolve_ip`
+//! takes an IPv4 or IPv6 address and writes the workload as a JSON object,
+//! `resolve_pid` writes the workload of a process of the host the same way, and
+//! `emit_metric` takes its labels as a JSON object of string values.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Error;
+use log::{debug, log, Level};
+use parking_lot::RwLock;
+use wasmtime::{Caller, Linker, Memory};
+
+use crate::common::constants::directories::RTDIR_FS_MAPS;
+use crate::managers::cache::CacheManager;
+use crate::progs::wasm::maps::PinnedMap;
+
+pub(crate) const HOST_MODULE: &str = "conductor";
+
+pub(crate) const ERR_NOT_FOUND: i32 = -1;
+pub(crate) const ERR_INVALID_ARGUMENT: i32 = -2;
+pub(crate) const ERR_INTERNAL: i32 = -3;
+
+pub(crate) const METRIC_KIND_GAUGE: i32 = 0;
+pub(crate) const METRIC_KIND_COUNTER: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MetricKind {
+    Gauge,
+    Counter,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MetricSample {
+    pub name: String,
+    pub help: String,
+    pub kind: MetricKind,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+}
+
+/// Per-instance state reachable from host functions.
+#[derive(Debug)]
+pub(crate) struct HostState {
+    pub name: String,
+    /// Shared with the program, for metadata updates to reach a running guest.
+    pub metadata: Arc<RwLock<HashMap<String, String>>>,
+    pub ebpf_maps: HashMap<String, u32>,
+    pub cache_mgr: CacheManager,
+    pub pinned_maps: HashMap<String, PinnedMap>,
+    pub metrics: Vec<MetricSample>,
+}
+
+impl HostState {
+    pub(crate) fn new(
+        name: String,
+        metadata: Arc<RwLock<HashMap<String, String>>>,
+        ebpf_maps: HashMap<String, u32>,
+        cache_mgr: CacheManager,
+    ) -> Self {
+        Self {
+            name,
+            metadata,
+            ebpf_maps,
+            cache_mgr,
+            pinned_maps: HashMap::new(),
+            metrics: Vec::new(),
+        }
+    }
+
+    fn pinned_map(&mut self, map_name: &str) -> Result<&PinnedMap, Error> {
+        if !self.pinned_maps.contains_key(map_name) {
+            let prog_id = self.ebpf_maps.get(map_name).ok_or(anyhow::anyhow!(
+                "No map named {} in the provided maps",
+                map_name
+            ))?;
+            let map_pin_path = Path::new(RTDIR_FS_MAPS).join(format!("{}/{}", prog_id, map_name));
+            let map = PinnedMap::from_pin(&map_pin_path)?;
+            self.pinned_maps.insert(map_name.to_string(), map);
+        }
+        Ok(&self.pinned_maps[map_name])
+    }
+}
+
+fn memory(caller: &mut Caller<'_, HostState>) -> Option<Memory> {
+    caller.get_export("memory").and_then(|e| e.into_memory())
+}
+
+fn read_bytes(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> Option<Vec<u8>> {
+    let memory = memory(caller)?;
+    let start = usize::try_from(ptr).ok()?;
+    let end = start.checked_add(usize::try_from(len).ok()?)?;
+    memory.data(&caller).get(start..end).map(|b| b.to_vec())
+}
+
+fn read_string(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> Option<String> {
+    read_bytes(caller, ptr, len).and_then(|b| String::from_utf8(b).ok())
+}
+
+fn write_bytes(caller: &mut Caller<'_, HostState>, ptr: i32, bytes: &[u8]) -> bool {
+    let Some(memory) = memory(caller) else {
+        return false;
+    };
+    let Ok(start) = usize::try_from(ptr) else {
+        return false;
+    };
+    let Some(end) = start.checked_add(bytes.len()) else {
+        return false;
+    };
+    match memory.data_mut(caller).get_mut(start..end) {
+        Some(dest) => {
+            dest.copy_from_slice(bytes);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Writes as much of `bytes` as fits into the guest buffer and returns the
+/// full length, following the convention described in the module docs.
+fn write_result(
+    caller: &mut Caller<'_, HostState>,
+    buf_ptr: i32,
+    buf_len: i32,
+    bytes: &[u8],
+) -> i32 {
+    let Ok(len) = i32::try_from(bytes.len()) else {
+        return ERR_INTERNAL;
+    };
+    if len <= buf_len && !write_bytes(caller, buf_ptr, bytes) {
+        return ERR_INVALID_ARGUMENT;
+    }
+    len
+}
+
+fn log_message(mut caller: Caller<'_, HostState>, level: i32, msg_ptr: i32, msg_len: i32) {
+    let level = match level {
+        0 => Level::Error,
+        1 => Level::Warn,
+        2 => Level::Info,
+        3 => Level::Debug,
+        _ => Level::Trace,
+    };
+    if let Some(msg) = read_string(&mut caller, msg_ptr, msg_len) {
+        log!(level, "[{}] {}", caller.data().name, msg);
+    }
+}
+
+fn metadata_get(
+    mut caller: Caller<'_, HostState>,
+    key_ptr: i32,
+    key_len: i32,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> i32 {
+    let Some(key) = read_string(&mut caller, key_ptr, key_len) else {
+        return ERR_INVALID_ARGUMENT;
+    };
+    let Some(value) = caller.data().metadata.read().get(&key).cloned() else {
+        return ERR_NOT_FOUND;
+    };
+    write_result(&mut caller, buf_ptr, buf_len, value.as_bytes())
+}
+
+/// The key and value sizes of a map, for the lengths the guest passes to be
+/// checked before any buffer is allocated for them.
+fn map_sizes(caller: &mut Caller<'_, HostState>, map_name: &str) -> Result<(usize, usize), i32> {
+    match caller.data_mut().pinned_map(map_name) {
+        Ok(map) => Ok((map.key_size(), map.value_size())),
+        Err(e) => {
+            debug!("Failed to open map {}: {:?}", map_name, e);
+            Err(ERR_INTERNAL)
+        }
+    }
+}
+
+fn map_lookup(
+    mut caller: Caller<'_, HostState>,
+    map_ptr: i32,
+    map_len: i32,
+    key_ptr: i32,
+    key_len: i32,
+    value_ptr: i32,
+    value_len: i32,
+) -> i32 {
+    let Some(map_name) = read_string(&mut caller, map_ptr, map_len) else {
+        return ERR_INVALID_ARGUMENT;
+    };
+    let (key_size, value_size) = match map_sizes(&mut caller, &map_name) {
+        Ok(sizes) => sizes,
+        Err(code) => return code,
+    };
+    if usize::try_from(key_len) != Ok(key_size) || usize::try_from(value_len) != Ok(value_size) {
+        return ERR_INVALID_ARGUMENT;
+    }
+    let Some(key) = read_bytes(&mut caller, key_ptr, key_len) else {
+        return ERR_INVALID_ARGUMENT;
+    };
+
+    let mut value = vec![0u8; value_size];
+    let found = caller
+        .data_mut()
+        .pinned_map(&map_name)
+        .and_then(|map| map.lookup(&key, &mut value));
+    match found {
+        Ok(true) => {
+            if write_bytes(&mut caller, value_ptr, &value) {
+                0
+            } else {
+                ERR_INVALID_ARGUMENT
+            }
+        }
+        Ok(false) => ERR_NOT_FOUND,
+        Err(e) => {
+            debug!("Map lookup in {} failed: {:?}", map_name, e);
+            ERR_INTERNAL
+        }
+    }
+}
+
+fn map_next_key(
+    mut caller: Caller<'_, HostState>,
+    map_ptr: i32,
+    map_len: i32,
+    key_ptr: i32,
+    key_len: i32,
+    next_ptr: i32,
+    next_len: i32,
+) -> i32 {
+    let Some(map_name) = read_string(&mut caller, map_ptr, map_len) else {
+        return ERR_INVALID_ARGUMENT;
+    };
+    let key_size = match map_sizes(&mut caller, &map_name) {
+        Ok((key_size, _)) => key_size,
+        Err(code) => return code,
+    };
+    if usize::try_from(next_len) != Ok(key_size) {
+        return ERR_INVALID_ARGUMENT;
+    }
+    let key = if key_len == 0 {
+        None
+    } else if usize::try_from(key_len) != Ok(key_size) {
+        return ERR_INVALID_ARGUMENT;
+    } else {
+        match read_bytes(&mut caller, key_ptr, key_len) {
+            Some(key) => Some(key),
+            None => return ERR_INVALID_ARGUMENT,
+        }
+    };
+
+    let mut next_key = vec![0u8; key_size];
+    let found = caller
+        .data_mut()
+        .pinned_map(&map_name)
+        .and_then(|map| map.next_key(key.as_deref(), &mut next_key));
+    match found {
+        Ok(true) => {
+            if write_bytes(&mut caller, next_ptr, &next_key) {
+                0
+            } else {
+                ERR_INVALID_ARGUMENT
+            }
+        }
+        Ok(false) => ERR_NOT_FOUND,
+        Err(e) => {
+            debug!("Map iteration in {} failed: {:?}", map_name, e);
+            ERR_INTERNAL
+        }
+    }
+}
+
+fn resolve_ip(
+    mut caller: Caller<'_, HostState>,
+    ip_ptr: i32,
+    ip_len: i32,
+    buf_ptr: i32,
+    buf_len: i32,
+) -> i32 {
+    let Some(ip) = read_string(&mut caller, ip_ptr, ip_len).and_then(|ip| ip.parse().ok()) else {
+        return ERR_INVALID_ARGUMENT;
+    };
+    let workload = caller.data().cache_mgr.resolve_ip(ip);
+    let Some(workload) = workload else {
+        return ERR_NOT_FOUND;
+    };
+    match serde_json::to_vec(workload.as_ref()) {
+        Ok(bytes) => write_result(&mut caller, buf_ptr, buf_len, &bytes),
+        Err(_) => ERR_INTERNAL,
+    }
+}
+
+fn resolve_pid(mut caller: Caller<'_, HostState>, pid: i32, buf_ptr: i32, buf_len: i32) -> i32 {
+    let Ok(pid) = u32::try_from(pid) else {
+        return ERR_INVALID_ARGUMENT;
+    };
+    let workload = caller.data().cache_mgr.resolve_pid(pid);
+    let Some(workload) = workload else {
+        return ERR_NOT_FOUND;
+    };
+    match serde_json::to_vec(workload.as_ref()) {
+        Ok(bytes) => write_result(&mut caller, buf_ptr, buf_len, &bytes),
+        Err(_) => ERR_INTERNAL,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn emit_metric(
+    mut caller: Caller<'_, HostState>,
+    kind: i32,
+    name_ptr: i32,
+    name_len: i32,
+    help_ptr: i32,
+    help_len: i32,
+    labels_ptr: i32,
+    labels_len: i32,
+    value: f64,
+) -> i32 {
+    let kind = match kind {
+        METRIC_KIND_GAUGE => MetricKind::Gauge,
+        METRIC_KIND_COUNTER => MetricKind::Counter,
+        _ => return ERR_INVALID_ARGUMENT,
+    };
+    let Some(name) = read_string(&mut caller, name_ptr, name_len) else {
+        return ERR_INVALID_ARGUMENT;
+    };
+    let Some(help) = read_string(&mut caller, help_ptr, help_len) else {
+        return ERR_INVALID_ARGUMENT;
+    };
+    let labels = if labels_len == 0 {
+        Vec::new()
+    } else {
+        let Some(raw) = read_bytes(&mut caller, labels_ptr, labels_len) else {
+            return ERR_INVALID_ARGUMENT;
+        };
+        match serde_json::from_slice::<HashMap<String, String>>(&raw) {
+            Ok(labels) => {
+                let mut labels: Vec<(String, String)> = labels.into_iter().collect();
+                labels.sort();
+                labels
+            }
+            Err(_) => return ERR_INVALID_ARGUMENT,
+        }
+    };
+
+    caller.data_mut().metrics.push(MetricSample {
+        name,
+        help,
+        kind,
+        labels,
+        value,
+    });
+    0
+}
+
+pub(crate) fn add_to_linker(linker: &mut Linker<HostState>) -> Result<(), Error> {
+    linker.func_wrap(HOST_MODULE, "log", log_message)?;
+    linker.func_wrap(HOST_MODULE, "metadata_get", metadata_get)?;
+    linker.func_wrap(HOST_MODULE, "map_lookup", map_lookup)?;
+    linker.func_wrap(HOST_MODULE, "map_next_key", map_next_key)?;
+    linker.func_wrap(HOST_MODULE, "resolve_ip", resolve_ip)?;
+    linker.func_wrap(HOST_MODULE, "resolve_pid", resolve_pid)?;
+    linker.func_wrap(HOST_MODULE, "emit_metric", emit_metric)?;
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::mem::size_of;
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::Path;
+
+use anyhow::Error;
+use aya::maps::MapData;
+use nix::errno::Errno;
+use nix::libc;
+
+const BPF_MAP_LOOKUP_ELEM: libc::c_long = 1;
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+
+const BPF_MAP_TYPE_PERCPU_HASH: u32 = 5;
+const BPF_MAP_TYPE_PERCPU_ARRAY: u32 = 6;
+const BPF_MAP_TYPE_LRU_PERCPU_HASH: u32 = 10;
+
+#[repr(C)]
+#[derive(Default)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// Runs one of the element commands on a map opened by aya.
+///
+/// aya's typed maps need the key and value types at compile time, which wasm
+/// programs only provide as raw bytes, so these two commands are issued here.
+fn map_elem(cmd: libc::c_long, map: &MapData, key: u64, value: u64) -> Result<(), Errno> {
+    let mut attr = MapElemAttr {
+        map_fd: map.fd().as_fd().as_raw_fd() as u32,
+        key,
+        value,
+        ..Default::default()
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            &mut attr as *mut MapElemAttr as *mut libc::c_void,
+            size_of::<MapElemAttr>(),
+        )
+    };
+    Errno::result(ret).map(drop)
+}
+
+/// A pinned eBPF map opened on behalf of a wasm program.
+///
+/// Wasm programs only know the key and value layout of a map at runtime, so
+/// lookups work on raw bytes and are checked against the sizes reported by the
+/// kernel before anything is copied into guest memory.
+#[derive(Debug)]
+pub(crate) struct PinnedMap {
+    map: MapData,
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+}
+
+impl PinnedMap {
+    pub(crate) fn from_pin(path: &Path) -> Result<Self, Error> {
+        let map = MapData::from_pin(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open pinned map {:?}: {}", path, e))?;
+        let info = map
+            .info()
+            .map_err(|e| anyhow::anyhow!("Failed to get info of map {:?}: {}", path, e))?;
+
+        Ok(Self {
+            map_type: info.map_type(),
+            key_size: info.key_size(),
+            value_size: info.value_size(),
+            map,
+        })
+    }
+
+    pub(crate) fn key_size(&self) -> usize {
+        self.key_size as usize
+    }
+
+    pub(crate) fn value_size(&self) -> usize {
+        self.value_size as usize
+    }
+
+    fn check_type(&self) -> Result<(), Error> {
+        if matches!(
+            self.map_type,
+            BPF_MAP_TYPE_PERCPU_HASH | BPF_MAP_TYPE_PERCPU_ARRAY | BPF_MAP_TYPE_LRU_PERCPU_HASH
+        ) {
+            return Err(Error::msg("Per-CPU maps are not supported"));
+        }
+        Ok(())
+    }
+
+    fn check_len(what: &str, expected: u32, got: usize) -> Result<(), Error> {
+        if got != expected as usize {
+            return Err(anyhow::anyhow!(
+                "{} size mismatch: expected {}, got {}",
+                what,
+                expected,
+                got
+            ));
+        }
+        Ok(())
+    }
+
+    /// Copies the value stored under `key` into `value`. Returns `false` if the
+    /// key does not exist.
+    pub(crate) fn lookup(&self, key: &[u8], value: &mut [u8]) -> Result<bool, Error> {
+        self.check_type()?;
+        Self::check_len("Key", self.key_size, key.len())?;
+        Self::check_len("Value", self.value_size, value.len())?;
+        match map_elem(
+            BPF_MAP_LOOKUP_ELEM,
+            &self.map,
+            key.as_ptr() as u64,
+            value.as_mut_ptr() as u64,
+        ) {
+            Ok(()) => Ok(true),
+            Err(Errno::ENOENT) => Ok(false),
+            Err(e) => Err(anyhow::anyhow!("Failed to lookup map element: {}", e)),
+        }
+    }
+
+    /// Writes the key following `key` into `next_key`, or the first key of the
+    /// map if `key` is `None`. Returns `false` once the iteration is complete.
+    pub(crate) fn next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> Result<bool, Error> {
+        Self::check_len("Key", self.key_size, next_key.len())?;
+        let key_ptr = match key {
+            Some(key) => {
+                Self::check_len("Key", self.key_size, key.len())?;
+                key.as_ptr() as u64
+            }
+            None => 0,
+        };
+        match map_elem(
+            BPF_MAP_GET_NEXT_KEY,
+            &self.map,
+            key_ptr,
+            next_key.as_mut_ptr() as u64,
+        ) {
+            Ok(()) => Ok(true),
+            Err(Errno::ENOENT) => Ok(false),
+            Err(e) => Err(anyhow::anyhow!("Failed to get next map key: {}", e)),
+        }
+    }
+}
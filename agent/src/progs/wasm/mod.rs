@@ -0,0 +1,3 @@
+pub(crate) mod host;
+pub(crate) mod maps;
+pub(crate) mod program;
@@ -0,0 +1,483 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fmt::Debug;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Error;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use parking_lot::{Mutex, RwLock};
+use prometheus_client::encoding::{DescriptorEncoder, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use tokio::sync::{broadcast, Notify};
+use tokio::time;
+use wasmtime::{Config, Engine, Instance, Linker, Module, Store};
+
+use agent_api::{ProgramState, ProgramType};
+use agent_api::v1::{BytecodeLocation, ProgramInfo};
+
+use crate::common::constants::DEFAULT_INTERVAL;
+use crate::managers::cache::CacheManager;
+use crate::progs::types::{positive_metadata, Program, ProgramData, ShutdownSignal};
+use crate::progs::wasm::host::{add_to_linker, HostState, MetricKind, MetricSample};
+
+lazy_static! {
+    static ref ENGINE: Engine =
+        Engine::new(Config::new().consume_fuel(true)).expect("Failed to create the wasm engine");
+}
+
+/// Fuel given to a guest for each call into it, so that a guest which does
+/// not return traps instead of holding on to a thread forever.
+const FUEL_PER_CALL: u64 = 1_000_000_000;
+
+/// Exports a wasm program may provide. All of them are optional, take no
+/// arguments and return zero on success.
+const EXPORT_INIT: &str = "init";
+const EXPORT_POLL: &str = "poll";
+const EXPORT_COLLECT: &str = "collect";
+
+#[derive(Debug)]
+struct WasmInstance {
+    store: Store<HostState>,
+    instance: Instance,
+}
+
+impl WasmInstance {
+    fn new(module: &Module, state: HostState) -> Result<Self, Error> {
+        let mut store = Store::new(&ENGINE, state);
+        let mut linker = Linker::new(&ENGINE);
+        add_to_linker(&mut linker)?;
+        let instance = linker.instantiate(&mut store, module)?;
+
+        let mut instance = WasmInstance { store, instance };
+        instance.call(EXPORT_INIT)?;
+        Ok(instance)
+    }
+
+    fn call(&mut self, name: &str) -> Result<(), Error> {
+        let func = match self.instance.get_func(&mut self.store, name) {
+            Some(func) => func,
+            None => return Ok(()),
+        };
+        let func = func.typed::<(), i32>(&self.store)?;
+        self.store.set_fuel(FUEL_PER_CALL)?;
+        let ret = func.call(&mut self.store, ())?;
+        if ret != 0 {
+            return Err(anyhow::anyhow!("{} returned {}", name, ret));
+        }
+        Ok(())
+    }
+}
+
+struct Inner {
+    data: ProgramData,
+    bytecode: BytecodeLocation,
+    module: Module,
+    cache_mgr: Option<CacheManager>,
+    /// The metrics the guest emitted at the last poll.
+    samples: Vec<MetricSample>,
+}
+
+impl Debug for Inner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Inner")
+            .field("data", &self.data)
+            .field("bytecode", &self.bytecode)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub struct WasmProgram {
+    inner: Arc<RwLock<Inner>>,
+    /// The guest, only locked from blocking tasks, and never while `inner` is
+    /// held, as calls into it last as long as its fuel does.
+    instance: Arc<Mutex<Option<WasmInstance>>>,
+    metadata: Arc<RwLock<HashMap<String, String>>>,
+    updated: Arc<Notify>,
+}
+
+impl WasmProgram {
+    pub(crate) fn new(
+        name: &str,
+        bytecode: &[u8],
+        location: BytecodeLocation,
+    ) -> Result<Self, Error> {
+        let module = Module::new(&ENGINE, bytecode)?;
+        let mut data = ProgramData::new(name);
+        data.program_type = ProgramType::Wasm;
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(Inner {
+                data,
+                bytecode: location,
+                module,
+                cache_mgr: None,
+                samples: Vec::new(),
+            })),
+            instance: Arc::new(Mutex::new(None)),
+            metadata: Arc::new(RwLock::new(HashMap::new())),
+            updated: Arc::new(Notify::new()),
+        })
+    }
+
+    fn interval(&self) -> Duration {
+        let interval = positive_metadata(&self.get_metadata(), "interval")
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_INTERVAL);
+        Duration::from_secs(interval)
+    }
+
+    async fn create_instance(
+        &self,
+        module: Module,
+        cache_mgr: CacheManager,
+    ) -> Result<WasmInstance, Error> {
+        let state = {
+            let inner = self.inner.read();
+            HostState::new(
+                inner.data.name.clone(),
+                self.metadata.clone(),
+                inner.data.ebpf_maps.clone(),
+                cache_mgr,
+            )
+        };
+        tokio::task::spawn_blocking(move || WasmInstance::new(&module, state)).await?
+    }
+
+    async fn set_instance(&self, instance: Option<WasmInstance>) -> Result<(), Error> {
+        let slot = self.instance.clone();
+        // the previous instance is dropped on the blocking task as well
+        tokio::task::spawn_blocking(move || {
+            *slot.lock() = instance;
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Polls the guest and gathers the metrics it emits, which are served
+    /// until the next poll.
+    async fn poll(&self) -> Result<(), Error> {
+        let slot = self.instance.clone();
+        let samples = tokio::task::spawn_blocking(move || {
+            let mut instance = slot.lock();
+            let instance = instance
+                .as_mut()
+                .ok_or(Error::msg("Program is not initialized"))?;
+            instance.call(EXPORT_POLL)?;
+            instance.store.data_mut().metrics.clear();
+            instance.call(EXPORT_COLLECT)?;
+            Ok::<_, Error>(std::mem::take(&mut instance.store.data_mut().metrics))
+        })
+        .await??;
+
+        self.inner.write().samples = samples;
+        Ok(())
+    }
+
+    fn encode_samples(
+        samples: Vec<MetricSample>,
+        encoder: &mut DescriptorEncoder,
+    ) -> Result<(), Error> {
+        let mut families: BTreeMap<String, (String, MetricKind, Vec<MetricSample>)> =
+            BTreeMap::new();
+        for sample in samples {
+            let family = families
+                .entry(sample.name.clone())
+                .or_insert_with(|| (sample.help.clone(), sample.kind, Vec::new()));
+            if family.1 != sample.kind {
+                warn!(
+                    "Metric {} emitted as both {:?} and {:?}, skipping sample",
+                    sample.name, family.1, sample.kind
+                );
+                continue;
+            }
+            family.2.push(sample);
+        }
+
+        for (name, (help, kind, samples)) in families {
+            match kind {
+                MetricKind::Gauge => {
+                    let metric = Family::<Vec<(String, String)>, Gauge<f64, AtomicU64>>::default();
+                    for sample in samples {
+                        metric.get_or_create(&sample.labels).set(sample.value);
+                    }
+                    let metric_encoder =
+                        encoder.encode_descriptor(&name, &help, None, metric.metric_type())?;
+                    metric.encode(metric_encoder)?;
+                }
+                MetricKind::Counter => {
+                    let metric =
+                        Family::<Vec<(String, String)>, Counter<f64, AtomicU64>>::default();
+                    for sample in samples {
+                        metric.get_or_create(&sample.labels).inc_by(sample.value);
+                    }
+                    let metric_encoder =
+                        encoder.encode_descriptor(&name, &help, None, metric.metric_type())?;
+                    metric.encode(metric_encoder)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Program for WasmProgram {
+    async fn init(
+        &self,
+        metadata: HashMap<String, String>,
+        cache_manager: CacheManager,
+        maps: HashMap<String, u32>,
+    ) -> Result<(), Error> {
+        positive_metadata::<u64>(&metadata, "interval")?;
+        let module = {
+            let mut inner = self.inner.write();
+            inner.data.metadata = metadata.clone();
+            inner.data.ebpf_maps = maps;
+            inner.cache_mgr = Some(cache_manager.clone());
+            inner.module.clone()
+        };
+        *self.metadata.write() = metadata;
+
+        let instance = self.create_instance(module, cache_manager).await?;
+        self.set_instance(Some(instance)).await
+    }
+
+    async fn start(
+        &self,
+        mut shutdown_rx: broadcast::Receiver<ShutdownSignal>,
+    ) -> Result<(), Error> {
+        let mut interval = time::interval(self.interval());
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = self.poll().await {
+                        debug!("Error polling: {:?}", e);
+                        return Err(e);
+                    }
+                }
+                _ = self.updated.notified() => {
+                    let period = self.interval();
+                    if period != interval.period() {
+                        debug!("Changing poll interval to {:?}", period);
+                        interval = time::interval(period);
+                    }
+                }
+                Ok(signal) = shutdown_rx.recv() => {
+                    match signal {
+                        ShutdownSignal::All => {
+                            break;
+                        },
+                        ShutdownSignal::ProgramName(name) if name == self.get_name() => {
+                            debug!("Received shutdown signal, stopping program: {}", name);
+                            break;
+                        },
+                        _ => {}
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn stop(&self) -> Result<(), Error> {
+        {
+            let mut inner = self.inner.write();
+            inner.cache_mgr = None;
+            inner.samples.clear();
+            inner.data.metadata.clear();
+            inner.data.ebpf_maps.clear();
+        }
+        self.metadata.write().clear();
+        self.set_instance(None).await
+    }
+
+    fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
+        let samples = self.inner.read().samples.clone();
+        Self::encode_samples(samples, encoder)
+    }
+
+    fn get_name(&self) -> String {
+        let inner = self.inner.read();
+        inner.data.name.clone()
+    }
+
+    fn get_state(&self) -> ProgramState {
+        let inner = self.inner.read();
+        inner.data.program_state.clone()
+    }
+
+    fn set_state(&self, state: ProgramState) {
+        let mut inner = self.inner.write();
+        inner.data.program_state = state
+    }
+
+    fn get_type(&self) -> ProgramType {
+        let inner = self.inner.read();
+        inner.data.program_type.clone()
+    }
+
+    fn get_metadata(&self) -> HashMap<String, String> {
+        let inner = self.inner.read();
+        inner.data.metadata.clone()
+    }
+
+    fn set_metadata(&self, metadata: HashMap<String, String>) {
+        let mut inner = self.inner.write();
+        inner.data.metadata = metadata.clone();
+        *self.metadata.write() = metadata;
+    }
+
+    fn get_program_info(&self) -> Result<ProgramInfo, Error> {
+        let program_type: u32 = self.get_type().try_into()?;
+        let state: u32 = self.get_state().clone().try_into()?;
+        let inner = self.inner.read();
+        Ok(ProgramInfo {
+            name: inner.data.name.clone(),
+            program_type,
+            state,
+            bytecode: Some(inner.bytecode.clone()),
+            ebpf_maps: inner.data.ebpf_maps.clone(),
+            metadata: inner.data.metadata.clone(),
+            ..Default::default()
+        })
+    }
+
+    fn on_update(&self) -> Result<(), Error> {
+        positive_metadata::<u64>(&self.get_metadata(), "interval")?;
+        self.updated.notify_one();
+        Ok(())
+    }
+
+    async fn update_bytecode(
+        &self,
+        bytecode: Vec<u8>,
+        location: BytecodeLocation,
+    ) -> Result<(), Error> {
+        let module = Module::new(&ENGINE, bytecode)?;
+        // A running program switches over to a new instance only once it was
+        // initialized successfully, otherwise the old one is kept.
+        let cache_mgr = self.inner.read().cache_mgr.clone();
+        if let Some(cache_mgr) = cache_mgr {
+            let instance = self.create_instance(module.clone(), cache_mgr).await?;
+            self.set_instance(Some(instance)).await?;
+        }
+        let mut inner = self.inner.write();
+        inner.module = module;
+        inner.bytecode = location;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::collector::Collector;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+
+    use super::*;
+    use crate::managers::cache::{CacheConfig, MetadataSource};
+
+    const GUEST: &str = r#"
+        (module
+          (import "conductor" "emit_metric"
+            (func $emit_metric (param i32 i32 i32 i32 i32 i32 i32 f64) (result i32)))
+          (memory (export "memory") 1)
+          (data (i32.const 0) "guest_polls")
+          (data (i32.const 16) "Number of polls")
+          (data (i32.const 32) "{\"pod\":\"a\"}")
+          (global $polls (mut i32) (i32.const 0))
+          (func (export "poll") (result i32)
+            (global.set $polls (i32.add (global.get $polls) (i32.const 1)))
+            (i32.const 0))
+          (func (export "collect") (result i32)
+            (call $emit_metric
+              (i32.const 1)
+              (i32.const 0) (i32.const 11)
+              (i32.const 16) (i32.const 15)
+              (i32.const 32) (i32.const 11)
+              (f64.convert_i32_u (global.get $polls)))))
+    "#;
+
+    #[derive(Debug)]
+    struct ProgramCollector(Arc<WasmProgram>);
+
+    impl Collector for ProgramCollector {
+        fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), fmt::Error> {
+            self.0.collect(&mut encoder).map_err(|_| fmt::Error)
+        }
+    }
+
+    async fn load(name: &str, guest: &str) -> Arc<WasmProgram> {
+        let program = WasmProgram::new(name, guest.as_bytes(), Default::default()).unwrap();
+        let cache_manager = CacheManager::new(CacheConfig {
+            source: MetadataSource::Local,
+            kube: Default::default(),
+            static_file: None,
+        })
+        .await
+        .unwrap();
+        program
+            .init(HashMap::new(), cache_manager, HashMap::new())
+            .await
+            .unwrap();
+        Arc::new(program)
+    }
+
+    fn scrape(program: &Arc<WasmProgram>) -> String {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(ProgramCollector(program.clone())));
+        let mut buf = String::new();
+        encode(&mut buf, &registry).unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_guest_metrics() {
+        let program = load("guest", GUEST).await;
+        // nothing is served before the first poll, and scrapes don't call
+        // into the guest
+        assert!(!scrape(&program).contains("guest_polls"));
+
+        program.poll().await.unwrap();
+        program.poll().await.unwrap();
+        let metrics = scrape(&program);
+        assert!(metrics.contains("# TYPE guest_polls counter"));
+        assert!(metrics.contains("guest_polls_total{pod=\"a\"} 2"));
+        assert_eq!(scrape(&program), metrics);
+
+        program.stop().await.unwrap();
+        assert!(program.poll().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_guest_traps() {
+        let program = load(
+            "trap",
+            r#"(module (func (export "poll") (result i32) unreachable))"#,
+        )
+        .await;
+        let err = program.poll().await.unwrap_err();
+        assert!(format!("{:?}", err).contains("unreachable"));
+
+        let program = load(
+            "spin",
+            r#"(module (func (export "poll") (result i32) (loop $spin (br $spin)) (i32.const 0)))"#,
+        )
+        .await;
+        let err = program.poll().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<wasmtime::Trap>(),
+            Some(&wasmtime::Trap::OutOfFuel)
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -9,42 +10,80 @@ use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use log::{debug, info};
+use log::{debug, error, info};
 use prometheus_client::{encoding::text::encode, registry::Registry};
 use tokio::net::TcpListener;
 use tokio::pin;
 use tokio::sync::broadcast::Receiver;
 use tokio::task::JoinHandle;
+use url::form_urlencoded;
+
+use agent_api::ProgramState;
 
 use crate::collector::Collector;
+use crate::common::types::ListFilter;
 use crate::managers::registry::RegistryManager;
+use crate::progs::graph::ServiceGraph;
 use crate::progs::types::ShutdownSignal;
 
+/// Path of the service graph of the running programs.
+const GRAPH_PATH: &str = "/graph";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GraphFormat {
+    Json,
+    Dot,
+}
+
+struct MetricsState {
+    metrics_path: String,
+    registry: Registry,
+    registry_manager: RegistryManager,
+}
+
 pub async fn serve(
     address: String,
+    metrics_path: String,
     registry_manager: RegistryManager,
     shutdown_rx: Receiver<ShutdownSignal>,
 ) -> anyhow::Result<JoinHandle<()>> {
     let metrics_addr = address.parse::<SocketAddr>()?;
-    let collector = Box::new(Collector::new(registry_manager));
+    let collector = Box::new(Collector::new(registry_manager.clone()));
     let mut registry = Registry::default();
     registry.register_collector(collector);
     let server_handle = tokio::spawn(async move {
-        start_metrics_server(metrics_addr, registry, shutdown_rx)
-            .await
-            .unwrap();
+        start_metrics_server(
+            metrics_addr,
+            metrics_path,
+            registry,
+            registry_manager,
+            shutdown_rx,
+        )
+        .await
+        .unwrap();
     });
     Ok(server_handle)
 }
 
 /// Start an HTTP server to report metrics.
+///
+/// `registry` is served on `metrics_path`. Requests to `<metrics_path>/<program>`
+/// or with `program` or `match_metadata` query parameters only get the metrics
+/// of the matching programs. The service graph is served on `/graph`, as JSON
+/// or with `format=dot` as Graphviz DOT, and takes the same query parameters.
 async fn start_metrics_server(
     addr: SocketAddr,
+    metrics_path: String,
     registry: Registry,
+    registry_manager: RegistryManager,
     mut shutdown_rx: Receiver<ShutdownSignal>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let listener = TcpListener::bind(&addr).await?;
-    let registry = Arc::new(registry);
+    let state = Arc::new(MetricsState {
+        metrics_path,
+        registry,
+        registry_manager,
+    });
     let connection_timeouts = vec![Duration::from_secs(5), Duration::from_secs(2)];
 
     loop {
@@ -61,11 +100,11 @@ async fn start_metrics_server(
             accept_result = listener.accept() => {
                 let (stream, _) = accept_result?;
                 let io = TokioIo::new(stream);
-                let registry = registry.clone();
+                let state = state.clone();
                 let connection_timeouts_clone = connection_timeouts.clone();
 
                 tokio::task::spawn(async move {
-                    let conn = http1::Builder::new().serve_connection(io, service_fn(move |req| request_handler(registry.clone(), req)));
+                    let conn = http1::Builder::new().serve_connection(io, service_fn(move |req| request_handler(state.clone(), req)));
                     pin!(conn);
 
                     for sleep_duration in connection_timeouts_clone {
@@ -91,13 +130,136 @@ async fn start_metrics_server(
     Ok(())
 }
 
+/// Works out which programs a scrape asks for. `None` means the whole registry
+/// is served, which is the case for the bare metrics path.
+fn scrape_filter(
+    metrics_path: &str,
+    path: &str,
+    query: Option<&str>,
+) -> Result<Option<ListFilter>, StatusCode> {
+    let mut program_names = Vec::new();
+    match path.strip_prefix(metrics_path.trim_end_matches('/')) {
+        Some("") | Some("/") => {}
+        Some(rest) => match rest.strip_prefix('/') {
+            Some(name) if !name.contains('/') => program_names.push(name.to_string()),
+            _ => return Err(StatusCode::NOT_FOUND),
+        },
+        None => return Err(StatusCode::NOT_FOUND),
+    }
+
+    let filter = query_filter(query, program_names)?;
+    if filter.program_names.is_empty() && filter.metadata_selector.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(filter))
+}
+
+/// Builds a filter from the `program` and `match_metadata` query parameters.
+fn query_filter(
+    query: Option<&str>,
+    mut program_names: Vec<String>,
+) -> Result<ListFilter, StatusCode> {
+    let mut metadata = HashMap::new();
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        match key.as_ref() {
+            "program" => program_names.extend(
+                value
+                    .split(',')
+                    .filter(|name| !name.is_empty())
+                    .map(|name| name.to_string()),
+            ),
+            "match_metadata" => {
+                let (k, v) = value.split_once('=').ok_or(StatusCode::BAD_REQUEST)?;
+                metadata.insert(k.to_string(), v.to_string());
+            }
+            _ => {}
+        }
+    }
+    Ok(ListFilter::new(None, metadata).with_program_names(program_names))
+}
+
+fn graph_request(query: Option<&str>) -> Result<(ListFilter, GraphFormat), StatusCode> {
+    let mut format = GraphFormat::Json;
+    for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+        if key == "format" {
+            format = match value.as_ref() {
+                "json" => GraphFormat::Json,
+                "dot" => GraphFormat::Dot,
+                _ => return Err(StatusCode::BAD_REQUEST),
+            };
+        }
+    }
+    Ok((query_filter(query, Vec::new())?, format))
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::from(Bytes::new()))
+        .unwrap()
+}
+
+fn graph_handler(state: &MetricsState, query: Option<&str>) -> Response<Full<Bytes>> {
+    let (filter, format) = match graph_request(query) {
+        Ok(request) => request,
+        Err(status) => return status_response(status),
+    };
+
+    let mut graph = ServiceGraph::new();
+    for prog in state.registry_manager.list_programs(filter) {
+        if prog.get_state() != ProgramState::Running {
+            continue;
+        }
+        match prog.service_graph() {
+            Ok(Some(g)) => graph.merge(g),
+            Ok(None) => {}
+            Err(e) => {
+                error!(
+                    "Failed to get the service graph of {}: {:?}",
+                    prog.get_name(),
+                    e
+                );
+                return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+
+    let (content_type, body) = match format {
+        GraphFormat::Json => ("application/json", graph.to_node_graph().to_string()),
+        GraphFormat::Dot => ("text/vnd.graphviz", graph.to_dot()),
+    };
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, content_type)
+        .body(Full::from(body))
+        .unwrap()
+}
+
 async fn request_handler(
-    registry: Arc<Registry>,
-    _request: Request<hyper::body::Incoming>,
+    state: Arc<MetricsState>,
+    request: Request<hyper::body::Incoming>,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
-    let reg = registry.clone();
+    let uri = request.uri();
+    if uri.path() == GRAPH_PATH {
+        return Ok(graph_handler(&state, uri.query()));
+    }
+    let filter = match scrape_filter(&state.metrics_path, uri.path(), uri.query()) {
+        Ok(filter) => filter,
+        Err(status) => return Ok(status_response(status)),
+    };
+
     let mut buf = String::new();
-    match encode(&mut buf, &reg.clone()) {
+    let result = match filter {
+        Some(filter) => {
+            let mut registry = Registry::default();
+            registry.register_collector(Box::new(Collector::with_filter(
+                state.registry_manager.clone(),
+                filter,
+            )));
+            encode(&mut buf, &registry)
+        }
+        None => encode(&mut buf, &state.registry),
+    };
+    match result {
         Ok(_) => Ok(Response::builder()
             .header(
                 hyper::header::CONTENT_TYPE,
@@ -105,14 +267,7 @@ async fn request_handler(
             )
             .body(Full::from(buf))
             .unwrap()),
-        Err(_) => {
-            // Handle or ignore the error here.
-            // For example, you can return an empty response with a status code of 500.
-            Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Full::from(Bytes::new()))
-                .unwrap())
-        }
+        Err(_) => Ok(status_response(StatusCode::INTERNAL_SERVER_ERROR)),
     }
 }
 
@@ -183,9 +338,15 @@ mod tests {
         let (_, shutdown_rx) = tokio::sync::broadcast::channel(1);
 
         let server_handle = tokio::spawn(async move {
-            start_metrics_server(metrics_addr, registry, shutdown_rx)
-                .await
-                .unwrap();
+            start_metrics_server(
+                metrics_addr,
+                "/metrics".to_string(),
+                registry,
+                RegistryManager::new(),
+                shutdown_rx,
+            )
+            .await
+            .unwrap();
         });
 
         // Add a delay to ensure the server has time to start
@@ -215,4 +376,58 @@ mod tests {
         assert_eq!(body_string, "# HELP http_requests Number of HTTP requests received.\n# TYPE http_requests counter\nhttp_requests_total{method=\"GET\",path=\"/metrics\"} 1\n# EOF\n");
         server_handle.abort();
     }
+
+    #[test]
+    fn test_scrape_filter() {
+        assert!(scrape_filter("/metrics", "/metrics", None)
+            .unwrap()
+            .is_none());
+        assert!(scrape_filter("/metrics/", "/metrics", Some("foo=bar"))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            scrape_filter("/metrics", "/other", None).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            scrape_filter("/metrics", "/metrics/a/b", None).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            scrape_filter("/metrics", "/metrics", Some("match_metadata=owner")).unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let filter = scrape_filter("/metrics", "/metrics/service_map", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(filter.program_names, vec!["service_map"]);
+        assert!(filter.metadata_selector.is_empty());
+
+        let filter = scrape_filter(
+            "/metrics",
+            "/metrics",
+            Some("program=a,b&program=c&match_metadata=owner%3Dacme"),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(filter.program_names, vec!["a", "b", "c"]);
+        assert_eq!(filter.metadata_selector["owner"], "acme");
+    }
+
+    #[test]
+    fn test_graph_request() {
+        let (filter, format) = graph_request(None).unwrap();
+        assert_eq!(format, GraphFormat::Json);
+        assert!(filter.program_names.is_empty());
+
+        let (filter, format) = graph_request(Some("format=dot&program=service_map")).unwrap();
+        assert_eq!(format, GraphFormat::Dot);
+        assert_eq!(filter.program_names, vec!["service_map"]);
+
+        assert_eq!(
+            graph_request(Some("format=svg")).unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+    }
 }
@@ -7,7 +7,13 @@ use tokio::task::{JoinHandle, JoinSet};
 use agent_api::select_channel;
 use agent_api::v1::agent_server::AgentServer;
 
+use crate::common::constants::directories::RTDIR_IMAGES;
+use crate::managers::cache::kubernetes::KubeConfig;
+use crate::managers::cache::CacheConfig;
+use crate::managers::image::verify::SignatureVerifier;
+use crate::managers::image::ImageManager;
 use crate::managers::prog::ProgManager;
+use crate::managers::state::StateStore;
 use crate::progs::types::ShutdownSignal;
 use crate::Args;
 
@@ -20,8 +26,31 @@ pub(crate) async fn serve(args: Args) -> anyhow::Result<()> {
 
     let channel = select_channel(args.bpfman_socket_path).unwrap();
     let bpf_client = BpfmanClient::new(channel);
-    let prog_manager = ProgManager::new(shutdown_tx.clone()).await?;
-    let agent_service = rpc::AgentService::new(prog_manager.clone(), bpf_client);
+    let verifier = match &args.image_verification_key {
+        Some(path) => Some(SignatureVerifier::from_pem(&std::fs::read(path)?)?),
+        None => None,
+    };
+    let image_manager = ImageManager::new(RTDIR_IMAGES, verifier);
+    let cache_config = CacheConfig {
+        source: args.metadata_source,
+        kube: KubeConfig {
+            node_name: args.node_name,
+            watched_kinds: args.watched_kinds.into_iter().collect(),
+            promoted_labels: args.promoted_labels,
+            promoted_annotations: args.promoted_annotations,
+        },
+        static_file: args.metadata_file,
+    };
+    let prog_manager = ProgManager::new(
+        shutdown_tx.clone(),
+        image_manager,
+        cache_config,
+        bpf_client,
+    )
+    .await?;
+    let state_store = StateStore::new(&args.state_dir);
+    let agent_service = rpc::AgentService::new(prog_manager.clone(), state_store);
+    agent_service.reconcile().await;
     let service = AgentServer::new(agent_service);
 
     let mut listeners: Vec<_> = Vec::new();
@@ -30,6 +59,7 @@ pub(crate) async fn serve(args: Args) -> anyhow::Result<()> {
     let shutdown_rx2 = shutdown_tx.subscribe();
     let http_server = http::serve(
         args.metrics_addr,
+        args.metrics_path,
         prog_manager.registry_manager.clone(),
         shutdown_rx2,
     )
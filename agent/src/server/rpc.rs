@@ -1,79 +1,133 @@
 use std::collections::HashMap;
 use std::fs::remove_file;
 use std::path::Path;
+use std::sync::Arc;
 
-use bpfman_api::v1::bpfman_client::BpfmanClient;
 use bpfman_lib::utils::set_file_permissions;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use tokio::net::UnixListener;
 use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
 use tokio_stream::wrappers::UnixListenerStream;
-use tonic::transport::{Channel, Server};
+use tonic::transport::Server;
 use tonic::{Request, Response, Status};
 
+use agent_api::ProgramType;
 use agent_api::v1::agent_server::{Agent, AgentServer};
 use agent_api::v1::list_response::ListResult;
 use agent_api::v1::{
-    GetRequest, GetResponse, ListRequest, ListResponse, LoadRequest, LoadResponse,
-    PullBytecodeRequest, PullBytecodeResponse, UnloadRequest, UnloadResponse,
+    BytecodeLocation, GetRequest, GetResponse, ListRequest, ListResponse, LoadRequest,
+    LoadResponse, PullBytecodeRequest, PullBytecodeResponse, UnloadRequest, UnloadResponse,
+    UpdateRequest, UpdateResponse,
 };
 
 use crate::common::constants::directories::SOCK_MODE;
 use crate::common::types::ListFilter;
+use crate::managers::image::verify::VerificationError;
 use crate::managers::prog::ProgManager;
-use crate::progs::types::ShutdownSignal;
+use crate::managers::state::StateStore;
+use crate::progs::types::{Program, ShutdownSignal};
+
+/// Images failing digest or signature verification are reported as
+/// `PermissionDenied` so clients can tell them apart from pull failures.
+fn image_error_status(msg: &str, e: anyhow::Error) -> Status {
+    let msg = format!("{}: {:?}", msg, e.to_string());
+    if e.downcast_ref::<VerificationError>().is_some() {
+        Status::permission_denied(msg)
+    } else {
+        Status::aborted(msg)
+    }
+}
 
 pub struct AgentService {
     pub prog_manager: ProgManager,
-    pub bpf_client: BpfmanClient<Channel>,
+    pub state_store: StateStore,
 }
 
 impl AgentService {
-    pub(crate) fn new(prog_manager: ProgManager, bpf_client: BpfmanClient<Channel>) -> Self {
+    pub(crate) fn new(prog_manager: ProgManager, state_store: StateStore) -> Self {
         Self {
             prog_manager,
-            bpf_client,
+            state_store,
         }
     }
 
-    async fn get_prog_ids_for_maps(
-        &self,
-        map_to_prog_name: HashMap<String, String>,
-    ) -> Result<HashMap<String, u32>, anyhow::Error> {
-        let req = Request::new(bpfman_api::v1::ListRequest {
-            program_type: None,
-            bpfman_programs_only: None,
-            match_metadata: Default::default(),
-        });
-        let mut bpf_client = self.bpf_client.clone();
-        let response = bpf_client.list(req).await?.into_inner();
-        let loaded_ebpf_progs = response
-            .results
-            .iter()
-            .filter_map(|prog| prog.kernel_info.as_ref())
-            .map(|info| (info.name.clone(), info.id))
-            .collect::<HashMap<String, u32>>();
-
-        let mut map_to_prog_id = HashMap::new();
-        for (map_name, prog_name) in map_to_prog_name {
-            let prog_id = loaded_ebpf_progs.get(&prog_name).ok_or(anyhow::anyhow!(
-                "Required eBPF program {} not loaded",
-                prog_name
-            ))?;
-            map_to_prog_id.insert(map_name, *prog_id);
+    /// Loads the programs persisted by a previous run of the agent. Programs
+    /// which fail to load are kept in the state directory and retried on the
+    /// next start.
+    pub(crate) async fn reconcile(&self) {
+        let requests = match self.state_store.list() {
+            Ok(requests) => requests,
+            Err(e) => {
+                error!(
+                    "Failed to read program state from {}: {:?}",
+                    self.state_store.dir().display(),
+                    e
+                );
+                return;
+            }
+        };
+
+        for request in requests {
+            let name = request.name.clone();
+            match self.load_program(request).await {
+                Ok(_) => info!("Program {} restored.", name),
+                Err(status) => error!("Failed to restore program {}: {}", name, status.message()),
+            }
         }
-        Ok(map_to_prog_id)
     }
-}
 
-#[tonic::async_trait]
-impl Agent for AgentService {
-    async fn load(&self, request: Request<LoadRequest>) -> Result<Response<LoadResponse>, Status> {
-        let request = request.into_inner();
+    async fn start_program(
+        &self,
+        name: String,
+        program_type: ProgramType,
+        metadata: HashMap<String, String>,
+        map_to_prog_id: HashMap<String, u32>,
+    ) -> Result<Arc<dyn Program>, Status> {
+        let prog = self
+            .prog_manager
+            .pre_load(
+                name,
+                program_type,
+                metadata,
+                self.prog_manager.cache_manager.clone(),
+                map_to_prog_id,
+            )
+            .await
+            .map_err(|e| {
+                Status::aborted(format!("Failed to pre-load program: {:?}", e.to_string()))
+            })?;
 
+        self.prog_manager
+            .load(prog.clone())
+            .await
+            .map_err(|e| Status::aborted(format!("Failed to load program: {:?}", e.to_string())))?;
+
+        Ok(prog)
+    }
+
+    /// Keeps the persisted load request of a program in line with an update.
+    fn persist_update(
+        &self,
+        name: &str,
+        metadata: HashMap<String, String>,
+        location: Option<BytecodeLocation>,
+    ) -> Result<(), anyhow::Error> {
+        let Some(mut request) = self.state_store.get(name)? else {
+            return Ok(());
+        };
+        request.metadata = metadata;
+        if location.is_some() {
+            request.bytecode = location;
+        }
+        self.state_store.save(&request)
+    }
+
+    /// Registers and starts the program of a load request.
+    async fn load_program(&self, request: LoadRequest) -> Result<Arc<dyn Program>, Status> {
         let map_to_prog_id = self
-            .get_prog_ids_for_maps(request.ebpf_maps)
+            .prog_manager
+            .get_prog_ids_for_maps(request.ebpf_maps.clone())
             .await
             .map_err(|e| {
                 Status::aborted(format!(
@@ -82,33 +136,85 @@ impl Agent for AgentService {
                 ))
             })?;
 
-        let program_type = request.program_type.try_into().map_err(|_| {
+        let program_type: ProgramType = request.program_type.try_into().map_err(|_| {
             Status::aborted(format!(
                 "Failed to convert program type: {:?}",
                 request.program_type
             ))
         })?;
 
+        let registered = match program_type {
+            ProgramType::Builtin => self
+                .prog_manager
+                .register_builtin(request.name.clone(), request.kind)
+                .await
+                .map_err(|e| {
+                    Status::aborted(format!(
+                        "Failed to register builtin program: {:?}",
+                        e.to_string()
+                    ))
+                })?,
+            ProgramType::Wasm => {
+                let location = request.bytecode.ok_or_else(|| {
+                    Status::invalid_argument("Bytecode location is required for wasm programs")
+                })?;
+                let bytecode = self
+                    .prog_manager
+                    .image_manager
+                    .get_bytecode(&location)
+                    .await
+                    .map_err(|e| image_error_status("Failed to get bytecode", e))?;
+                self.prog_manager
+                    .register_wasm(request.name.clone(), bytecode, location)
+                    .await
+                    .map_err(|e| {
+                        Status::aborted(format!(
+                            "Failed to register wasm program: {:?}",
+                            e.to_string()
+                        ))
+                    })?;
+                true
+            }
+        };
+
         let prog = self
-            .prog_manager
-            .pre_load(
-                request.name,
-                program_type,
+            .start_program(
+                request.name.clone(),
+                program_type.clone(),
                 request.metadata,
-                self.prog_manager.cache_manager.clone(),
                 map_to_prog_id,
             )
-            .await
-            .map_err(|e| {
-                Status::aborted(format!("Failed to pre-load program: {:?}", e.to_string()))
-            })?;
+            .await;
+        match prog {
+            Ok(prog) => {
+                self.prog_manager
+                    .map_programs
+                    .lock()
+                    .insert(request.name, request.ebpf_maps);
+                Ok(prog)
+            }
+            Err(status) => {
+                if registered {
+                    self.prog_manager
+                        .registry_manager
+                        .remove_program(request.name.as_str(), Some(program_type));
+                }
+                Err(status)
+            }
+        }
+    }
+}
 
-        self.prog_manager
-            .load(prog.clone())
-            .await
-            .map_err(|e| Status::aborted(format!("Failed to load program: {:?}", e.to_string())))?;
+#[tonic::async_trait]
+impl Agent for AgentService {
+    async fn load(&self, request: Request<LoadRequest>) -> Result<Response<LoadResponse>, Status> {
+        let request = request.into_inner();
+        let prog = self.load_program(request.clone()).await?;
+        if let Err(e) = self.state_store.save(&request) {
+            warn!("Failed to persist program {}: {:?}", request.name, e);
+        }
 
-        let prog_info = prog.get_program_info().map_err(|e| {
+        let prog_info = self.prog_manager.program_info(&prog).map_err(|e| {
             Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
         })?;
 
@@ -128,6 +234,12 @@ impl Agent for AgentService {
             .map_err(|e| {
                 Status::aborted(format!("Failed to unload program: {:?}", e.to_string()))
             })?;
+        if let Err(e) = self.state_store.remove(&request.name) {
+            warn!(
+                "Failed to remove persisted program {}: {:?}",
+                request.name, e
+            );
+        }
         Ok(Response::new(UnloadResponse {}))
     }
 
@@ -141,7 +253,7 @@ impl Agent for AgentService {
 
         for prog in progs.iter() {
             let reply_entry = ListResult {
-                info: Some(prog.get_program_info().map_err(|e| {
+                info: Some(self.prog_manager.program_info(prog).map_err(|e| {
                     Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
                 })?),
             };
@@ -153,9 +265,63 @@ impl Agent for AgentService {
 
     async fn pull_bytecode(
         &self,
-        _request: Request<PullBytecodeRequest>,
+        request: Request<PullBytecodeRequest>,
     ) -> Result<Response<PullBytecodeResponse>, Status> {
-        todo!()
+        let request = request.into_inner();
+        let image = request
+            .image
+            .ok_or_else(|| Status::invalid_argument("Bytecode image is required"))?;
+
+        self.prog_manager
+            .image_manager
+            .pull_image(&image)
+            .await
+            .map_err(|e| image_error_status("Failed to pull bytecode", e))?;
+
+        Ok(Response::new(PullBytecodeResponse {}))
+    }
+
+    async fn update(
+        &self,
+        request: Request<UpdateRequest>,
+    ) -> Result<Response<UpdateResponse>, Status> {
+        let request = request.into_inner();
+        let name = request.name.clone();
+        if self.prog_manager.get(name.clone(), None).await.is_none() {
+            return Err(Status::not_found(format!("Program {} not found", name)));
+        }
+        let location = request.bytecode.clone();
+        let bytecode = match request.bytecode {
+            Some(location) => {
+                let bytecode = self
+                    .prog_manager
+                    .image_manager
+                    .get_bytecode(&location)
+                    .await
+                    .map_err(|e| image_error_status("Failed to get bytecode", e))?;
+                Some((bytecode, location))
+            }
+            None => None,
+        };
+
+        let prog = self
+            .prog_manager
+            .update(request.name, request.metadata, bytecode)
+            .await
+            .map_err(|e| {
+                Status::aborted(format!("Failed to update program: {:?}", e.to_string()))
+            })?;
+        if let Err(e) = self.persist_update(&name, prog.get_metadata(), location) {
+            warn!("Failed to persist update of program {}: {:?}", name, e);
+        }
+
+        let prog_info = self.prog_manager.program_info(&prog).map_err(|e| {
+            Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
+        })?;
+
+        Ok(Response::new(UpdateResponse {
+            info: Some(prog_info),
+        }))
     }
 
     async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
@@ -166,7 +332,7 @@ impl Agent for AgentService {
             .await
             .ok_or_else(|| Status::aborted(format!("Program {} not found", request.name)))?;
 
-        let prog_info = prog.get_program_info().map_err(|e| {
+        let prog_info = self.prog_manager.program_info(&prog).map_err(|e| {
             Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
         })?;
 
@@ -219,3 +385,63 @@ pub async fn serve(
         info!("Shutdown Unix Handler {}", socket_path.display());
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use bpfman_api::v1::bpfman_client::BpfmanClient;
+    use tonic::transport::Endpoint;
+    use tonic::Code;
+
+    use super::*;
+    use crate::managers::cache::{CacheConfig, MetadataSource};
+    use crate::managers::image::ImageManager;
+
+    #[test]
+    fn test_image_error_status() {
+        let rejected = VerificationError::InvalidSignature("sha256:abc".to_string());
+        let status = image_error_status("Failed to pull bytecode", rejected.into());
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert!(status.message().contains("sha256:abc"));
+
+        let rejected = anyhow::Error::from(VerificationError::MissingSignature(
+            "sha256:abc".to_string(),
+        ))
+        .context("Failed to fetch image");
+        let status = image_error_status("Failed to pull bytecode", rejected);
+        assert_eq!(status.code(), Code::PermissionDenied);
+
+        let status = image_error_status(
+            "Failed to pull bytecode",
+            anyhow::anyhow!("connection refused"),
+        );
+        assert_eq!(status.code(), Code::Aborted);
+    }
+
+    #[tokio::test]
+    async fn test_update_unknown_program() {
+        let dir = std::env::temp_dir().join(format!("agent-rpc-{}", std::process::id()));
+        let (shutdown_tx, _) = broadcast::channel(1);
+        let channel = Endpoint::from_static("http://localhost").connect_lazy();
+        let prog_manager = ProgManager::new(
+            shutdown_tx,
+            ImageManager::new(dir.join("images"), None),
+            CacheConfig {
+                source: MetadataSource::Local,
+                kube: Default::default(),
+                static_file: None,
+            },
+            BpfmanClient::new(channel),
+        )
+        .await
+        .unwrap();
+        let service = AgentService::new(prog_manager, StateStore::new(dir.join("state")));
+
+        let request = UpdateRequest {
+            name: "missing".to_string(),
+            metadata: HashMap::from([("interval".to_string(), "5".to_string())]),
+            ..Default::default()
+        };
+        let status = service.update(Request::new(request)).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+}
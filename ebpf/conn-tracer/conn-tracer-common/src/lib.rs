@@ -20,6 +20,21 @@ pub const TCP_MAX_STATES: i32 = 13;
 pub const INET_SOCK_SKADDR_OFFSET: usize = 8;
 pub const INET_SOCK_NEWSTATE_OFFSET: usize = 20;
 
+/// Offset of `skaddr` in the `tcp:tcp_retransmit_skb` and `tcp:tcp_send_reset`
+/// tracepoints.
+pub const TCP_EVENT_SKADDR_OFFSET: usize = 16;
+
+pub const IPPROTO_TCP: u32 = 6;
+pub const IPPROTO_UDP: u32 = 17;
+
+pub const UDP_HEADER_LEN: u32 = 8;
+
+/// Bits of `sk_buff._nfct` holding the `ip_conntrack_info` of the packet, the
+/// others point to its `nf_conn`.
+pub const NFCT_INFOMASK: u64 = 7;
+pub const IP_CT_DIR_ORIGINAL: usize = 0;
+pub const IP_CT_DIR_REPLY: usize = 1;
+
 pub const CONNECTION_ROLE_UNKNOWN: u32 = 0;
 pub const CONNECTION_ROLE_CLIENT: u32 = 1;
 pub const CONNECTION_ROLE_SERVER: u32 = 2;
@@ -31,21 +46,33 @@ pub struct SockInfo {
     pub pid: u32,
     pub is_active: u32,
     pub role: u32,
+    /// `bpf_ktime_get_ns` of when the socket was first seen.
+    pub start_ns: u64,
+    /// RST segments sent on the socket.
+    pub resets: u64,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for SockInfo {}
 
-#[derive(Copy, Clone, Debug, Default)]
+/// Addresses are IPv6 addresses in network byte order, IPv4 addresses are
+/// stored IPv4-mapped (`::ffff:a.b.c.d`). An unknown address is all zeros.
+///
+/// UDP flows have no connection to follow, so they are keyed by their 5-tuple
+/// and the sending or receiving process, with an `id` of 0.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub struct ConnectionKey {
     pub id: u32,
+    /// tgid of the process owning the socket, 0 when it is unknown as for the
+    /// connections accepted by a TCP server.
     pub pid: u32,
-    pub src_addr: u32,
+    pub src_addr: [u8; 16],
     pub src_port: u32,
-    pub dest_addr: u32,
+    pub dest_addr: [u8; 16],
     pub dest_port: u32,
     pub role: u32,
+    pub protocol: u32,
 }
 
 #[cfg(feature = "user")]
@@ -57,7 +84,45 @@ pub struct ConnectionStats {
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub is_active: u64,
+    /// `bpf_ktime_get_ns` of when the connection or UDP flow started, 0 if it
+    /// is unknown.
+    pub start_ns: u64,
+    /// `bpf_ktime_get_ns` of the last activity seen on the connection.
+    pub last_seen_ns: u64,
+    /// Smoothed round trip time in microseconds, TCP only.
+    pub srtt_us: u64,
+    /// Segments retransmitted, TCP only.
+    pub retransmits: u64,
+    /// RST segments sent, TCP only.
+    pub resets: u64,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ConnectionStats {}
+
+/// A flow as sent by its client, before any NAT. Addresses are stored as in
+/// `ConnectionKey`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct NatKey {
+    pub src_addr: [u8; 16],
+    pub src_port: u32,
+    pub dest_addr: [u8; 16],
+    pub dest_port: u32,
+    pub protocol: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for NatKey {}
+
+/// The destination a flow was translated to by a DNAT, e.g. the backend pod
+/// kube-proxy picked for a Service ClusterIP.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct NatTarget {
+    pub dest_addr: [u8; 16],
+    pub dest_port: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for NatTarget {}
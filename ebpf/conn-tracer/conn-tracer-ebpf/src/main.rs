@@ -7,11 +7,15 @@ use aya_ebpf::{
     programs::{ProbeContext, TracePointContext},
 };
 use conn_tracer_common::{
-    ConnectionKey, ConnectionStats, SockInfo, AF_INET, AF_INET6, CONNECTION_ROLE_CLIENT,
-    CONNECTION_ROLE_SERVER, CONNECTION_ROLE_UNKNOWN, INET_SOCK_NEWSTATE_OFFSET,
-    INET_SOCK_SKADDR_OFFSET, MAX_CONNECTIONS, TCP_CLOSE, TCP_SYN_RECV, TCP_SYN_SENT,
+    ConnectionKey, ConnectionStats, NatKey, NatTarget, SockInfo, AF_INET, AF_INET6,
+    CONNECTION_ROLE_CLIENT, CONNECTION_ROLE_SERVER, CONNECTION_ROLE_UNKNOWN,
+    INET_SOCK_NEWSTATE_OFFSET, INET_SOCK_SKADDR_OFFSET, IPPROTO_TCP, IPPROTO_UDP,
+    IP_CT_DIR_ORIGINAL, IP_CT_DIR_REPLY, MAX_CONNECTIONS, NFCT_INFOMASK, TCP_CLOSE,
+    TCP_EVENT_SKADDR_OFFSET, TCP_SYN_RECV, TCP_SYN_SENT, UDP_HEADER_LEN,
+};
+use vmlinux::{
+    flowi4, flowi6, nf_conn, nf_conntrack_tuple, nf_inet_addr, sk_buff, sock, sock_common, tcp_sock,
 };
-use vmlinux::{sock, sock_common, tcp_sock};
 
 #[allow(non_upper_case_globals)]
 #[allow(non_snake_case)]
@@ -27,6 +31,10 @@ static mut SOCKETS: aya_ebpf::maps::LruHashMap<*const sock, SockInfo> =
 static mut CONNECTIONS: aya_ebpf::maps::LruHashMap<ConnectionKey, ConnectionStats> =
     aya_ebpf::maps::LruHashMap::<ConnectionKey, ConnectionStats>::pinned(MAX_CONNECTIONS, 0);
 
+#[map(name = "NAT_TRANSLATIONS")]
+static mut NAT_TRANSLATIONS: aya_ebpf::maps::LruHashMap<NatKey, NatTarget> =
+    aya_ebpf::maps::LruHashMap::<NatKey, NatTarget>::pinned(MAX_CONNECTIONS, 0);
+
 #[kprobe]
 pub fn sock_conn_tracer(ctx: ProbeContext) -> u32 {
     match try_sock_conn_tracer(ctx) {
@@ -41,12 +49,17 @@ pub fn sock_conn_tracer(ctx: ProbeContext) -> u32 {
 fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
     // first argument to tcp_data_queue is a struct sock*
     let sk: *const sock = ctx.arg(0).ok_or(1i64)?;
+    update_connection(sk)
+}
+
+/// Refreshes the stats of the connection of a TCP socket.
+fn update_connection(sk: *const sock) -> Result<u32, i64> {
     let mut conn_key = ConnectionKey::default();
     let mut conn_stats = ConnectionStats::default();
 
     parse_sock_data(sk, &mut conn_key, &mut conn_stats)?;
 
-    if conn_key.dest_addr == 0 && conn_key.dest_port == 0 {
+    if conn_key.dest_addr == [0u8; 16] && conn_key.dest_port == 0 {
         return Ok(0);
     }
 
@@ -59,6 +72,8 @@ fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
                 return Err(1i64);
             }
             conn_stats.is_active = sock_info.is_active as u64;
+            conn_stats.start_ns = sock_info.start_ns;
+            conn_stats.resets = sock_info.resets;
             unsafe {
                 CONNECTIONS.insert(&conn_key, &conn_stats, 0_u64)?;
             }
@@ -69,6 +84,8 @@ fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
                 pid: 0,
                 is_active: 1,
                 role: get_sock_role(sk),
+                start_ns: conn_stats.last_seen_ns,
+                resets: 0,
             };
 
             unsafe {
@@ -79,6 +96,7 @@ fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
             conn_key.pid = sock_info.pid;
             conn_key.role = sock_info.role;
             conn_stats.is_active = 1;
+            conn_stats.start_ns = sock_info.start_ns;
 
             unsafe {
                 CONNECTIONS.insert(&conn_key, &conn_stats, 0_u64)?;
@@ -104,27 +122,46 @@ fn parse_sock_data(
         unsafe { bpf_probe_read_kernel(&(*tcp_sk).bytes_sent as *const u64).map_err(|e| e)? };
     conn_stats.bytes_received =
         unsafe { bpf_probe_read_kernel(&(*tcp_sk).bytes_received as *const u64).map_err(|e| e)? };
+    conn_stats.last_seen_ns = unsafe { bpf_ktime_get_ns() };
+    // srtt_us is stored left shifted by 3
+    let srtt = unsafe { bpf_probe_read_kernel(&(*tcp_sk).srtt_us as *const u32)? };
+    conn_stats.srtt_us = (srtt >> 3) as u64;
+    conn_stats.retransmits =
+        unsafe { bpf_probe_read_kernel(&(*tcp_sk).total_retrans as *const u32)? } as u64;
 
     // read connection data
+    let src_port = u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num });
+    let dest_port = u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport });
     match sk_common.skc_family {
         AF_INET => {
-            let src_addr =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr });
-            let dest_addr: u32 =
-                u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr });
-            let src_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num });
-            let dest_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport });
-            conn_key.src_addr = src_addr;
-            conn_key.dest_addr = dest_addr;
-            conn_key.src_port = src_port as u32;
-            conn_key.dest_port = dest_port as u32;
-            Ok(0)
+            conn_key.src_addr =
+                ipv4_mapped(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr });
+            conn_key.dest_addr =
+                ipv4_mapped(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr });
         }
-        AF_INET6 => Err(1i64),
-        _ => Err(1i64),
+        AF_INET6 => {
+            // Dual-stack sockets talking IPv4 already carry IPv4-mapped addresses.
+            conn_key.src_addr = unsafe { sk_common.skc_v6_rcv_saddr.in6_u.u6_addr8 };
+            conn_key.dest_addr = unsafe { sk_common.skc_v6_daddr.in6_u.u6_addr8 };
+        }
+        _ => return Err(1i64),
+    }
+    conn_key.src_port = src_port as u32;
+    conn_key.dest_port = dest_port as u32;
+    conn_key.protocol = IPPROTO_TCP;
+    Ok(0)
+}
+
+/// Maps a network byte order IPv4 address into the IPv6 address space, leaving
+/// an unset address all zeros.
+fn ipv4_mapped(addr: u32) -> [u8; 16] {
+    let mut mapped = [0u8; 16];
+    if addr != 0 {
+        mapped[10] = 0xff;
+        mapped[11] = 0xff;
+        mapped[12..].copy_from_slice(&addr.to_ne_bytes());
     }
+    mapped
 }
 
 fn get_sock_role(sk: *const sock) -> u32 {
@@ -170,12 +207,14 @@ fn try_state_tracer(ctx: TracePointContext) -> Result<u32, i64> {
 
 fn handle_tcp_syn_sent(sk: *const sock) -> Result<u32, i64> {
     let id = get_unique_id();
-    let pid = bpf_get_current_pid_tgid() as u32;
+    let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
     let sock_info = SockInfo {
         id,
         pid,
         is_active: 1,
         role: CONNECTION_ROLE_CLIENT,
+        start_ns: unsafe { bpf_ktime_get_ns() },
+        resets: 0,
     };
 
     unsafe {
@@ -196,19 +235,22 @@ fn handle_tcp_syn_recv(sk: *const sock) -> Result<u32, i64> {
         pid: 0,
         is_active: 1,
         role: CONNECTION_ROLE_SERVER,
+        start_ns: conn_stats.last_seen_ns,
+        resets: 0,
     };
 
     unsafe {
         SOCKETS.insert(&sk, &sock_info, 0_u64)?;
     }
 
-    if conn_key.dest_addr == 0 {
+    if conn_key.dest_addr == [0u8; 16] {
         return Ok(0);
     }
 
     conn_key.id = sock_info.id;
     conn_key.pid = sock_info.pid;
     conn_key.role = sock_info.role;
+    conn_stats.start_ns = sock_info.start_ns;
 
     unsafe {
         CONNECTIONS.insert(&conn_key, &conn_stats, 0_u64)?;
@@ -227,6 +269,8 @@ fn handle_tcp_close(sk: *const sock) -> Result<u32, i64> {
         conn_key.id = sock_info.id;
         conn_key.pid = sock_info.pid;
         conn_key.role = sock_info.role;
+        conn_stats.start_ns = sock_info.start_ns;
+        conn_stats.resets = sock_info.resets;
         unsafe {
             SOCKETS.remove(&sk)?;
         }
@@ -244,6 +288,290 @@ fn handle_tcp_close(sk: *const sock) -> Result<u32, i64> {
     Ok(0)
 }
 
+#[tracepoint]
+pub fn tcp_retransmit_tracer(ctx: TracePointContext) -> u32 {
+    match try_tcp_retransmit_tracer(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_tcp_retransmit_tracer(ctx: TracePointContext) -> Result<u32, i64> {
+    let sk: *const sock = unsafe { ctx.read_at::<*const sock>(TCP_EVENT_SKADDR_OFFSET)? };
+    if sk.is_null() || unsafe { SOCKETS.get(&sk) }.is_none() {
+        return Ok(0);
+    }
+    update_connection(sk)
+}
+
+#[tracepoint]
+pub fn tcp_reset_tracer(ctx: TracePointContext) -> u32 {
+    match try_tcp_reset_tracer(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_tcp_reset_tracer(ctx: TracePointContext) -> Result<u32, i64> {
+    // resets sent without a full socket, e.g. to a closed port, have no skaddr
+    let sk: *const sock = unsafe { ctx.read_at::<*const sock>(TCP_EVENT_SKADDR_OFFSET)? };
+    if sk.is_null() {
+        return Ok(0);
+    }
+    match unsafe { SOCKETS.get_ptr_mut(&sk) } {
+        Some(sock_info) => unsafe { (*sock_info).resets += 1 },
+        None => return Ok(0),
+    }
+    update_connection(sk)
+}
+
+#[kprobe]
+pub fn udp_send_tracer(ctx: ProbeContext) -> u32 {
+    match try_udp_send_tracer(ctx, AF_INET) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+#[kprobe]
+pub fn udp_v6_send_tracer(ctx: ProbeContext) -> u32 {
+    match try_udp_send_tracer(ctx, AF_INET6) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_udp_send_tracer(ctx: ProbeContext, family: u16) -> Result<u32, i64> {
+    // udp_send_skb and udp_v6_send_skb take the skb and the routed flow, so
+    // the source address is known even for sockets bound to a wildcard address
+    let skb: *const sk_buff = ctx.arg(0).ok_or(1i64)?;
+    let mut conn_key = ConnectionKey::default();
+
+    let ports = if family == AF_INET {
+        let fl4: *const flowi4 = ctx.arg(1).ok_or(1i64)?;
+        let fl4 = unsafe { bpf_probe_read_kernel(fl4)? };
+        conn_key.src_addr = ipv4_mapped(fl4.saddr);
+        conn_key.dest_addr = ipv4_mapped(fl4.daddr);
+        unsafe { fl4.uli.ports }
+    } else {
+        let fl6: *const flowi6 = ctx.arg(1).ok_or(1i64)?;
+        let fl6 = unsafe { bpf_probe_read_kernel(fl6)? };
+        conn_key.src_addr = unsafe { fl6.saddr.in6_u.u6_addr8 };
+        conn_key.dest_addr = unsafe { fl6.daddr.in6_u.u6_addr8 };
+        unsafe { fl6.uli.ports }
+    };
+    conn_key.src_port = u16::from_be(ports.sport) as u32;
+    conn_key.dest_port = u16::from_be(ports.dport) as u32;
+
+    let len = unsafe { bpf_probe_read_kernel(&(*skb).len as *const u32)? };
+    let sk = unsafe { bpf_probe_read_kernel(&(*skb).__bindgen_anon_2.sk as *const *mut sock)? };
+    let bytes_sent = len.saturating_sub(UDP_HEADER_LEN) as u64;
+
+    track_udp_flow(sk, &mut conn_key, bytes_sent, 0, CONNECTION_ROLE_CLIENT)
+}
+
+#[kprobe]
+pub fn udp_recv_tracer(ctx: ProbeContext) -> u32 {
+    match try_udp_recv_tracer(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_udp_recv_tracer(ctx: ProbeContext) -> Result<u32, i64> {
+    // skb_consume_udp is called for every datagram copied to a UDP socket of
+    // either family, with the number of bytes copied
+    let sk: *const sock = ctx.arg(0).ok_or(1i64)?;
+    let skb: *const sk_buff = ctx.arg(1).ok_or(1i64)?;
+    let len: i32 = ctx.arg(2).ok_or(1i64)?;
+    if len <= 0 {
+        return Ok(0);
+    }
+
+    let (head, network_header, transport_header) = unsafe {
+        let headers = (*skb).__bindgen_anon_5.__bindgen_anon_1.as_ref();
+        (
+            bpf_probe_read_kernel(&(*skb).head as *const *mut u8)?,
+            bpf_probe_read_kernel(&headers.network_header as *const u16)?,
+            bpf_probe_read_kernel(&headers.transport_header as *const u16)?,
+        )
+    };
+
+    // the packet's destination is the local end of the flow
+    let mut conn_key = ConnectionKey::default();
+    let ip = unsafe { head.add(network_header as usize) };
+    match unsafe { bpf_probe_read_kernel(ip)? } >> 4 {
+        4 => unsafe {
+            conn_key.dest_addr = ipv4_mapped(bpf_probe_read_kernel(ip.add(12) as *const u32)?);
+            conn_key.src_addr = ipv4_mapped(bpf_probe_read_kernel(ip.add(16) as *const u32)?);
+        },
+        6 => unsafe {
+            conn_key.dest_addr = bpf_probe_read_kernel(ip.add(8) as *const [u8; 16])?;
+            conn_key.src_addr = bpf_probe_read_kernel(ip.add(24) as *const [u8; 16])?;
+        },
+        _ => return Ok(0),
+    }
+
+    let udp = unsafe { head.add(transport_header as usize) };
+    let ports = unsafe { bpf_probe_read_kernel(udp as *const [u16; 2])? };
+    conn_key.dest_port = u16::from_be(ports[0]) as u32;
+    conn_key.src_port = u16::from_be(ports[1]) as u32;
+
+    track_udp_flow(sk, &mut conn_key, 0, len as u64, CONNECTION_ROLE_SERVER)
+}
+
+/// Accounts a datagram to its flow. A socket which sends before it receives is
+/// the client of all its flows, otherwise the server.
+fn track_udp_flow(
+    sk: *const sock,
+    conn_key: &mut ConnectionKey,
+    bytes_sent: u64,
+    bytes_received: u64,
+    role: u32,
+) -> Result<u32, i64> {
+    if conn_key.dest_addr == [0u8; 16] || conn_key.dest_port == 0 {
+        return Ok(0);
+    }
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    conn_key.protocol = IPPROTO_UDP;
+    conn_key.pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    conn_key.role = match unsafe { SOCKETS.get(&sk) } {
+        Some(sock_info) => sock_info.role,
+        None => {
+            let sock_info = SockInfo {
+                id: get_unique_id(),
+                pid: conn_key.pid,
+                is_active: 1,
+                role,
+                start_ns: now,
+                resets: 0,
+            };
+            unsafe {
+                SOCKETS.insert(&sk, &sock_info, 0_u64)?;
+            }
+            role
+        }
+    };
+
+    match unsafe { CONNECTIONS.get_ptr_mut(conn_key) } {
+        Some(stats) => unsafe {
+            (*stats).bytes_sent += bytes_sent;
+            (*stats).bytes_received += bytes_received;
+            (*stats).last_seen_ns = now;
+        },
+        None => {
+            let conn_stats = ConnectionStats {
+                bytes_sent,
+                bytes_received,
+                is_active: 1,
+                start_ns: now,
+                last_seen_ns: now,
+                ..Default::default()
+            };
+            unsafe {
+                CONNECTIONS.insert(conn_key, &conn_stats, 0_u64)?;
+            }
+        }
+    }
+
+    Ok(0)
+}
+
+#[kprobe]
+pub fn udp_sock_release(ctx: ProbeContext) -> u32 {
+    // attached to udp_destroy_sock and udpv6_destroy_sock, so a new socket
+    // reusing the address does not inherit the role. Flows expire when idle.
+    if let Some(sk) = ctx.arg::<*const sock>(0) {
+        let _ = unsafe { SOCKETS.remove(&sk) };
+    }
+    0
+}
+
+#[kprobe]
+pub fn conntrack_confirm_tracer(ctx: ProbeContext) -> u32 {
+    match try_conntrack_confirm_tracer(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => match ret.try_into() {
+            Ok(rt) => rt,
+            Err(_) => 1,
+        },
+    }
+}
+
+fn try_conntrack_confirm_tracer(ctx: ProbeContext) -> Result<u32, i64> {
+    // __nf_conntrack_confirm takes the first packet of a flow once its NAT is
+    // set up, so the reply tuple of its conntrack comes from the real
+    // destination
+    let skb: *const sk_buff = ctx.arg(0).ok_or(1i64)?;
+    let nfct = unsafe { bpf_probe_read_kernel(&(*skb)._nfct as *const u64)? };
+    let ct = (nfct & !NFCT_INFOMASK) as *const nf_conn;
+    if ct.is_null() {
+        return Ok(0);
+    }
+
+    let (original, reply) = unsafe {
+        (
+            bpf_probe_read_kernel(
+                &(*ct).tuplehash[IP_CT_DIR_ORIGINAL].tuple as *const nf_conntrack_tuple,
+            )?,
+            bpf_probe_read_kernel(
+                &(*ct).tuplehash[IP_CT_DIR_REPLY].tuple as *const nf_conntrack_tuple,
+            )?,
+        )
+    };
+    let protocol = original.dst.protonum as u32;
+    if protocol != IPPROTO_TCP && protocol != IPPROTO_UDP {
+        return Ok(0);
+    }
+
+    let nat_key = NatKey {
+        src_addr: conntrack_addr(&original.src.u3, original.src.l3num)?,
+        src_port: u16::from_be(unsafe { original.src.u.all }) as u32,
+        dest_addr: conntrack_addr(&original.dst.u3, original.src.l3num)?,
+        dest_port: u16::from_be(unsafe { original.dst.u.all }) as u32,
+        protocol,
+    };
+    let target = NatTarget {
+        dest_addr: conntrack_addr(&reply.src.u3, reply.src.l3num)?,
+        dest_port: u16::from_be(unsafe { reply.src.u.all }) as u32,
+    };
+    if target.dest_addr == nat_key.dest_addr && target.dest_port == nat_key.dest_port {
+        return Ok(0);
+    }
+
+    unsafe {
+        NAT_TRANSLATIONS.insert(&nat_key, &target, 0_u64)?;
+    }
+
+    Ok(0)
+}
+
+/// Converts an address of a conntrack tuple, the l3num of a tuple being the
+/// address family.
+fn conntrack_addr(addr: &nf_inet_addr, l3num: u16) -> Result<[u8; 16], i64> {
+    match l3num {
+        AF_INET => Ok(ipv4_mapped(unsafe { addr.ip })),
+        AF_INET6 => Ok(unsafe { addr.in6.in6_u.u6_addr8 }),
+        _ => Err(1i64),
+    }
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
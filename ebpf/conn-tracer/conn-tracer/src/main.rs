@@ -47,6 +47,50 @@ async fn main() -> Result<(), anyhow::Error> {
     sock_state_tracer.load()?;
     sock_state_tracer.attach("sock", "inet_sock_set_state")?;
 
+    let tcp_retransmit_tracer: &mut TracePoint = bpf
+        .program_mut("tcp_retransmit_tracer")
+        .unwrap()
+        .try_into()?;
+    tcp_retransmit_tracer.load()?;
+    tcp_retransmit_tracer.attach("tcp", "tcp_retransmit_skb")?;
+
+    let tcp_reset_tracer: &mut TracePoint =
+        bpf.program_mut("tcp_reset_tracer").unwrap().try_into()?;
+    tcp_reset_tracer.load()?;
+    tcp_reset_tracer.attach("tcp", "tcp_send_reset")?;
+
+    // the UDP functions may be inlined, and the ipv6 ones are missing when ipv6
+    // is not loaded, UDP traffic is then only partially or not traced
+    let udp_probes: [(&str, &[&str]); 4] = [
+        ("udp_send_tracer", &["udp_send_skb"]),
+        ("udp_v6_send_tracer", &["udp_v6_send_skb"]),
+        ("udp_recv_tracer", &["skb_consume_udp"]),
+        (
+            "udp_sock_release",
+            &["udp_destroy_sock", "udpv6_destroy_sock"],
+        ),
+    ];
+    for (name, functions) in udp_probes {
+        let probe: &mut KProbe = bpf.program_mut(name).unwrap().try_into()?;
+        probe.load()?;
+        for function in functions {
+            if let Err(e) = probe.attach(function, 0) {
+                warn!("failed to attach the {} probe to {}: {}", name, function, e);
+            }
+        }
+    }
+
+    // nf_conntrack is not loaded on the hosts without NAT, connections are then
+    // reported to their ClusterIPs only
+    let conntrack_confirm_tracer: &mut KProbe = bpf
+        .program_mut("conntrack_confirm_tracer")
+        .unwrap()
+        .try_into()?;
+    conntrack_confirm_tracer.load()?;
+    if let Err(e) = conntrack_confirm_tracer.attach("__nf_conntrack_confirm", 0) {
+        warn!("failed to attach the conntrack probe: {}", e);
+    }
+
     info!("Waiting for Ctrl-C...");
     signal::ctrl_c().await?;
     info!("Exiting...");